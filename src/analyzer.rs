@@ -0,0 +1,289 @@
+use rust_stemmers::{Algorithm, Stemmer};
+use serde_derive::Serialize;
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+// Language text is analyzed as, the catalog mixes Indonesian and English
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Lang {
+    En,
+    Id,
+}
+
+impl Lang {
+    // None for anything that is not a known language
+    pub fn from_param(lang: &str) -> Option<Lang> {
+        match lang.trim().to_lowercase().as_str() {
+            "en" | "english" => Some(Lang::En),
+            "id" | "indonesian" | "indonesia" => Some(Lang::Id),
+            _ => None,
+        }
+    }
+}
+
+// Turns text into the terms that go in the index or get looked up in it,
+// the same analyzer has to be used on both sides for a word to match
+pub trait Analyzer: Send + Sync {
+    fn is_stopword(&self, word: &str) -> bool;
+    fn stem(&self, word: &str) -> String;
+
+    fn analyze(&self, text: &str) -> Vec<String> {
+        return tokenize(text)
+            .into_iter()
+            .filter(|w| !self.is_stopword(w))
+            .map(|w| self.stem(&w))
+            .collect();
+    }
+}
+
+const ENGLISH_STOPWORDS: &[&str] = &[
+    "a", "about", "an", "and", "are", "as", "at", "be", "by", "for", "from", "has", "have", "in",
+    "into", "is", "it", "its", "of", "on", "or", "that", "the", "their", "this", "to", "was",
+    "were", "will", "with",
+];
+
+const INDONESIAN_STOPWORDS: &[&str] = &[
+    "ada", "adalah", "akan", "atau", "bagi", "dalam", "dan", "dari", "dengan", "di", "ialah",
+    "ini", "itu", "juga", "karena", "ke", "oleh", "pada", "para", "sebagai", "serta", "tentang",
+    "tidak", "untuk", "yang",
+];
+
+pub struct English;
+
+impl Analyzer for English {
+    fn is_stopword(&self, word: &str) -> bool {
+        return ENGLISH_STOPWORDS.contains(&word);
+    }
+
+    fn stem(&self, word: &str) -> String {
+        return Stemmer::create(Algorithm::English).stem(word).into_owned();
+    }
+}
+
+pub struct Indonesian;
+
+impl Analyzer for Indonesian {
+    fn is_stopword(&self, word: &str) -> bool {
+        return INDONESIAN_STOPWORDS.contains(&word);
+    }
+
+    fn stem(&self, word: &str) -> String {
+        return stem_indonesian(word);
+    }
+}
+
+pub fn for_lang(lang: Lang) -> &'static dyn Analyzer {
+    match lang {
+        Lang::En => &English,
+        Lang::Id => &Indonesian,
+    }
+}
+
+// NFKD, accents dropped ("café" -> "cafe"), lowercased
+pub fn normalize(text: &str) -> String {
+    return text
+        .nfkd()
+        .filter(|c| !is_combining_mark(*c))
+        .collect::<String>()
+        .to_lowercase();
+}
+
+// Normalized words of `text`, split on anything that is not a letter or a
+// digit. A trailing `+` or `#` stays on the word so "C++" and "C#" survive
+pub fn tokenize(text: &str) -> Vec<String> {
+    let mut res: Vec<String> = Vec::new();
+    let mut word = String::new();
+    for c in normalize(text).chars() {
+        if c.is_alphanumeric() || ((c == '+' || c == '#') && !word.is_empty()) {
+            word.push(c);
+        } else if !word.is_empty() {
+            res.push(std::mem::take(&mut word));
+        }
+    }
+    if !word.is_empty() {
+        res.push(word);
+    }
+    return res;
+}
+
+// Guess the language from stopwords, then from Indonesian affixes,
+// English when there is nothing to go on
+pub fn detect(text: &str) -> Lang {
+    let words = tokenize(text);
+    let en = words.iter().filter(|w| English.is_stopword(w)).count();
+    let id = words.iter().filter(|w| Indonesian.is_stopword(w)).count();
+    if id != en {
+        return if id > en { Lang::Id } else { Lang::En };
+    }
+
+    let affixed = words
+        .iter()
+        .filter(|w| w.len() > 5)
+        .filter(|w| {
+            [
+                "meng", "meny", "mem", "men", "peng", "peny", "pem", "pen", "ber", "ter",
+            ]
+            .iter()
+            .any(|p| w.starts_with(p))
+                || ["kan", "nya", "an"].iter().any(|s| w.ends_with(s))
+        })
+        .count();
+    if affixed * 2 > words.len() {
+        return Lang::Id;
+    }
+    return Lang::En;
+}
+
+// Shortest stem an affix is allowed to leave behind
+const MIN_STEM: usize = 4;
+
+fn strip_suffix_of(word: &str, suffixes: &[&str]) -> Option<String> {
+    for s in suffixes {
+        if let Some(rest) = word.strip_suffix(s) {
+            if rest.chars().count() >= MIN_STEM {
+                return Some(rest.to_string());
+            }
+        }
+    }
+    return None;
+}
+
+fn starts_with_vowel(word: &str) -> bool {
+    return word.starts_with(['a', 'e', 'i', 'o', 'u']);
+}
+
+// One derivational prefix taken off, with the first letter the nasal
+// replaced put back: "menulis" -> "tulis", "pemrograman" -> "programan"
+fn strip_prefix_once(word: &str) -> Option<String> {
+    let rest = if let Some(r) = word.strip_prefix("meng").or(word.strip_prefix("peng")) {
+        r.to_string()
+    } else if let Some(r) = word.strip_prefix("meny").or(word.strip_prefix("peny")) {
+        format!("s{}", r)
+    } else if let Some(r) = word.strip_prefix("mem").or(word.strip_prefix("pem")) {
+        if starts_with_vowel(r) || r.starts_with('r') {
+            format!("p{}", r)
+        } else {
+            r.to_string()
+        }
+    } else if let Some(r) = word.strip_prefix("men").or(word.strip_prefix("pen")) {
+        if starts_with_vowel(r) {
+            format!("t{}", r)
+        } else {
+            r.to_string()
+        }
+    } else if let Some(r) = word.strip_prefix("bel").filter(|r| r.starts_with("ajar")) {
+        r.to_string()
+    } else if let Some(r) = ["ber", "per", "ter", "me", "pe", "be", "di", "ke", "se"]
+        .iter()
+        .find_map(|p| word.strip_prefix(p))
+    {
+        r.to_string()
+    } else {
+        return None;
+    };
+    if rest.chars().count() < MIN_STEM - 1 {
+        return None;
+    }
+    return Some(rest);
+}
+
+// Rule based Indonesian stemmer after Nazief and Adriani, without the
+// dictionary: particles, possessives, one derivational suffix, then up to
+// two prefixes
+pub fn stem_indonesian(word: &str) -> String {
+    if word.chars().count() <= MIN_STEM || !word.chars().all(|c| c.is_alphabetic()) {
+        return word.to_string();
+    }
+    let mut w = word.to_string();
+    if let Some(rest) = strip_suffix_of(&w, &["lah", "kah", "tah", "pun"]) {
+        w = rest;
+    }
+    if let Some(rest) = strip_suffix_of(&w, &["nya", "ku", "mu"]) {
+        w = rest;
+    }
+
+    let before_suffix = w.clone();
+    let suffix_i = w.ends_with('i');
+    if let Some(rest) = strip_suffix_of(&w, &["kan", "an", "i"]) {
+        w = rest;
+    }
+
+    let mut prefixed = false;
+    for _ in 0..2 {
+        match strip_prefix_once(&w) {
+            Some(rest) => {
+                w = rest;
+                prefixed = true;
+            }
+            None => break,
+        }
+    }
+    // A bare "-i" is too often part of the root ("tinggi"), only trust it
+    // together with a prefix
+    if suffix_i && !prefixed {
+        return before_suffix;
+    }
+    return w;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_are_folded_and_split_on_punctuation() {
+        assert_eq!(
+            tokenize("C: The Complete Reference, 4th Ed."),
+            ["c", "the", "complete", "reference", "4th", "ed"]
+        );
+        assert_eq!(tokenize("Café  Crème"), ["cafe", "creme"]);
+        assert_eq!(tokenize("C++ & C# (2nd)"), ["c++", "c#", "2nd"]);
+        assert_eq!(tokenize("battle-hardened"), ["battle", "hardened"]);
+    }
+
+    #[test]
+    fn indonesian_affixes_are_removed() {
+        let cases = [
+            ("pemrograman", "program"),
+            ("memrogram", "program"),
+            ("menulis", "tulis"),
+            ("penulisan", "tulis"),
+            ("menyapu", "sapu"),
+            ("memukul", "pukul"),
+            ("pembelajaran", "ajar"),
+            ("belajar", "ajar"),
+            ("memperbaiki", "baik"),
+            ("pertanian", "tani"),
+            ("bukunya", "buku"),
+            ("tinggi", "tinggi"),
+            ("data", "data"),
+        ];
+        for (word, stem) in cases {
+            assert_eq!(stem_indonesian(word), stem, "{}", word);
+        }
+    }
+
+    #[test]
+    fn both_languages_meet_on_the_same_stem() {
+        assert_eq!(English.analyze("Programming"), ["program"]);
+        assert_eq!(Indonesian.analyze("Pemrograman"), ["program"]);
+        assert_eq!(English.analyze("the art of the start"), ["art", "start"]);
+        assert_eq!(
+            Indonesian.analyze("dasar dan pemrograman"),
+            ["dasar", "program"]
+        );
+    }
+
+    #[test]
+    fn language_is_detected() {
+        assert_eq!(
+            detect("Pemrograman Berorientasi Objek dengan Java"),
+            Lang::Id
+        );
+        assert_eq!(detect("pemrograman"), Lang::Id);
+        assert_eq!(detect("The C Programming Language"), Lang::En);
+        assert_eq!(detect("programming"), Lang::En);
+        assert_eq!(Lang::from_param("ID"), Some(Lang::Id));
+        assert_eq!(Lang::from_param("fr"), None);
+    }
+}
@@ -1,3 +1,4 @@
+use serde::{Deserialize as _, Deserializer};
 use serde_derive::{Deserialize, Serialize};
 
 // Data model for books and tags
@@ -18,6 +19,117 @@ pub struct Tag {
     pub name: String,
 }
 
+// A tag given by id or by name when creating a book. JSON strings are always
+// names, form and multipart bodies send ids as digits
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum TagRef {
+    Id(i32),
+    Name(String),
+}
+
+// Accept either `"tags": 4` or `"tags": [4, "c99"]`
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<TagRef>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(TagRef),
+        Many(Vec<TagRef>),
+    }
+    match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(tag) => Ok(vec![tag]),
+        OneOrMany::Many(tags) => Ok(tags),
+    }
+}
+
+// Body of `/add_book`
+#[derive(Deserialize, Clone, Debug)]
+pub struct NewBook {
+    pub title: String,
+    pub author: String,
+    #[serde(default)]
+    pub desc: String,
+    #[serde(default)]
+    pub year: String,
+    #[serde(default)]
+    pub cover: String,
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub tags: Vec<TagRef>,
+}
+
+// Body of `PATCH /books/{id}`, fields left out keep their value
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct BookPatch {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub desc: Option<String>,
+    pub year: Option<String>,
+    pub cover: Option<String>,
+    #[serde(default, deserialize_with = "some_one_or_many")]
+    pub tags: Option<Vec<TagRef>>,
+}
+
+fn some_one_or_many<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<TagRef>>, D::Error> {
+    return one_or_many(deserializer).map(Some);
+}
+
+// Body of `/add_tag`
+#[derive(Deserialize, Clone, Debug)]
+pub struct NewTag {
+    pub name: String,
+    #[serde(default)]
+    pub img: String,
+}
+
+// Body of `PATCH /tags/{id}`
+#[derive(Deserialize, Clone, Debug)]
+pub struct TagRename {
+    pub name: String,
+}
+
+// Body of `POST /tags/merge`, every source tag is folded into `target`
+#[derive(Deserialize, Clone, Debug)]
+pub struct TagMerge {
+    pub sources: Vec<i32>,
+    pub target: i32,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct TagMergeResult {
+    pub target: Tag,
+    pub merged: Vec<i32>,
+    pub books_affected: i64,
+}
+
+// Envelope of every list endpoint. `total` counts all matches, not only
+// this page; pass `next_cursor` back to get the next page, it is null on
+// the last one
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub total: i64,
+    pub next_cursor: Option<String>,
+}
+
+impl<T> Page<T> {
+    // Offset paging, the cursor is the offset of the next page
+    pub fn from_offset(items: Vec<T>, total: i64, offset: i32) -> Page<T> {
+        let next = offset as i64 + items.len() as i64;
+        let next_cursor = if !items.is_empty() && next < total {
+            Some(next.to_string())
+        } else {
+            None
+        };
+        return Page {
+            items,
+            total,
+            next_cursor,
+        };
+    }
+}
+
 // Sample book data
 /* pub fn sample_books() -> Vec<Book> {
     vec![
@@ -47,3 +159,54 @@ pub struct Tag {
         },
     ]
 } */
+
+// Fixtures for the tests: books known only by id and their tags, tags
+// named after their id, or books with a real title, author and tag names
+#[cfg(test)]
+pub mod test_support {
+    use super::{Book, Tag};
+
+    pub fn tag(id: i32) -> Tag {
+        return Tag {
+            id,
+            name: format!("tag {}", id),
+        };
+    }
+
+    // Tags 1 to `count`
+    pub fn tags(count: i32) -> Vec<Tag> {
+        return (1..=count).map(tag).collect();
+    }
+
+    pub fn book(id: i32, tags: &[i32]) -> Book {
+        return Book {
+            id,
+            title: format!("book {}", id),
+            author: String::new(),
+            desc: String::new(),
+            tags: tags.iter().map(|t| tag(*t)).collect(),
+            year: String::new(),
+            cover: String::new(),
+        };
+    }
+
+    pub fn named(id: i32, name: &str) -> Tag {
+        return Tag {
+            id,
+            name: name.to_string(),
+        };
+    }
+
+    // Description, year and cover are left empty
+    pub fn titled(id: i32, title: &str, author: &str, tags: Vec<Tag>) -> Book {
+        return Book {
+            id,
+            title: title.to_string(),
+            author: author.to_string(),
+            desc: String::new(),
+            tags,
+            year: String::new(),
+            cover: String::new(),
+        };
+    }
+}
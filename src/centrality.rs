@@ -0,0 +1,287 @@
+use crate::book::{Book, Tag};
+use crate::graph::TagGraph;
+use crate::path::Node;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+
+const DAMPING: f64 = 0.85;
+const PAGERANK_ROUNDS: usize = 100;
+const PAGERANK_TOLERANCE: f64 = 1e-10;
+
+// Which score books and tags are ranked by
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Measure {
+    Degree,
+    #[default]
+    Pagerank,
+    Betweenness,
+}
+
+// Centrality of one node of the book-tag graph. `degree` is the number of
+// tags of a book or books of a tag, `pagerank` sums to 1 over the graph and
+// `betweenness` is the share of shortest paths between other nodes going
+// through this one, from 0 to 1
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Scores {
+    pub degree: usize,
+    pub pagerank: f64,
+    pub betweenness: f64,
+}
+
+impl Scores {
+    pub fn get(&self, measure: Measure) -> f64 {
+        match measure {
+            Measure::Degree => self.degree as f64,
+            Measure::Pagerank => self.pagerank,
+            Measure::Betweenness => self.betweenness,
+        }
+    }
+}
+
+// Scores of every book and tag, computed in one go over the whole graph
+// as it was at `generation`
+#[derive(Clone, Debug, Default)]
+pub struct Centrality {
+    pub generation: u64,
+    scores: HashMap<Node, Scores>,
+}
+
+impl Centrality {
+    pub fn compute(graph: &TagGraph) -> Centrality {
+        let mut nodes: Vec<Node> = graph.book_ids().map(Node::Book).collect();
+        nodes.extend(graph.tag_ids().map(Node::Tag));
+        nodes.sort_by_key(|n| match n {
+            Node::Book(id) => (0, *id),
+            Node::Tag(id) => (1, *id),
+        });
+        let slot: HashMap<Node, usize> = nodes.iter().enumerate().map(|(i, n)| (*n, i)).collect();
+        let adj: Vec<Vec<usize>> = nodes
+            .iter()
+            .map(|n| {
+                let next: Vec<Node> = match n {
+                    Node::Book(id) => graph.tags_of(*id).iter().map(|t| Node::Tag(*t)).collect(),
+                    Node::Tag(id) => graph.books_of(*id).map(Node::Book).collect(),
+                };
+                next.iter().filter_map(|n| slot.get(n).copied()).collect()
+            })
+            .collect();
+
+        let pagerank = pagerank(&adj);
+        let betweenness = betweenness(&adj);
+        let scores = nodes
+            .iter()
+            .enumerate()
+            .map(|(i, n)| {
+                let s = Scores {
+                    degree: adj[i].len(),
+                    pagerank: pagerank[i],
+                    betweenness: betweenness[i],
+                };
+                (*n, s)
+            })
+            .collect();
+        return Centrality {
+            generation: graph.generation(),
+            scores,
+        };
+    }
+
+    // Book ids by `measure`, highest first, then by id
+    pub fn books(&self, measure: Measure) -> Vec<(i32, Scores)> {
+        return self.ranked(measure, |n| match n {
+            Node::Book(id) => Some(id),
+            Node::Tag(_) => None,
+        });
+    }
+
+    // Tag ids by `measure`, highest first, then by id
+    pub fn tags(&self, measure: Measure) -> Vec<(i32, Scores)> {
+        return self.ranked(measure, |n| match n {
+            Node::Tag(id) => Some(id),
+            Node::Book(_) => None,
+        });
+    }
+
+    fn ranked(&self, measure: Measure, pick: impl Fn(Node) -> Option<i32>) -> Vec<(i32, Scores)> {
+        let mut res: Vec<(i32, Scores)> = self
+            .scores
+            .iter()
+            .filter_map(|(n, s)| Some((pick(*n)?, *s)))
+            .collect();
+        res.sort_by(|a, b| {
+            b.1.get(measure)
+                .total_cmp(&a.1.get(measure))
+                .then(a.0.cmp(&b.0))
+        });
+        return res;
+    }
+}
+
+// PageRank by power iteration, the rank of nodes without links is spread
+// over every node
+fn pagerank(adj: &[Vec<usize>]) -> Vec<f64> {
+    let n = adj.len();
+    if n == 0 {
+        return Vec::new();
+    }
+    let mut rank = vec![1.0 / n as f64; n];
+    for _ in 0..PAGERANK_ROUNDS {
+        let dangling: f64 = (0..n).filter(|v| adj[*v].is_empty()).map(|v| rank[v]).sum();
+        let base = (1.0 - DAMPING) / n as f64 + DAMPING * dangling / n as f64;
+        let mut next = vec![base; n];
+        for (v, links) in adj.iter().enumerate() {
+            for w in links {
+                next[*w] += DAMPING * rank[v] / links.len() as f64;
+            }
+        }
+        let change: f64 = next.iter().zip(&rank).map(|(a, b)| (a - b).abs()).sum();
+        rank = next;
+        if change < PAGERANK_TOLERANCE {
+            break;
+        }
+    }
+    return rank;
+}
+
+// Brandes' algorithm: one breadth first search per node, then the shortest
+// path counts are pushed back from the farthest nodes. The buffers are
+// made once and cleared for each search
+fn betweenness(adj: &[Vec<usize>]) -> Vec<f64> {
+    let n = adj.len();
+    let mut res = vec![0.0; n];
+    let mut stack: Vec<usize> = Vec::with_capacity(n);
+    let mut pred: Vec<Vec<usize>> = vec![Vec::new(); n];
+    let mut paths = vec![0.0; n];
+    let mut dist: Vec<Option<usize>> = vec![None; n];
+    let mut delta = vec![0.0; n];
+    let mut queue: VecDeque<usize> = VecDeque::with_capacity(n);
+    for s in 0..n {
+        for p in pred.iter_mut() {
+            p.clear();
+        }
+        paths.fill(0.0);
+        dist.fill(None);
+        delta.fill(0.0);
+        paths[s] = 1.0;
+        dist[s] = Some(0);
+        queue.push_back(s);
+        while let Some(v) = queue.pop_front() {
+            stack.push(v);
+            let d = dist[v].unwrap_or(0);
+            for w in &adj[v] {
+                if dist[*w].is_none() {
+                    dist[*w] = Some(d + 1);
+                    queue.push_back(*w);
+                }
+                if dist[*w] == Some(d + 1) {
+                    paths[*w] += paths[v];
+                    pred[*w].push(v);
+                }
+            }
+        }
+        while let Some(w) = stack.pop() {
+            for v in &pred[w] {
+                delta[*v] += paths[*v] / paths[w] * (1.0 + delta[w]);
+            }
+            if w != s {
+                res[w] += delta[w];
+            }
+        }
+    }
+    // Every pair was counted from both ends
+    if n > 2 {
+        let pairs = ((n - 1) * (n - 2)) as f64;
+        for b in res.iter_mut() {
+            *b /= pairs;
+        }
+    }
+    return res;
+}
+
+// Reply of the central endpoints, each list best first by `measure`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CentralNodes {
+    pub measure: Measure,
+    pub books: Vec<CentralBook>,
+    pub tags: Vec<CentralTag>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CentralBook {
+    pub book: Book,
+    #[serde(flatten)]
+    pub scores: Scores,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CentralTag {
+    pub tag: Tag,
+    #[serde(flatten)]
+    pub scores: Scores,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::book::test_support::{book, tags};
+
+    fn score(c: &Centrality, node: Node) -> Scores {
+        return c.scores.get(&node).copied().unwrap_or_default();
+    }
+
+    fn ids(ranked: &[(i32, Scores)]) -> Vec<i32> {
+        return ranked.iter().map(|(id, _)| *id).collect();
+    }
+
+    #[test]
+    fn path_graph_scores() {
+        // tag 1 - book 1 - tag 2 - book 2 - tag 3, a path of five nodes
+        let g = TagGraph::build(vec![book(1, &[1, 2]), book(2, &[2, 3])], tags(3));
+        let c = Centrality::compute(&g);
+
+        let middle = score(&c, Node::Tag(2));
+        assert_eq!(middle.degree, 2);
+        // 4 of the 6 pairs of other nodes go through the middle
+        assert!((middle.betweenness - 4.0 / 6.0).abs() < 1e-9);
+        assert!((score(&c, Node::Book(1)).betweenness - 3.0 / 6.0).abs() < 1e-9);
+        assert_eq!(score(&c, Node::Tag(1)).betweenness, 0.0);
+
+        let total: f64 = [
+            Node::Book(1),
+            Node::Book(2),
+            Node::Tag(1),
+            Node::Tag(2),
+            Node::Tag(3),
+        ]
+        .iter()
+        .map(|n| score(&c, *n).pagerank)
+        .sum();
+        assert!((total - 1.0).abs() < 1e-9);
+        assert_eq!(ids(&c.tags(Measure::Pagerank))[0], 2);
+        assert_eq!(ids(&c.tags(Measure::Degree)), [2, 1, 3]);
+        assert_eq!(ids(&c.books(Measure::Betweenness)), [1, 2]);
+    }
+
+    #[test]
+    fn hub_tag_ranks_first() {
+        let g = TagGraph::build(
+            vec![
+                book(1, &[1, 2]),
+                book(2, &[1, 3]),
+                book(3, &[1]),
+                book(4, &[4]),
+            ],
+            tags(4),
+        );
+        let c = Centrality::compute(&g);
+        for measure in [Measure::Degree, Measure::Pagerank, Measure::Betweenness] {
+            assert_eq!(ids(&c.tags(measure))[0], 1);
+        }
+        assert!(score(&c, Node::Tag(1)).pagerank > score(&c, Node::Tag(4)).pagerank);
+        assert_eq!(score(&c, Node::Book(9)), Scores::default());
+        assert!(Centrality::compute(&TagGraph::default())
+            .tags(Measure::Pagerank)
+            .is_empty());
+    }
+}
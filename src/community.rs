@@ -0,0 +1,316 @@
+use crate::book::{Book, Tag};
+use crate::graph::TagGraph;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+// Symmetric weighted graph over nodes 0..n, a row holds a node's links and
+// its self loop. Aggregated graphs keep the weight inside a community as
+// the self loop of its node
+type Weights = Vec<BTreeMap<usize, f64>>;
+
+// A group of tags found by `communities`, by id
+#[derive(Clone, Debug, PartialEq)]
+pub struct TagGroup {
+    // Connected component the group lies in, groups never span two
+    pub component: usize,
+    // Share of the graph modularity coming from this group
+    pub modularity: f64,
+    pub tags: Vec<i32>,
+    // Books having most of their tags in the group
+    pub books: Vec<i32>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Partition {
+    pub modularity: f64,
+    pub components: usize,
+    pub groups: Vec<TagGroup>,
+}
+
+// Reply of the communities endpoints
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Communities {
+    pub modularity: f64,
+    pub components: usize,
+    pub communities: Vec<Community>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Community {
+    pub id: usize,
+    pub component: usize,
+    pub modularity: f64,
+    pub tags: Vec<Tag>,
+    pub books: Vec<Book>,
+}
+
+// Label of each node, renumbered from 0 in order of first appearance
+fn renumber(labels: &[usize]) -> Vec<usize> {
+    let mut seen: HashMap<usize, usize> = HashMap::new();
+    return labels
+        .iter()
+        .map(|l| {
+            let next = seen.len();
+            *seen.entry(*l).or_insert(next)
+        })
+        .collect();
+}
+
+// Component of each node, found breadth first
+fn components(weights: &Weights) -> Vec<usize> {
+    let mut res: Vec<Option<usize>> = vec![None; weights.len()];
+    let mut count = 0;
+    for start in 0..weights.len() {
+        if res[start].is_some() {
+            continue;
+        }
+        res[start] = Some(count);
+        let mut queue = vec![start];
+        while let Some(node) = queue.pop() {
+            for next in weights[node].keys() {
+                if res[*next].is_none() {
+                    res[*next] = Some(count);
+                    queue.push(*next);
+                }
+            }
+        }
+        count += 1;
+    }
+    return res.into_iter().map(|c| c.unwrap_or(0)).collect();
+}
+
+// First Louvain phase: move nodes one by one to the neighboring community
+// that raises modularity the most until no move helps
+fn local_moves(weights: &Weights) -> Vec<usize> {
+    let degree: Vec<f64> = weights.iter().map(|row| row.values().sum()).collect();
+    let total: f64 = degree.iter().sum();
+    let mut comm: Vec<usize> = (0..weights.len()).collect();
+    if total == 0.0 {
+        return comm;
+    }
+    // Summed degree of each community
+    let mut tot = degree.clone();
+    loop {
+        let mut moved = false;
+        for node in 0..weights.len() {
+            let own = comm[node];
+            let mut links: BTreeMap<usize, f64> = BTreeMap::new();
+            for (next, w) in &weights[node] {
+                if *next != node {
+                    *links.entry(comm[*next]).or_insert(0.0) += w;
+                }
+            }
+            tot[own] -= degree[node];
+            // Modularity gained by putting the node in `c`, up to a constant factor
+            let gain = |c: usize| {
+                return links.get(&c).copied().unwrap_or(0.0) - tot[c] * degree[node] / total;
+            };
+            let mut best = own;
+            let mut best_gain = gain(own);
+            for c in links.keys() {
+                let g = gain(*c);
+                if g > best_gain + 1e-12 {
+                    best = *c;
+                    best_gain = g;
+                }
+            }
+            tot[best] += degree[node];
+            if best != own {
+                comm[node] = best;
+                moved = true;
+            }
+        }
+        if !moved {
+            break;
+        }
+    }
+    return renumber(&comm);
+}
+
+// Louvain community detection: local moves, then every community becomes
+// one node of a smaller graph and it starts again, until nothing merges
+fn louvain(weights: &Weights) -> Vec<usize> {
+    let mut membership: Vec<usize> = (0..weights.len()).collect();
+    let mut current = weights.clone();
+    loop {
+        let level = local_moves(&current);
+        let count = level.iter().max().map(|m| m + 1).unwrap_or(0);
+        if count == current.len() {
+            break;
+        }
+        for m in membership.iter_mut() {
+            *m = level[*m];
+        }
+        let mut next: Weights = vec![BTreeMap::new(); count];
+        for (node, row) in current.iter().enumerate() {
+            for (other, w) in row {
+                *next[level[node]].entry(level[*other]).or_insert(0.0) += w;
+            }
+        }
+        current = next;
+    }
+    return membership;
+}
+
+// Contribution of each community to the modularity Q = sum over
+// communities of in/2m - (tot/2m)^2
+fn modularity(weights: &Weights, comm: &[usize], count: usize) -> Vec<f64> {
+    let total: f64 = weights.iter().flat_map(|row| row.values()).sum();
+    let mut inside = vec![0.0; count];
+    let mut tot = vec![0.0; count];
+    for (node, row) in weights.iter().enumerate() {
+        for (other, w) in row {
+            tot[comm[node]] += w;
+            if comm[node] == comm[*other] {
+                inside[comm[node]] += w;
+            }
+        }
+    }
+    if total == 0.0 {
+        return vec![0.0; count];
+    }
+    return (0..count)
+        .map(|c| inside[c] / total - (tot[c] / total).powi(2))
+        .collect();
+}
+
+// Subject clusters of the catalog: connected components of the tag graph,
+// split further into Louvain communities. Groups with the most books come
+// first, a tag no book shares is a group of its own
+pub fn communities(graph: &TagGraph) -> Partition {
+    let tag_ids: Vec<i32> = graph.tag_ids().collect();
+    let slot: HashMap<i32, usize> = tag_ids.iter().enumerate().map(|(i, t)| (*t, i)).collect();
+    let mut weights: Weights = vec![BTreeMap::new(); tag_ids.len()];
+    for (i, tag) in tag_ids.iter().enumerate() {
+        for (other, w) in graph.links(*tag) {
+            if let Some(j) = slot.get(&other) {
+                weights[i].insert(*j, w as f64);
+            }
+        }
+    }
+
+    let component = components(&weights);
+    let comm = louvain(&weights);
+    let count = comm.iter().max().map(|m| m + 1).unwrap_or(0);
+    let scores = modularity(&weights, &comm, count);
+
+    let mut groups: Vec<TagGroup> = (0..count)
+        .map(|c| TagGroup {
+            component: 0,
+            modularity: scores[c],
+            tags: Vec::new(),
+            books: Vec::new(),
+        })
+        .collect();
+    for (i, tag) in tag_ids.iter().enumerate() {
+        groups[comm[i]].component = component[i];
+        groups[comm[i]].tags.push(*tag);
+    }
+
+    let mut book_ids: Vec<i32> = graph.book_ids().collect();
+    book_ids.sort_unstable();
+    for book in book_ids {
+        // Ties go to the group of the lowest tag id
+        let mut votes: BTreeMap<usize, usize> = BTreeMap::new();
+        for tag in graph.tags_of(book) {
+            if let Some(i) = slot.get(tag) {
+                *votes.entry(comm[*i]).or_insert(0) += 1;
+            }
+        }
+        let best = votes
+            .iter()
+            .max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0)))
+            .map(|(c, _)| *c);
+        if let Some(c) = best {
+            groups[c].books.push(book);
+        }
+    }
+
+    groups.sort_by(|a, b| {
+        b.books
+            .len()
+            .cmp(&a.books.len())
+            .then(b.tags.len().cmp(&a.tags.len()))
+            .then(a.tags.first().cmp(&b.tags.first()))
+    });
+    // Components numbered in the order their first group comes
+    let order = renumber(&groups.iter().map(|g| g.component).collect::<Vec<usize>>());
+    let components = order.iter().max().map(|m| m + 1).unwrap_or(0);
+    for (group, c) in groups.iter_mut().zip(order) {
+        group.component = c;
+    }
+    return Partition {
+        modularity: scores.iter().sum(),
+        components,
+        groups,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::book::test_support::{book, tags};
+
+    fn graph(books: Vec<Book>, tag_count: i32) -> TagGraph {
+        return TagGraph::build(books, tags(tag_count));
+    }
+
+    #[test]
+    fn two_cliques_joined_by_one_book_split() {
+        // Tags 1-3 and 4-6 are each seen together a lot, book 9 alone
+        // links them, tag 7 is on no book
+        let g = graph(
+            vec![
+                book(1, &[1, 2, 3]),
+                book(2, &[1, 2, 3]),
+                book(3, &[1, 2]),
+                book(4, &[4, 5, 6]),
+                book(5, &[4, 5, 6]),
+                book(6, &[5, 6]),
+                book(7, &[4, 6]),
+                book(9, &[3, 4]),
+            ],
+            7,
+        );
+        let res = communities(&g);
+        let tags: Vec<&[i32]> = res.groups.iter().map(|g| g.tags.as_slice()).collect();
+        assert_eq!(tags, [&[1, 2, 3][..], &[4, 5, 6], &[7]]);
+        // Book 9 is split evenly, the group of the lower tag id wins
+        assert_eq!(res.groups[0].books, [1, 2, 3, 9]);
+        assert_eq!(res.groups[1].books, [4, 5, 6, 7]);
+        assert!(res.groups[2].books.is_empty());
+
+        assert_eq!(res.components, 2);
+        assert_eq!(res.groups[0].component, res.groups[1].component);
+        assert_ne!(res.groups[0].component, res.groups[2].component);
+        assert!(res.modularity > 0.3);
+        let sum: f64 = res.groups.iter().map(|g| g.modularity).sum();
+        assert!((sum - res.modularity).abs() < 1e-9);
+        assert_eq!(res.groups[2].modularity, 0.0);
+    }
+
+    #[test]
+    fn empty_and_unlinked_graphs() {
+        let res = communities(&graph(Vec::new(), 0));
+        assert_eq!((res.components, res.groups.len()), (0, 0));
+
+        let res = communities(&graph(vec![book(1, &[1]), book(2, &[2])], 2));
+        assert_eq!(res.components, 2);
+        assert_eq!(res.modularity, 0.0);
+        assert!(res.groups.iter().all(|g| g.books.len() == 1));
+    }
+
+    #[test]
+    fn modularity_of_known_partition() {
+        // Two triangles joined by one edge: Q = 5/14
+        let mut w: Weights = vec![BTreeMap::new(); 6];
+        for (a, b) in [(0, 1), (1, 2), (0, 2), (3, 4), (4, 5), (3, 5), (2, 3)] {
+            w[a].insert(b, 1.0);
+            w[b].insert(a, 1.0);
+        }
+        let comm = louvain(&w);
+        assert_eq!(comm, [0, 0, 0, 1, 1, 1]);
+        let q: f64 = modularity(&w, &comm, 2).iter().sum();
+        assert!((q - 5.0 / 14.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,559 @@
+use crate::analyzer;
+use crate::book::{Book, Tag};
+use serde_derive::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+
+// What a suggestion completes to
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Entry {
+    Title(i32),
+    Author(String),
+    Tag(i32),
+}
+
+impl Entry {
+    // Slot of the entry's group in per-kind arrays
+    fn kind(&self) -> usize {
+        match self {
+            Entry::Title(_) => 0,
+            Entry::Author(_) => 1,
+            Entry::Tag(_) => 2,
+        }
+    }
+}
+
+// How a suggestion ranks, larger is better: its text starts with what was
+// typed, then more books, then shorter text
+type Rank = (bool, usize, Reverse<usize>);
+
+// A suggestion being considered, best first when sorted: rank, then text,
+// then entry so equal ones always come out in the same order
+type Candidate<'a> = (Reverse<Rank>, &'a str, &'a Entry);
+
+// Character trie, each node lists the entries whose key ends there. A key
+// ending at the start of the text is kept apart from one starting at a
+// later word, the first kind ranks higher
+#[derive(Default)]
+struct Node {
+    children: BTreeMap<char, Node>,
+    entries: Vec<(Entry, bool)>,
+    // Best rank of each kind of entry anywhere under this node, so a walk
+    // can skip what can't make the list
+    best: [Option<Rank>; 3],
+}
+
+impl Node {
+    fn insert(&mut self, key: &[char], entry: (Entry, bool), catalog: &Catalog) {
+        match key.split_first() {
+            None => {
+                if !self.entries.contains(&entry) {
+                    self.entries.push(entry);
+                }
+            }
+            Some((c, rest)) => {
+                let child = self.children.entry(*c).or_default();
+                child.insert(rest, entry, catalog);
+            }
+        }
+        self.refresh(catalog);
+    }
+
+    // Take the entry out, dropping nodes left with nothing under them
+    fn remove(&mut self, key: &[char], entry: &(Entry, bool), catalog: &Catalog) {
+        let Some((c, rest)) = key.split_first() else {
+            self.entries.retain(|e| e != entry);
+            self.refresh(catalog);
+            return;
+        };
+        if let Some(child) = self.children.get_mut(c) {
+            child.remove(rest, entry, catalog);
+            if child.entries.is_empty() && child.children.is_empty() {
+                self.children.remove(c);
+            }
+        }
+        self.refresh(catalog);
+    }
+
+    // Work the best ranks out again along `key`, after the books behind an
+    // entry ending there changed
+    fn touch(&mut self, key: &[char], catalog: &Catalog) {
+        if let Some((c, rest)) = key.split_first() {
+            if let Some(child) = self.children.get_mut(c) {
+                child.touch(rest, catalog);
+            }
+        }
+        self.refresh(catalog);
+    }
+
+    fn refresh(&mut self, catalog: &Catalog) {
+        let mut best: [Option<Rank>; 3] = [None; 3];
+        for (entry, first) in &self.entries {
+            let rank = catalog.rank(entry, *first);
+            best[entry.kind()] = best[entry.kind()].max(rank);
+        }
+        for child in self.children.values() {
+            for (b, c) in best.iter_mut().zip(child.best) {
+                *b = (*b).max(c);
+            }
+        }
+        self.best = best;
+    }
+
+    fn find(&self, prefix: &str) -> Option<&Node> {
+        let mut node = self;
+        for c in prefix.chars() {
+            node = node.children.get(&c)?;
+        }
+        return Some(node);
+    }
+
+    // Best `limit` entries of each kind under this node into `lists`. The
+    // most promising children go first and a child is skipped once its
+    // best can't beat the last of every list it could add to
+    fn walk<'a>(&'a self, catalog: &'a Catalog, limit: usize, lists: &mut [Vec<Candidate<'a>>; 3]) {
+        for (entry, first) in &self.entries {
+            if let Some((books, text)) = catalog.measure(entry) {
+                let rank = (*first, books, Reverse(text.len()));
+                offer(
+                    &mut lists[entry.kind()],
+                    limit,
+                    (Reverse(rank), text, entry),
+                );
+            }
+        }
+        let mut children: Vec<&Node> = self.children.values().collect();
+        children.sort_by_key(|c| Reverse(c.best.iter().max().copied().flatten()));
+        for child in children {
+            let useful = child.best.iter().zip(lists.iter()).any(|(best, list)| {
+                let Some(best) = best else {
+                    return false;
+                };
+                return list.len() < limit || list.last().is_some_and(|last| *best >= last.0 .0);
+            });
+            if useful {
+                child.walk(catalog, limit, lists);
+            }
+        }
+    }
+}
+
+// Keep `list` to its best `limit` candidates in order, an entry reached
+// both from its first word and a later one keeps the better spot
+fn offer<'a>(list: &mut Vec<Candidate<'a>>, limit: usize, candidate: Candidate<'a>) {
+    if let Some(i) = list.iter().position(|c| c.2 == candidate.2) {
+        if list[i] <= candidate {
+            return;
+        }
+        list.remove(i);
+    }
+    let at = list.partition_point(|c| *c < candidate);
+    if at < limit {
+        list.insert(at, candidate);
+        list.truncate(limit);
+    }
+}
+
+// Keys a text is completed from: the normalized words joined by one space,
+// starting at each word so "ref" completes "C: The Complete Reference".
+// The flag is true for the key starting at the first word
+fn keys(text: &str) -> Vec<(String, bool)> {
+    let words = analyzer::tokenize(text);
+    return (0..words.len())
+        .map(|i| (words[i..].join(" "), i == 0))
+        .collect();
+}
+
+// What suggestions are made of, apart from the trie so the trie can rank
+// its entries while it is being changed
+#[derive(Default)]
+struct Catalog {
+    // Title, author and tag ids each book was added under
+    books: HashMap<i32, (String, String, Vec<i32>)>,
+    tags: HashMap<i32, String>,
+    // Spelling shown for each author and number of their books, authors
+    // are merged on their normalized name
+    authors: HashMap<String, (String, usize)>,
+    tag_books: HashMap<i32, usize>,
+}
+
+impl Catalog {
+    // Books behind an entry and the text it is shown as
+    fn measure(&self, entry: &Entry) -> Option<(usize, &str)> {
+        match entry {
+            Entry::Title(id) => {
+                let (title, _, _) = self.books.get(id)?;
+                return Some((1, title));
+            }
+            Entry::Author(key) => {
+                let (shown, count) = self.authors.get(key)?;
+                return Some((*count, shown));
+            }
+            Entry::Tag(id) => {
+                let books = self.tag_books.get(id).copied().unwrap_or(0);
+                return Some((books, self.tags.get(id)?));
+            }
+        }
+    }
+
+    fn rank(&self, entry: &Entry, first: bool) -> Option<Rank> {
+        let (books, text) = self.measure(entry)?;
+        return Some((first, books, Reverse(text.len())));
+    }
+
+    fn suggestion(&self, entry: &Entry) -> Option<Suggestion> {
+        let (books, text) = self.measure(entry)?;
+        let id = match entry {
+            Entry::Title(id) | Entry::Tag(id) => Some(*id),
+            Entry::Author(_) => None,
+        };
+        return Some(Suggestion {
+            id,
+            text: text.to_string(),
+            books,
+        });
+    }
+}
+
+// Prefix completion over titles, authors and tag names, updated book by
+// book and tag by tag like the search index
+#[derive(Default)]
+pub struct Completer {
+    root: Node,
+    catalog: Catalog,
+}
+
+impl Completer {
+    pub fn build(books: Vec<Book>, tags: Vec<Tag>) -> Completer {
+        let mut res = Completer::default();
+        for tag in tags {
+            res.upsert_tag(tag);
+        }
+        for book in books {
+            res.upsert_book(&book);
+        }
+        return res;
+    }
+
+    fn add_text(&mut self, text: &str, entry: Entry) {
+        for (key, first) in keys(text) {
+            let key: Vec<char> = key.chars().collect();
+            self.root
+                .insert(&key, (entry.clone(), first), &self.catalog);
+        }
+    }
+
+    fn remove_text(&mut self, text: &str, entry: Entry) {
+        for (key, first) in keys(text) {
+            let key: Vec<char> = key.chars().collect();
+            self.root
+                .remove(&key, &(entry.clone(), first), &self.catalog);
+        }
+    }
+
+    // The book count of what `text` completes to changed
+    fn touch_text(&mut self, text: &str) {
+        for (key, _) in keys(text) {
+            let key: Vec<char> = key.chars().collect();
+            self.root.touch(&key, &self.catalog);
+        }
+    }
+
+    fn touch_tags(&mut self, tag_ids: &[i32]) {
+        for id in tag_ids {
+            if let Some(name) = self.catalog.tags.get(id).cloned() {
+                self.touch_text(&name);
+            }
+        }
+    }
+
+    pub fn upsert_book(&mut self, book: &Book) {
+        self.remove_book(book.id);
+
+        let author = analyzer::tokenize(&book.author).join(" ");
+        let tag_ids: Vec<i32> = book.tags.iter().map(|t| t.id).collect();
+        self.catalog.books.insert(
+            book.id,
+            (book.title.clone(), author.clone(), tag_ids.clone()),
+        );
+        self.add_text(&book.title, Entry::Title(book.id));
+        if !author.is_empty() {
+            let known = self.catalog.authors.contains_key(&author);
+            let count = self
+                .catalog
+                .authors
+                .entry(author.clone())
+                .or_insert_with(|| (book.author.trim().to_string(), 0));
+            count.1 += 1;
+            if known {
+                self.touch_text(&book.author);
+            } else {
+                self.add_text(&book.author, Entry::Author(author));
+            }
+        }
+        for id in &tag_ids {
+            *self.catalog.tag_books.entry(*id).or_insert(0) += 1;
+        }
+        self.touch_tags(&tag_ids);
+    }
+
+    pub fn remove_book(&mut self, book_id: i32) {
+        let Some((title, author, tag_ids)) = self.catalog.books.get(&book_id).cloned() else {
+            return;
+        };
+        self.remove_text(&title, Entry::Title(book_id));
+        self.catalog.books.remove(&book_id);
+        if let Some((shown, count)) = self.catalog.authors.get_mut(&author) {
+            let shown = shown.clone();
+            if *count == 1 {
+                self.remove_text(&shown, Entry::Author(author.clone()));
+                self.catalog.authors.remove(&author);
+            } else {
+                *count -= 1;
+                self.touch_text(&shown);
+            }
+        }
+        for id in &tag_ids {
+            if let Some(count) = self.catalog.tag_books.get_mut(id) {
+                *count -= 1;
+                if *count == 0 {
+                    self.catalog.tag_books.remove(id);
+                }
+            }
+        }
+        self.touch_tags(&tag_ids);
+    }
+
+    pub fn upsert_tag(&mut self, tag: Tag) {
+        self.remove_tag(tag.id);
+        self.catalog.tags.insert(tag.id, tag.name.clone());
+        self.add_text(&tag.name, Entry::Tag(tag.id));
+    }
+
+    pub fn remove_tag(&mut self, tag_id: i32) {
+        if let Some(name) = self.catalog.tags.get(&tag_id).cloned() {
+            self.remove_text(&name, Entry::Tag(tag_id));
+            self.catalog.tags.remove(&tag_id);
+        }
+    }
+
+    // Up to `limit` suggestions of each kind for what was typed so far.
+    // Texts starting with it come first, then the ones with more books,
+    // then the shorter ones
+    pub fn complete(&self, prefix: &str, limit: usize) -> Completions {
+        let mut res = Completions::default();
+        let prefix = analyzer::tokenize(prefix).join(" ");
+        if prefix.is_empty() || limit == 0 {
+            return res;
+        }
+        let Some(node) = self.root.find(&prefix) else {
+            return res;
+        };
+        let mut lists: [Vec<Candidate>; 3] = Default::default();
+        node.walk(&self.catalog, limit, &mut lists);
+
+        let [titles, authors, tags] = lists.map(|list| {
+            return list
+                .into_iter()
+                .filter_map(|(_, _, entry)| self.catalog.suggestion(entry))
+                .collect::<Vec<Suggestion>>();
+        });
+        res.titles = titles;
+        res.authors = authors;
+        res.tags = tags;
+        return res;
+    }
+}
+
+// One completion, `id` is the book or tag id, authors have none
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Suggestion {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<i32>,
+    pub text: String,
+    // Books behind the suggestion: 1 for a title, the author's or the tag's book count
+    pub books: usize,
+}
+
+// Suggestions grouped by kind, best first in each group
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Completions {
+    pub titles: Vec<Suggestion>,
+    pub authors: Vec<Suggestion>,
+    pub tags: Vec<Suggestion>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::book::test_support::{named, titled};
+
+    fn texts(group: &[Suggestion]) -> Vec<&str> {
+        return group.iter().map(|s| s.text.as_str()).collect();
+    }
+
+    fn catalog() -> Completer {
+        let programming = named(1, "programming");
+        let python = named(2, "python");
+        return Completer::build(
+            vec![
+                titled(
+                    1,
+                    "C: The Complete Reference",
+                    "Herbert Schildt",
+                    vec![programming.clone()],
+                ),
+                titled(
+                    2,
+                    "Java: The Complete Reference",
+                    "Herbert Schildt",
+                    vec![programming.clone()],
+                ),
+                titled(
+                    3,
+                    "Programming Python",
+                    "Mark Lutz",
+                    vec![programming.clone(), python.clone()],
+                ),
+                titled(
+                    4,
+                    "Python Crash Course",
+                    "Eric Matthes",
+                    vec![python.clone()],
+                ),
+            ],
+            vec![programming, python, named(3, "poetry")],
+        );
+    }
+
+    #[test]
+    fn suggestions_are_grouped_and_ranked() {
+        let c = catalog();
+
+        let res = c.complete("p", 5);
+        assert_eq!(
+            texts(&res.titles),
+            ["Programming Python", "Python Crash Course"]
+        );
+        assert!(res.authors.is_empty());
+        // Most used tag first, tags without books still show up
+        assert_eq!(texts(&res.tags), ["programming", "python", "poetry"]);
+        assert_eq!(res.tags[0].books, 3);
+        assert_eq!(res.tags[2].books, 0);
+
+        // A later word matches too, after the texts starting with it
+        let res = c.complete("pyth", 5);
+        assert_eq!(
+            texts(&res.titles),
+            ["Python Crash Course", "Programming Python"]
+        );
+
+        let res = c.complete("  SCHILDT", 5);
+        assert_eq!(texts(&res.authors), ["Herbert Schildt"]);
+        assert_eq!(res.authors[0].books, 2);
+        assert_eq!(res.authors[0].id, None);
+
+        let res = c.complete("the complete r", 1);
+        assert_eq!(res.titles.len(), 1);
+        assert!(c.complete("zz", 5).titles.is_empty());
+        assert_eq!(c.complete("", 5), Completions::default());
+    }
+
+    #[test]
+    fn suggestions_follow_catalog_changes() {
+        let mut c = catalog();
+
+        c.remove_book(1);
+        let res = c.complete("herb", 5);
+        assert_eq!(res.authors[0].books, 1);
+        c.remove_book(2);
+        assert!(c.complete("herb", 5).authors.is_empty());
+        assert!(c.complete("c the", 5).titles.is_empty());
+        assert_eq!(c.complete("prog", 5).tags[0].books, 1);
+
+        c.upsert_book(&titled(
+            4,
+            "Fluent Python",
+            "Luciano Ramalho",
+            vec![named(2, "python")],
+        ));
+        assert!(c.complete("crash", 5).titles.is_empty());
+        assert_eq!(texts(&c.complete("flu", 5).titles), ["Fluent Python"]);
+
+        c.upsert_tag(named(3, "poems"));
+        assert!(c.complete("poet", 5).tags.is_empty());
+        assert_eq!(texts(&c.complete("poe", 5).tags), ["poems"]);
+        c.remove_tag(3);
+        assert!(c.complete("poe", 5).tags.is_empty());
+    }
+
+    // Every entry under the prefix ranked in full, what the walk has to
+    // agree with
+    fn full_ranking(c: &Completer, prefix: &str, limit: usize) -> Completions {
+        fn all<'a>(node: &'a Node, res: &mut Vec<&'a (Entry, bool)>) {
+            res.extend(node.entries.iter());
+            for child in node.children.values() {
+                all(child, res);
+            }
+        }
+        let mut found = Vec::new();
+        if let Some(node) = c.root.find(prefix) {
+            all(node, &mut found);
+        }
+        let mut lists: [Vec<Candidate>; 3] = Default::default();
+        for (entry, first) in found {
+            let (books, text) = c.catalog.measure(entry).unwrap();
+            let rank = (*first, books, Reverse(text.len()));
+            offer(
+                &mut lists[entry.kind()],
+                usize::MAX,
+                (Reverse(rank), text, entry),
+            );
+        }
+        let [titles, authors, tags] = lists.map(|list| {
+            return list
+                .into_iter()
+                .take(limit)
+                .filter_map(|(_, _, e)| c.catalog.suggestion(e))
+                .collect::<Vec<Suggestion>>();
+        });
+        return Completions {
+            titles,
+            authors,
+            tags,
+        };
+    }
+
+    #[test]
+    fn bounded_walk_matches_full_ranking() {
+        let words = ["pa", "pan", "panel", "pi", "pine", "pit", "po", "pond"];
+        let names = [
+            "paper", "pasta", "pixel", "pivot", "polar", "posh", "pulp", "punk",
+        ];
+        let tags: Vec<Tag> = (1..=8)
+            .map(|id| named(id, names[id as usize - 1]))
+            .collect();
+        let mut c = Completer::build(Vec::new(), tags.clone());
+        let check = |c: &Completer| {
+            for prefix in ["p", "pa", "pan", "pi", "piv", "po", "pu", "pond p", "x"] {
+                for limit in [1, 3, 20] {
+                    assert_eq!(c.complete(prefix, limit), full_ranking(c, prefix, limit));
+                }
+            }
+        };
+        for id in 1..=60 {
+            let n = id as usize;
+            let title = format!("{} {} {}", words[n % 8], words[n * 3 % 8], words[n * 5 % 7]);
+            let author = format!("{} {}", words[n % 5], words[n % 3]);
+            let tagged = vec![tags[n % 8].clone(), tags[n * n % 8].clone()];
+            c.upsert_book(&titled(id, &title, &author, tagged));
+        }
+        check(&c);
+        for id in (1..=60).step_by(4) {
+            c.remove_book(id);
+        }
+        c.upsert_tag(named(2, "pinecone"));
+        c.remove_tag(5);
+        check(&c);
+    }
+}
@@ -0,0 +1,173 @@
+use crate::search::ParseError;
+use axum::{
+    extract::rejection::{JsonRejection, PathRejection, QueryRejection},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_derive::Serialize;
+use serde_json::{json, Value};
+
+// What went wrong, decides the HTTP status and the `code` field
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorKind {
+    NotFound,
+    Validation,
+    Conflict,
+    Storage,
+    Internal,
+}
+
+impl ErrorKind {
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            ErrorKind::Validation => StatusCode::BAD_REQUEST,
+            ErrorKind::Conflict => StatusCode::CONFLICT,
+            ErrorKind::Storage => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorKind::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn code(self) -> &'static str {
+        match self {
+            ErrorKind::NotFound => "not_found",
+            ErrorKind::Validation => "validation",
+            ErrorKind::Conflict => "conflict",
+            ErrorKind::Storage => "storage",
+            ErrorKind::Internal => "internal",
+        }
+    }
+}
+
+// Error type shared by the sql layer, search and every handler
+#[derive(Debug)]
+pub struct AppError {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub details: Option<Value>,
+}
+
+// Body sent to the client, `{code, message, details}`
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    code: &'static str,
+    message: &'a str,
+    details: &'a Option<Value>,
+}
+
+impl AppError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> AppError {
+        return AppError {
+            kind,
+            message: message.into(),
+            details: None,
+        };
+    }
+
+    pub fn not_found(message: impl Into<String>) -> AppError {
+        return AppError::new(ErrorKind::NotFound, message);
+    }
+
+    pub fn validation(message: impl Into<String>) -> AppError {
+        return AppError::new(ErrorKind::Validation, message);
+    }
+
+    pub fn conflict(message: impl Into<String>) -> AppError {
+        return AppError::new(ErrorKind::Conflict, message);
+    }
+
+    pub fn storage(message: impl Into<String>) -> AppError {
+        return AppError::new(ErrorKind::Storage, message);
+    }
+
+    pub fn internal(message: impl Into<String>) -> AppError {
+        return AppError::new(ErrorKind::Internal, message);
+    }
+
+    pub fn with_details(mut self, details: Value) -> AppError {
+        self.details = Some(details);
+        return self;
+    }
+}
+
+impl std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.kind.code(), self.message)
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            code: self.kind.code(),
+            message: &self.message,
+            details: &self.details,
+        };
+        return (self.kind.status(), Json(body)).into_response();
+    }
+}
+
+impl From<rusqlite::Error> for AppError {
+    fn from(err: rusqlite::Error) -> Self {
+        match err {
+            rusqlite::Error::QueryReturnedNoRows => AppError::not_found("record not found"),
+            rusqlite::Error::SqliteFailure(e, msg)
+                if e.code == rusqlite::ErrorCode::ConstraintViolation =>
+            {
+                AppError::conflict(msg.unwrap_or_else(|| e.to_string()))
+            }
+            rusqlite::Error::SqliteFailure(e, msg)
+                if e.code == rusqlite::ErrorCode::DatabaseBusy
+                    || e.code == rusqlite::ErrorCode::DatabaseLocked =>
+            {
+                AppError::storage("database is busy, try again")
+                    .with_details(json!({ "source": msg.unwrap_or_else(|| e.to_string()) }))
+            }
+            other => AppError::storage("database error")
+                .with_details(json!({ "source": other.to_string() })),
+        }
+    }
+}
+
+impl From<r2d2::Error> for AppError {
+    fn from(err: r2d2::Error) -> Self {
+        return AppError::storage("no database connection available")
+            .with_details(json!({ "source": err.to_string() }));
+    }
+}
+
+impl From<tokio::task::JoinError> for AppError {
+    fn from(err: tokio::task::JoinError) -> Self {
+        return AppError::internal("background task failed")
+            .with_details(json!({ "source": err.to_string() }));
+    }
+}
+
+impl From<QueryRejection> for AppError {
+    fn from(err: QueryRejection) -> Self {
+        return AppError::validation("invalid query parameters")
+            .with_details(json!({ "source": err.body_text() }));
+    }
+}
+
+impl From<JsonRejection> for AppError {
+    fn from(err: JsonRejection) -> Self {
+        return AppError::validation("invalid JSON body")
+            .with_details(json!({ "source": err.body_text() }));
+    }
+}
+
+impl From<PathRejection> for AppError {
+    fn from(err: PathRejection) -> Self {
+        return AppError::validation("invalid path parameter")
+            .with_details(json!({ "source": err.body_text() }));
+    }
+}
+
+impl From<ParseError> for AppError {
+    fn from(err: ParseError) -> Self {
+        return AppError::validation(format!("invalid query: {}", err.message))
+            .with_details(json!({ "position": err.position }));
+    }
+}
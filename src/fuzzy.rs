@@ -0,0 +1,92 @@
+// Edit distance between two words, counting insertions, deletions,
+// substitutions and swaps of two neighbouring letters ("progarm" -> "program")
+pub fn damerau_levenshtein(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let (n, m) = (a.len(), b.len());
+
+    // d[i][j] is the distance between the first i letters of a and first j of b
+    let mut d = vec![vec![0usize; m + 1]; n + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in d[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=n {
+        for j in 1..=m {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            let mut best = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                best = best.min(d[i - 2][j - 2] + 1);
+            }
+            d[i][j] = best;
+        }
+    }
+    return d[n][m];
+}
+
+// Typos allowed for a word of this many letters, short words must be exact
+pub fn max_edits(len: usize) -> usize {
+    match len {
+        0..=3 => 0,
+        4..=7 => 1,
+        _ => 2,
+    }
+}
+
+// Terms within the allowed number of edits of `word`, with their distance
+pub fn corrections<'a>(
+    terms: impl Iterator<Item = &'a String>,
+    word: &str,
+) -> Vec<(&'a String, usize)> {
+    let len = word.chars().count();
+    let limit = max_edits(len);
+    if limit == 0 {
+        return Vec::new();
+    }
+
+    let mut res: Vec<(&String, usize)> = Vec::new();
+    for term in terms {
+        // Cheap length check first, the distance can't be under the difference
+        if term.chars().count().abs_diff(len) > limit {
+            continue;
+        }
+        let dist = damerau_levenshtein(word, term);
+        if dist > 0 && dist <= limit {
+            res.push((term, dist));
+        }
+    }
+    return res;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn distance_counts_each_kind_of_edit() {
+        assert_eq!(damerau_levenshtein("program", "program"), 0);
+        assert_eq!(damerau_levenshtein("progam", "program"), 1);
+        assert_eq!(damerau_levenshtein("programm", "program"), 1);
+        assert_eq!(damerau_levenshtein("prigram", "program"), 1);
+        assert_eq!(damerau_levenshtein("progarm", "program"), 1);
+        assert_eq!(damerau_levenshtein("progamming", "programming"), 1);
+        assert_eq!(damerau_levenshtein("", "abc"), 3);
+        assert_eq!(damerau_levenshtein("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn allowed_edits_grow_with_length() {
+        let terms: Vec<String> = ["c99", "java", "javas", "python", "programming"]
+            .iter()
+            .map(|t| t.to_string())
+            .collect();
+        assert!(corrections(terms.iter(), "c98").is_empty());
+        assert_eq!(corrections(terms.iter(), "jvaa"), [(&terms[1], 1)]);
+        assert_eq!(corrections(terms.iter(), "pyhton"), [(&terms[3], 1)]);
+        assert_eq!(corrections(terms.iter(), "porgramminng"), [(&terms[4], 2)]);
+    }
+}
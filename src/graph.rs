@@ -0,0 +1,415 @@
+use crate::book::{Book, Tag};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+// Weighted undirected graph of tags built from `book_tags`: two tags are
+// linked by as many books as carry both of them. Kept in memory and updated
+// book by book. The book-tag links themselves are kept too, they make the
+// bipartite graph books and tags are walked through
+#[derive(Clone, Default)]
+pub struct TagGraph {
+    tags: BTreeMap<i32, String>,
+    book_tags: HashMap<i32, Vec<i32>>,
+    tag_books: HashMap<i32, BTreeSet<i32>>,
+    edges: HashMap<i32, HashMap<i32, u32>>,
+    // Bumped by every change, tells whether scores worked out from the
+    // graph are still current
+    generation: u64,
+}
+
+// A tag linked to another one, `weight` books carry both
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Neighbor {
+    pub id: i32,
+    pub name: String,
+    pub weight: u32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TagNeighbors {
+    pub tag: Tag,
+    pub neighbors: Vec<Neighbor>,
+}
+
+// How related books are scored
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Similarity {
+    #[default]
+    AdamicAdar,
+    Jaccard,
+}
+
+// A book sharing tags with another one, the shared tags explain why
+#[derive(Clone, Debug)]
+pub struct RelatedBook {
+    pub book_id: i32,
+    pub score: f64,
+    pub shared_tags: Vec<Tag>,
+}
+
+// Reply of the related books endpoints
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RelatedBooks {
+    pub id: i32,
+    pub method: Similarity,
+    pub related: Vec<Related>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Related {
+    pub book: Book,
+    pub score: f64,
+    pub shared_tags: Vec<Tag>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GraphNode {
+    pub id: i32,
+    pub name: String,
+    pub books: usize,
+}
+
+// Each edge is listed once, with `source` the lower tag id
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GraphEdge {
+    pub source: i32,
+    pub target: i32,
+    pub weight: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GraphJson {
+    pub nodes: Vec<GraphNode>,
+    pub edges: Vec<GraphEdge>,
+}
+
+impl TagGraph {
+    pub fn build(books: Vec<Book>, tags: Vec<Tag>) -> TagGraph {
+        let mut res = TagGraph::default();
+        for tag in tags {
+            res.upsert_tag(tag);
+        }
+        for book in books {
+            res.upsert_book(&book);
+        }
+        return res;
+    }
+
+    fn link(&mut self, a: i32, b: i32, change: i64) {
+        for (from, to) in [(a, b), (b, a)] {
+            let links = self.edges.entry(from).or_default();
+            let weight = links.entry(to).or_insert(0);
+            *weight = (*weight as i64 + change).max(0) as u32;
+            if *weight == 0 {
+                links.remove(&to);
+            }
+            if links.is_empty() {
+                self.edges.remove(&from);
+            }
+        }
+    }
+
+    // Count the links the tags of a book make, once per pair
+    fn add_links(&mut self, book_id: i32, tag_ids: &[i32], change: i64) {
+        for (i, a) in tag_ids.iter().enumerate() {
+            let books = self.tag_books.entry(*a).or_default();
+            if change > 0 {
+                books.insert(book_id);
+            } else {
+                books.remove(&book_id);
+            }
+            if books.is_empty() {
+                self.tag_books.remove(a);
+            }
+            for b in &tag_ids[i + 1..] {
+                self.link(*a, *b, change);
+            }
+        }
+    }
+
+    pub fn upsert_book(&mut self, book: &Book) {
+        self.remove_book(book.id);
+        self.generation += 1;
+        let mut tag_ids: Vec<i32> = book.tags.iter().map(|t| t.id).collect();
+        tag_ids.sort_unstable();
+        tag_ids.dedup();
+        self.add_links(book.id, &tag_ids, 1);
+        self.book_tags.insert(book.id, tag_ids);
+    }
+
+    pub fn remove_book(&mut self, book_id: i32) {
+        self.generation += 1;
+        if let Some(tag_ids) = self.book_tags.remove(&book_id) {
+            self.add_links(book_id, &tag_ids, -1);
+        }
+    }
+
+    // Links of a tag go with the books carrying it, which get synced on
+    // their own
+    pub fn upsert_tag(&mut self, tag: Tag) {
+        self.generation += 1;
+        self.tags.insert(tag.id, tag.name);
+    }
+
+    pub fn remove_tag(&mut self, tag_id: i32) {
+        self.generation += 1;
+        self.tags.remove(&tag_id);
+    }
+
+    pub fn tag(&self, tag_id: i32) -> Option<Tag> {
+        return self.tags.get(&tag_id).map(|name| Tag {
+            id: tag_id,
+            name: name.clone(),
+        });
+    }
+
+    pub fn has_book(&self, book_id: i32) -> bool {
+        return self.book_tags.contains_key(&book_id);
+    }
+
+    pub fn generation(&self) -> u64 {
+        return self.generation;
+    }
+
+    pub fn has_tag(&self, tag_id: i32) -> bool {
+        return self.tags.contains_key(&tag_id);
+    }
+
+    // Tags of a book, by id
+    pub fn tags_of(&self, book_id: i32) -> &[i32] {
+        return self
+            .book_tags
+            .get(&book_id)
+            .map(|t| t.as_slice())
+            .unwrap_or(&[]);
+    }
+
+    // Books carrying a tag, by id
+    pub fn books_of(&self, tag_id: i32) -> impl Iterator<Item = i32> + '_ {
+        return self.tag_books.get(&tag_id).into_iter().flatten().copied();
+    }
+
+    pub fn tag_ids(&self) -> impl Iterator<Item = i32> + '_ {
+        return self.tags.keys().copied();
+    }
+
+    pub fn book_ids(&self) -> impl Iterator<Item = i32> + '_ {
+        return self.book_tags.keys().copied();
+    }
+
+    // Tags linked to this one with their weight, in no particular order
+    pub fn links(&self, tag_id: i32) -> impl Iterator<Item = (i32, u32)> + '_ {
+        return self
+            .edges
+            .get(&tag_id)
+            .into_iter()
+            .flatten()
+            .map(|(t, w)| (*t, *w));
+    }
+
+    // Books sharing tags with this one, best first. Each shared tag counts
+    // for 1 / ln(books carrying it) with Adamic-Adar so a rare tag says
+    // more than one half the catalog carries, Jaccard is shared tags over
+    // the tags of both books
+    pub fn related_books(&self, book_id: i32, method: Similarity) -> Vec<RelatedBook> {
+        let Some(own) = self.book_tags.get(&book_id) else {
+            return Vec::new();
+        };
+        let mut shared: BTreeMap<i32, Vec<i32>> = BTreeMap::new();
+        for tag_id in own {
+            for other in self.tag_books.get(tag_id).into_iter().flatten() {
+                if *other != book_id {
+                    shared.entry(*other).or_default().push(*tag_id);
+                }
+            }
+        }
+
+        let mut res: Vec<RelatedBook> = Vec::new();
+        for (other, tag_ids) in shared {
+            let theirs = self.book_tags.get(&other).map(|t| t.len()).unwrap_or(0);
+            let union = own.len() + theirs - tag_ids.len();
+            let jaccard = tag_ids.len() as f64 / union as f64;
+            let adamic_adar: f64 = tag_ids
+                .iter()
+                .map(|t| {
+                    let books = self.tag_books.get(t).map(|b| b.len()).unwrap_or(2);
+                    1.0 / (books as f64).ln()
+                })
+                .sum();
+            let mut shared_tags: Vec<Tag> = tag_ids.iter().filter_map(|t| self.tag(*t)).collect();
+            shared_tags.sort_by(|a, b| a.name.cmp(&b.name));
+            res.push(RelatedBook {
+                book_id: other,
+                score: match method {
+                    Similarity::AdamicAdar => adamic_adar,
+                    Similarity::Jaccard => jaccard,
+                },
+                shared_tags,
+            });
+        }
+        res.sort_by(|a, b| {
+            b.score
+                .total_cmp(&a.score)
+                .then(b.shared_tags.len().cmp(&a.shared_tags.len()))
+                .then(a.book_id.cmp(&b.book_id))
+        });
+        return res;
+    }
+
+    // Tags sharing books with this one, most shared books first, then by name
+    pub fn neighbors(&self, tag_id: i32) -> Vec<Neighbor> {
+        let Some(links) = self.edges.get(&tag_id) else {
+            return Vec::new();
+        };
+        let mut res: Vec<Neighbor> = links
+            .iter()
+            .filter_map(|(id, weight)| {
+                Some(Neighbor {
+                    id: *id,
+                    name: self.tags.get(id)?.clone(),
+                    weight: *weight,
+                })
+            })
+            .collect();
+        res.sort_by(|a, b| {
+            b.weight
+                .cmp(&a.weight)
+                .then(a.name.cmp(&b.name))
+                .then(a.id.cmp(&b.id))
+        });
+        return res;
+    }
+
+    // Every tag and every edge of at least `min_weight`, ordered by id
+    pub fn to_json(&self, min_weight: u32) -> GraphJson {
+        let nodes = self
+            .tags
+            .iter()
+            .map(|(id, name)| GraphNode {
+                id: *id,
+                name: name.clone(),
+                books: self.tag_books.get(id).map(|b| b.len()).unwrap_or(0),
+            })
+            .collect();
+        let mut edges: Vec<GraphEdge> = Vec::new();
+        for (source, links) in &self.edges {
+            for (target, weight) in links {
+                if source < target
+                    && *weight >= min_weight
+                    && self.tags.contains_key(source)
+                    && self.tags.contains_key(target)
+                {
+                    edges.push(GraphEdge {
+                        source: *source,
+                        target: *target,
+                        weight: *weight,
+                    });
+                }
+            }
+        }
+        edges.sort_by_key(|e| (e.source, e.target));
+        return GraphJson { nodes, edges };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::book::test_support::{book, tags};
+
+    const PROG: i32 = 1;
+    const C: i32 = 2;
+    const PY: i32 = 3;
+    const POETRY: i32 = 4;
+
+    fn graph() -> TagGraph {
+        return TagGraph::build(
+            vec![
+                book(1, &[PROG, C]),
+                book(2, &[PROG, C, PY]),
+                book(3, &[PROG, PY]),
+                book(4, &[PROG, C]),
+            ],
+            tags(4),
+        );
+    }
+
+    fn weights(neighbors: &[Neighbor]) -> Vec<(i32, u32)> {
+        return neighbors.iter().map(|n| (n.id, n.weight)).collect();
+    }
+
+    #[test]
+    fn edges_count_shared_books() {
+        let g = graph();
+        assert_eq!(weights(&g.neighbors(1)), [(2, 3), (3, 2)]);
+        assert_eq!(weights(&g.neighbors(3)), [(1, 2), (2, 1)]);
+        assert!(g.neighbors(4).is_empty());
+
+        let json = g.to_json(1);
+        assert_eq!(json.nodes.len(), 4);
+        assert_eq!(json.nodes[0].books, 4);
+        assert_eq!(json.nodes[3].books, 0);
+        let edges: Vec<(i32, i32, u32)> = json
+            .edges
+            .iter()
+            .map(|e| (e.source, e.target, e.weight))
+            .collect();
+        assert_eq!(edges, [(1, 2, 3), (1, 3, 2), (2, 3, 1)]);
+        assert_eq!(g.to_json(2).edges.len(), 2);
+    }
+
+    fn related(g: &TagGraph, book_id: i32, method: Similarity) -> Vec<(i32, Vec<i32>)> {
+        return g
+            .related_books(book_id, method)
+            .into_iter()
+            .map(|r| (r.book_id, r.shared_tags.iter().map(|t| t.id).collect()))
+            .collect();
+    }
+
+    #[test]
+    fn related_books_share_rare_tags() {
+        let mut g = graph();
+        g.upsert_book(&book(5, &[PY, POETRY]));
+        g.upsert_book(&book(6, &[POETRY]));
+
+        // Python is rarer than programming, so book 5 sharing only python
+        // comes before books 1 and 4 sharing only programming
+        assert_eq!(
+            related(&g, 3, Similarity::AdamicAdar),
+            [(2, vec![1, 3]), (5, vec![3]), (1, vec![1]), (4, vec![1])]
+        );
+        // Book 5 shares half its tags with 6, a third with 3
+        assert_eq!(
+            related(&g, 5, Similarity::Jaccard),
+            [(6, vec![4]), (3, vec![3]), (2, vec![3])]
+        );
+        let best = &g.related_books(5, Similarity::Jaccard)[0];
+        assert_eq!(best.score, 0.5);
+        assert_eq!(best.shared_tags[0].name, "tag 4");
+
+        g.upsert_book(&book(7, &[]));
+        assert!(g.has_book(7));
+        assert!(g.related_books(7, Similarity::AdamicAdar).is_empty());
+        assert!(!g.has_book(8));
+    }
+
+    #[test]
+    fn edges_follow_book_changes() {
+        let mut g = graph();
+        g.remove_book(2);
+        assert_eq!(weights(&g.neighbors(1)), [(2, 2), (3, 1)]);
+        assert!(g.neighbors(2).iter().all(|n| n.id != 3));
+
+        // Retagged book moves its links
+        g.upsert_book(&book(4, &[PY, POETRY]));
+        assert_eq!(weights(&g.neighbors(1)), [(2, 1), (3, 1)]);
+        assert_eq!(weights(&g.neighbors(4)), [(3, 1)]);
+
+        g.remove_book(1);
+        g.remove_book(3);
+        g.remove_book(4);
+        assert!(g.to_json(1).edges.is_empty());
+        assert!(g.to_json(1).nodes.iter().all(|n| n.books == 0));
+    }
+}
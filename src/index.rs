@@ -0,0 +1,384 @@
+use crate::analyzer::{self, Lang};
+use crate::book::Book;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::RangeInclusive;
+
+// Part of a book a term was found in
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Field {
+    Title,
+    Author,
+    Tag,
+    Desc,
+    Year,
+}
+
+pub const FIELD_COUNT: usize = 5;
+
+impl Field {
+    pub fn slot(self) -> usize {
+        match self {
+            Field::Title => 0,
+            Field::Author => 1,
+            Field::Tag => 2,
+            Field::Desc => 3,
+            Field::Year => 4,
+        }
+    }
+}
+
+// One book containing a term, with how often the term shows up in that field
+#[derive(Clone, Debug, PartialEq)]
+pub struct Posting {
+    pub book_id: i32,
+    pub field: Field,
+    pub freq: u32,
+}
+
+// Term -> postings map kept in memory, updated book by book so a query
+// only touches the terms it asks for instead of the whole catalog
+#[derive(Default)]
+pub struct SearchIndex {
+    terms: BTreeMap<String, Vec<Posting>>,
+    books: HashMap<i32, Book>,
+    // Terms each book was indexed under, so it can be taken out again
+    book_terms: HashMap<i32, Vec<String>>,
+    // Number of books containing each term, kept up to date with the postings
+    doc_freqs: HashMap<String, usize>,
+    // Terms by their number of letters, typo lookups only go through the
+    // lengths a few edits away
+    by_length: BTreeMap<usize, BTreeSet<String>>,
+    // Number of terms in each field of each book, and summed over the catalog
+    field_lengths: HashMap<i32, [u32; FIELD_COUNT]>,
+    total_lengths: [u64; FIELD_COUNT],
+    // Language the title and description of each book were analyzed as
+    langs: HashMap<i32, Lang>,
+}
+
+// Terms of one field's text: titles and descriptions go through the
+// analyzer of the book's language, names are only normalized so an author
+// is found whatever language the query is in, tags are kept whole
+pub fn field_terms(field: Field, lang: Lang, text: &str) -> Vec<String> {
+    match field {
+        Field::Title | Field::Desc => analyzer::for_lang(lang).analyze(text),
+        Field::Author => analyzer::tokenize(text),
+        Field::Tag => vec![analyzer::normalize(text.trim())],
+        Field::Year => vec![text.trim().to_string()],
+    }
+}
+
+// Every (term, field) pair of a book, one entry per occurrence
+fn book_tokens(book: &Book, lang: Lang) -> Vec<(String, Field)> {
+    let mut res: Vec<(String, Field)> = Vec::new();
+    let mut push = |field: Field, text: &str| {
+        for term in field_terms(field, lang, text) {
+            if !term.is_empty() {
+                res.push((term, field));
+            }
+        }
+    };
+    push(Field::Title, &book.title);
+    push(Field::Author, &book.author);
+    for tag in &book.tags {
+        push(Field::Tag, &tag.name);
+    }
+    push(Field::Desc, &book.desc);
+    push(Field::Year, &book.year);
+    return res;
+}
+
+impl SearchIndex {
+    pub fn build(books: Vec<Book>) -> SearchIndex {
+        let mut index = SearchIndex::default();
+        for book in books {
+            index.upsert(book);
+        }
+        return index;
+    }
+
+    // Add a book, replacing whatever was indexed under its id before
+    pub fn upsert(&mut self, book: Book) {
+        self.remove(book.id);
+
+        let lang = analyzer::detect(&format!("{} {}", book.title, book.desc));
+        let mut counts: HashMap<(String, Field), u32> = HashMap::new();
+        let mut lengths = [0u32; FIELD_COUNT];
+        for (term, field) in book_tokens(&book, lang) {
+            lengths[field.slot()] += 1;
+            *counts.entry((term, field)).or_insert(0) += 1;
+        }
+
+        let mut terms: Vec<String> = Vec::new();
+        for ((term, field), freq) in &counts {
+            self.terms.entry(term.clone()).or_default().push(Posting {
+                book_id: book.id,
+                field: *field,
+                freq: *freq,
+            });
+            if !terms.contains(term) {
+                terms.push(term.clone());
+            }
+        }
+        for term in &terms {
+            let count = self.doc_freqs.entry(term.clone()).or_insert(0);
+            if *count == 0 {
+                self.by_length
+                    .entry(term.chars().count())
+                    .or_default()
+                    .insert(term.clone());
+            }
+            *count += 1;
+        }
+
+        for (total, len) in self.total_lengths.iter_mut().zip(lengths) {
+            *total += len as u64;
+        }
+        self.field_lengths.insert(book.id, lengths);
+        self.langs.insert(book.id, lang);
+        self.book_terms.insert(book.id, terms);
+        self.books.insert(book.id, book);
+    }
+
+    // Take a book out of the index, false when it was not there
+    pub fn remove(&mut self, book_id: i32) -> bool {
+        let Some(terms) = self.book_terms.remove(&book_id) else {
+            return false;
+        };
+        for term in terms {
+            if let Some(postings) = self.terms.get_mut(&term) {
+                postings.retain(|p| p.book_id != book_id);
+                if postings.is_empty() {
+                    self.terms.remove(&term);
+                }
+            }
+            if let Some(count) = self.doc_freqs.get_mut(&term) {
+                *count -= 1;
+                if *count == 0 {
+                    self.doc_freqs.remove(&term);
+                    let len = term.chars().count();
+                    if let Some(bucket) = self.by_length.get_mut(&len) {
+                        bucket.remove(&term);
+                        if bucket.is_empty() {
+                            self.by_length.remove(&len);
+                        }
+                    }
+                }
+            }
+        }
+        if let Some(lengths) = self.field_lengths.remove(&book_id) {
+            for (total, len) in self.total_lengths.iter_mut().zip(lengths) {
+                *total -= len as u64;
+            }
+        }
+        self.langs.remove(&book_id);
+        self.books.remove(&book_id);
+        return true;
+    }
+
+    pub fn book(&self, book_id: i32) -> Option<&Book> {
+        return self.books.get(&book_id);
+    }
+
+    pub fn lang(&self, book_id: i32) -> Lang {
+        return self.langs.get(&book_id).copied().unwrap_or(Lang::En);
+    }
+
+    pub fn book_ids(&self) -> impl Iterator<Item = i32> + '_ {
+        return self.books.keys().copied();
+    }
+
+    pub fn doc_count(&self) -> usize {
+        return self.books.len();
+    }
+
+    // Number of books containing `term` in any field
+    pub fn doc_freq(&self, term: &str) -> usize {
+        return self.doc_freqs.get(term).copied().unwrap_or(0);
+    }
+
+    pub fn field_length(&self, book_id: i32, field: Field) -> u32 {
+        return self
+            .field_lengths
+            .get(&book_id)
+            .map(|l| l[field.slot()])
+            .unwrap_or(0);
+    }
+
+    pub fn avg_field_length(&self, field: Field) -> f64 {
+        if self.books.is_empty() {
+            return 0.0;
+        }
+        return self.total_lengths[field.slot()] as f64 / self.books.len() as f64;
+    }
+
+    pub fn postings(&self, term: &str) -> &[Posting] {
+        return self.terms.get(term).map(|p| p.as_slice()).unwrap_or(&[]);
+    }
+
+    // Indexed terms with a number of letters in `lengths`
+    pub fn terms_of_length(&self, lengths: RangeInclusive<usize>) -> impl Iterator<Item = &String> {
+        return self
+            .by_length
+            .range(lengths)
+            .flat_map(|(_, terms)| terms.iter());
+    }
+
+    // Indexed terms starting with `prefix`, the term itself included
+    pub fn prefixed<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> impl Iterator<Item = (&'a String, &'a Vec<Posting>)> + 'a {
+        return self
+            .terms
+            .range(prefix.to_string()..)
+            .take_while(move |(term, _)| term.starts_with(prefix));
+    }
+
+    // Ids of the indexed books carrying any of the tags
+    pub fn books_with_tags(&self, tag_ids: &[i32]) -> Vec<i32> {
+        return self
+            .books
+            .values()
+            .filter(|b| b.tags.iter().any(|t| tag_ids.contains(&t.id)))
+            .map(|b| b.id)
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::book::test_support::{named, titled};
+    use crate::search::{parse_query, s_search_book, FilterSets, ResultSets, SearchOptions};
+
+    // Title term as the index stores it for this book
+    fn term(index: &SearchIndex, book_id: i32, word: &str) -> String {
+        return field_terms(Field::Title, index.lang(book_id), word).remove(0);
+    }
+
+    // Cached document frequencies agree with the postings
+    fn check_doc_freqs(index: &SearchIndex) {
+        for term in index.terms.keys() {
+            let mut ids: Vec<i32> = index.postings(term).iter().map(|p| p.book_id).collect();
+            ids.sort_unstable();
+            ids.dedup();
+            assert_eq!(index.doc_freq(term), ids.len(), "{}", term);
+        }
+        assert_eq!(index.doc_freqs.len(), index.terms.keys().count());
+        let mut bucketed: Vec<&String> = index.terms_of_length(0..=usize::MAX).collect();
+        bucketed.sort();
+        assert_eq!(bucketed, index.terms.keys().collect::<Vec<&String>>());
+    }
+
+    fn found(index: &SearchIndex, query: &str) -> Vec<i32> {
+        let parsed = parse_query(query).unwrap();
+        let res = s_search_book(
+            index,
+            &ResultSets::new(),
+            query,
+            &parsed,
+            &FilterSets::new(),
+            &SearchOptions::default(),
+        )
+        .unwrap();
+        let mut ids: Vec<i32> = res.page.items.iter().map(|r| r.book.id).collect();
+        ids.sort_unstable();
+        return ids;
+    }
+
+    #[test]
+    fn upsert_adds_postings() {
+        let mut index = SearchIndex::default();
+        index.upsert(titled(
+            1,
+            "Rust in Action, rust",
+            "Tim McNamara",
+            vec![named(1, "systems")],
+        ));
+        index.upsert(titled(2, "Rust for Rustaceans", "Jon Gjengset", Vec::new()));
+
+        let rust = term(&index, 1, "rust");
+        let postings = index.postings(&rust);
+        let title = postings.iter().find(|p| p.book_id == 1).unwrap();
+        assert_eq!((title.field, title.freq), (Field::Title, 2));
+        assert_eq!(index.doc_freq(&rust), 2);
+        assert_eq!(index.doc_freq("systems"), 1);
+        assert_eq!(index.postings("systems")[0].field, Field::Tag);
+        assert_eq!(index.doc_freq("gjengset"), 1);
+        assert_eq!(index.field_length(1, Field::Author), 2);
+        assert_eq!(index.avg_field_length(Field::Author), 2.0);
+        assert_eq!(index.doc_count(), 2);
+        assert_eq!(found(&index, "rust"), [1, 2]);
+        assert_eq!(found(&index, "systems"), [1]);
+        check_doc_freqs(&index);
+    }
+
+    #[test]
+    fn upsert_again_replaces_old_fields() {
+        let mut index = SearchIndex::default();
+        index.upsert(titled(
+            1,
+            "Rust in Action",
+            "Tim McNamara",
+            vec![named(1, "systems")],
+        ));
+        index.upsert(titled(2, "Rust for Rustaceans", "Jon Gjengset", Vec::new()));
+        index.upsert(titled(
+            1,
+            "Zig in Action",
+            "Tim McNamara",
+            vec![named(1, "embedded")],
+        ));
+
+        let rust = term(&index, 2, "rust");
+        assert_eq!(index.doc_freq(&rust), 1);
+        assert!(index.postings(&rust).iter().all(|p| p.book_id == 2));
+        assert!(index.postings("systems").is_empty());
+        assert!(!index.terms.keys().any(|t| t == "systems"));
+        assert_eq!(index.doc_freq("embedded"), 1);
+        assert_eq!(index.doc_freq(&term(&index, 1, "zig")), 1);
+        // Lengths are not counted twice
+        assert_eq!(index.avg_field_length(Field::Author), 2.0);
+        assert_eq!(index.doc_count(), 2);
+        assert_eq!(found(&index, "rust"), [2]);
+        assert_eq!(found(&index, "zig embedded"), [1]);
+        assert!(found(&index, "systems").is_empty());
+        check_doc_freqs(&index);
+    }
+
+    #[test]
+    fn remove_drops_every_trace() {
+        let mut index = SearchIndex::default();
+        index.upsert(titled(
+            1,
+            "Rust in Action",
+            "Tim McNamara",
+            vec![named(1, "systems")],
+        ));
+        index.upsert(titled(
+            2,
+            "Rust for Rustaceans",
+            "Jon Gjengset",
+            vec![named(1, "systems")],
+        ));
+
+        assert!(index.remove(1));
+        assert!(!index.remove(1));
+        assert!(index.book(1).is_none());
+        assert_eq!(index.doc_freq("systems"), 1);
+        assert_eq!(index.doc_freq("mcnamara"), 0);
+        assert!(index.postings("mcnamara").is_empty());
+        assert_eq!(index.field_length(1, Field::Title), 0);
+        assert_eq!(found(&index, "mcnamara"), Vec::<i32>::new());
+        assert_eq!(found(&index, "rust"), [2]);
+        check_doc_freqs(&index);
+
+        assert!(index.remove(2));
+        assert_eq!(index.terms.keys().count(), 0);
+        assert_eq!(index.avg_field_length(Field::Title), 0.0);
+        assert!(found(&index, "rust").is_empty());
+        check_doc_freqs(&index);
+    }
+}
@@ -1,15 +1,29 @@
+#![allow(clippy::needless_return)]
+
+mod analyzer;
 mod book;
+mod centrality;
+mod community;
+mod complete;
+mod error;
+mod fuzzy;
+mod graph;
+mod index;
+mod path;
+mod payload;
 mod search;
 mod serve;
 mod sql;
-use axum::{routing::get, routing::post, Router};
 use serve::*;
-use sql::set_sql_path_val;
+use sql::{create_pool, run_migrations};
 use std::env;
 
 struct ProgArgs {
     port: String,
     sql_path: String,
+    pool_size: u32,
+    migrate_only: bool,
+    dry_run: bool,
 }
 
 impl ProgArgs {
@@ -17,6 +31,9 @@ impl ProgArgs {
         return ProgArgs {
             port: "8081".to_string(),
             sql_path: "./db.sqlite".to_string(),
+            pool_size: 8,
+            migrate_only: false,
+            dry_run: false,
         };
     }
 }
@@ -33,17 +50,25 @@ fn parse_args(args: Vec<String>) -> Option<ProgArgs> {
         let current_arg = &args[idx];
         match &current_arg[..] {
             "-p" | "--port" => {
-                if idx + 1 <= args.len() - 1 {
+                if idx + 1 < args.len() {
                     res.port = args[idx + 1].clone();
                 }
                 idx += 1;
             }
             "-d" | "--databse" => {
-                if idx + 1 <= args.len() - 1 {
+                if idx + 1 < args.len() {
                     res.sql_path = args[idx + 1].clone();
                 }
                 idx += 1;
             }
+            "--pool-size" => {
+                if idx + 1 < args.len() {
+                    res.pool_size = args[idx + 1].parse().unwrap_or(res.pool_size);
+                }
+                idx += 1;
+            }
+            "--migrate-only" => res.migrate_only = true,
+            "--dry-run" => res.dry_run = true,
             _ => {}
         }
         idx += 1;
@@ -58,19 +83,43 @@ async fn main() {
     let ip: &str = "0.0.0.0";
     let port: &str = &parsed.port;
 
-    set_sql_path_val(&parsed.sql_path);
+    match run_migrations(&parsed.sql_path, parsed.dry_run) {
+        Ok(done) => {
+            let verb = if parsed.dry_run { "pending" } else { "applied" };
+            for m in &done {
+                println!("Migration {} {}: {}", m.version, verb, m.name);
+            }
+            if done.is_empty() {
+                println!("Database schema is up to date");
+            }
+        }
+        Err(e) => {
+            eprintln!("Migration failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+    if parsed.migrate_only || parsed.dry_run {
+        return;
+    }
+
+    let pool = match create_pool(&parsed.sql_path, parsed.pool_size) {
+        Ok(val) => val,
+        Err(e) => {
+            eprintln!("Failed to open database: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let state = match AppState::load(pool).await {
+        Ok(val) => val,
+        Err(e) => {
+            eprintln!("Failed to build the search index: {}", e);
+            std::process::exit(1);
+        }
+    };
 
     let combine: &str = &format!("{}:{}", ip, port);
 
-    let app = Router::new()
-        .route("/get_tag", get(get_tag))
-        .route("/search", get(search_book))
-        .route("/get_book_info", get(get_book_info))
-        .route("/get_book_from_tag", get(get_book_from_tag))
-        .route("/add_book", post(add_new_book))
-        .route("/add_tag", post(add_new_tag))
-        .route("/del_tag", post(del_tag))
-        .route("/del_book", post(del_book));
+    let app = router(state);
 
     let addr = tokio::net::TcpListener::bind(combine).await.unwrap();
 
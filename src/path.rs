@@ -0,0 +1,280 @@
+use crate::book::{Book, Tag};
+use crate::error::AppError;
+use crate::graph::TagGraph;
+use serde_derive::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::hash_map::Entry;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+
+// A node of the book-tag graph, books only link to tags and tags to books
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Node {
+    Book(i32),
+    Tag(i32),
+}
+
+impl Node {
+    // `book:{id}` or `tag:{id}`
+    pub fn from_param(text: &str) -> Result<Node, AppError> {
+        let invalid = || {
+            AppError::validation(format!(
+                "'{}' is not a node, use 'book:{{id}}' or 'tag:{{id}}'",
+                text
+            ))
+        };
+        let (kind, id) = text.trim().split_once(':').ok_or_else(invalid)?;
+        let id: i32 = id.trim().parse().map_err(|_| invalid())?;
+        match kind.trim() {
+            "book" => Ok(Node::Book(id)),
+            "tag" => Ok(Node::Tag(id)),
+            _ => Err(invalid()),
+        }
+    }
+
+    pub fn describe(self) -> String {
+        match self {
+            Node::Book(id) => format!("book {}", id),
+            Node::Tag(id) => format!("tag {}", id),
+        }
+    }
+}
+
+// How a path is measured
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PathCost {
+    // Fewest steps, found breadth first
+    #[default]
+    Hops,
+    // Going from one tag to another through a book costs 1 / the number of
+    // books carrying both, so the path sticks to tags often seen together
+    Weighted,
+}
+
+// A node of a path as sent to clients, `{"kind": "book", ..}` or `{"kind": "tag", ..}`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum PathStep {
+    Book(Book),
+    Tag(Tag),
+}
+
+// Reply of the path endpoints, `hops` is the number of steps and `cost`
+// what the path was measured with
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GraphPath {
+    pub method: PathCost,
+    pub hops: usize,
+    pub cost: f64,
+    pub path: Vec<PathStep>,
+}
+
+// Fewest steps from one node to another, None when nothing links them
+pub fn shortest_hops(graph: &TagGraph, from: Node, to: Node) -> Option<Vec<Node>> {
+    let mut prev: HashMap<Node, Node> = HashMap::new();
+    let mut queue: VecDeque<Node> = VecDeque::from([from]);
+    prev.insert(from, from);
+    while let Some(node) = queue.pop_front() {
+        if node == to {
+            return Some(walk_back(&prev, from, to));
+        }
+        let next: Vec<Node> = match node {
+            Node::Book(id) => graph.tags_of(id).iter().map(|t| Node::Tag(*t)).collect(),
+            Node::Tag(id) => graph.books_of(id).map(Node::Book).collect(),
+        };
+        for n in next {
+            if let Entry::Vacant(e) = prev.entry(n) {
+                e.insert(node);
+                queue.push_back(n);
+            }
+        }
+    }
+    return None;
+}
+
+fn walk_back(prev: &HashMap<Node, Node>, from: Node, to: Node) -> Vec<Node> {
+    let mut res = vec![to];
+    let mut node = to;
+    while node != from {
+        node = prev[&node];
+        res.push(node);
+    }
+    res.reverse();
+    return res;
+}
+
+// Entry of the Dijkstra queue, the lowest cost pops first
+struct Visit {
+    cost: f64,
+    tag: i32,
+}
+
+impl PartialEq for Visit {
+    fn eq(&self, other: &Self) -> bool {
+        return self.cmp(other) == Ordering::Equal;
+    }
+}
+
+impl Eq for Visit {}
+
+impl PartialOrd for Visit {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        return Some(self.cmp(other));
+    }
+}
+
+impl Ord for Visit {
+    fn cmp(&self, other: &Self) -> Ordering {
+        return other
+            .cost
+            .total_cmp(&self.cost)
+            .then(other.tag.cmp(&self.tag));
+    }
+}
+
+// Cheapest path with `PathCost::Weighted` and its cost. Dijkstra runs over
+// tags, each step to another tag goes through the lowest id book carrying
+// both. A book at either end costs nothing to reach its own tags
+pub fn cheapest(graph: &TagGraph, from: Node, to: Node) -> Option<(Vec<Node>, f64)> {
+    if from == to {
+        return Some((vec![from], 0.0));
+    }
+    let starts: Vec<i32> = match from {
+        Node::Book(id) => graph.tags_of(id).to_vec(),
+        Node::Tag(id) => vec![id],
+    };
+    let is_goal = |tag: i32| match to {
+        Node::Book(id) => graph.tags_of(id).contains(&tag),
+        Node::Tag(id) => id == tag,
+    };
+
+    let mut dist: HashMap<i32, f64> = HashMap::new();
+    // Tag reached from, and the book walked through
+    let mut prev: HashMap<i32, (i32, i32)> = HashMap::new();
+    let mut heap: BinaryHeap<Visit> = BinaryHeap::new();
+    for tag in starts {
+        dist.insert(tag, 0.0);
+        heap.push(Visit { cost: 0.0, tag });
+    }
+
+    while let Some(Visit { cost, tag }) = heap.pop() {
+        if cost > dist[&tag] {
+            continue;
+        }
+        if is_goal(tag) {
+            let mut res: Vec<Node> = Vec::new();
+            if let Node::Book(_) = to {
+                res.push(to);
+            }
+            let mut t = tag;
+            res.push(Node::Tag(t));
+            while let Some((before, book)) = prev.get(&t) {
+                res.push(Node::Book(*book));
+                res.push(Node::Tag(*before));
+                t = *before;
+            }
+            if let Node::Book(_) = from {
+                res.push(from);
+            }
+            res.reverse();
+            return Some((res, cost));
+        }
+        for (next, weight) in graph.links(tag) {
+            let next_cost = cost + 1.0 / weight as f64;
+            if dist.get(&next).is_some_and(|d| *d <= next_cost) {
+                continue;
+            }
+            let Some(book) = graph
+                .books_of(tag)
+                .find(|b| graph.tags_of(*b).contains(&next))
+            else {
+                continue;
+            };
+            dist.insert(next, next_cost);
+            prev.insert(next, (tag, book));
+            heap.push(Visit {
+                cost: next_cost,
+                tag: next,
+            });
+        }
+    }
+    return None;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::book::test_support::{book, tags};
+
+    // Tags 1-2-3 in a chain through single books, with a second route
+    // 1-4-3 seen on three books each, tag 5 on a book of its own
+    fn graph() -> TagGraph {
+        return TagGraph::build(
+            vec![
+                book(1, &[1, 2]),
+                book(2, &[2, 3]),
+                book(3, &[1, 4]),
+                book(4, &[1, 4]),
+                book(5, &[1, 4]),
+                book(6, &[4, 3]),
+                book(7, &[4, 3]),
+                book(8, &[4, 3]),
+                book(9, &[5]),
+            ],
+            tags(5),
+        );
+    }
+
+    #[test]
+    fn hops_alternate_books_and_tags() {
+        let g = graph();
+        assert_eq!(
+            shortest_hops(&g, Node::Book(1), Node::Book(2)),
+            Some(vec![Node::Book(1), Node::Tag(2), Node::Book(2)])
+        );
+        let path = shortest_hops(&g, Node::Tag(1), Node::Tag(3)).unwrap();
+        assert_eq!(path.len(), 5);
+        assert_eq!(
+            shortest_hops(&g, Node::Tag(5), Node::Tag(5)),
+            Some(vec![Node::Tag(5)])
+        );
+        assert_eq!(shortest_hops(&g, Node::Book(9), Node::Tag(1)), None);
+    }
+
+    #[test]
+    fn weighted_path_prefers_strong_links() {
+        let g = graph();
+        // 1-2-3 costs 2, 1-4-3 costs 2/3
+        let (path, cost) = cheapest(&g, Node::Tag(1), Node::Tag(3)).unwrap();
+        assert_eq!(
+            path,
+            [
+                Node::Tag(1),
+                Node::Book(3),
+                Node::Tag(4),
+                Node::Book(6),
+                Node::Tag(3)
+            ]
+        );
+        assert!((cost - 2.0 / 3.0).abs() < 1e-9);
+
+        let (path, cost) = cheapest(&g, Node::Book(1), Node::Book(6)).unwrap();
+        assert_eq!(path.first(), Some(&Node::Book(1)));
+        assert_eq!(path.last(), Some(&Node::Book(6)));
+        assert_eq!(path[1], Node::Tag(1));
+        assert!((cost - 1.0 / 3.0).abs() < 1e-9);
+
+        let (path, cost) = cheapest(&g, Node::Book(3), Node::Book(4)).unwrap();
+        assert_eq!(path.len(), 3);
+        assert_eq!(cost, 0.0);
+        assert!(cheapest(&g, Node::Tag(5), Node::Book(1)).is_none());
+    }
+
+    #[test]
+    fn nodes_are_parsed() {
+        assert_eq!(Node::from_param("book:3").unwrap(), Node::Book(3));
+        assert_eq!(Node::from_param(" tag: 7 ").unwrap(), Node::Tag(7));
+        assert!(Node::from_param("3").is_err());
+        assert!(Node::from_param("shelf:3").is_err());
+    }
+}
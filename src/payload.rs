@@ -0,0 +1,195 @@
+use crate::error::AppError;
+use axum::{
+    async_trait,
+    extract::{FromRequest, Multipart, Query, Request},
+    http::{header, HeaderMap, HeaderValue},
+    Form, Json,
+};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Map, Value};
+
+// Types that can still be built from the old query-string form of their route
+pub trait FromLegacyQuery: Sized {
+    type Params: DeserializeOwned;
+    fn from_legacy(params: Self::Params) -> Result<Self, AppError>;
+}
+
+// Types read from the text fields of a multipart or form body. Those fields
+// are all strings, the ones named here are turned into numbers when they are
+// all digits so they read as ids
+pub trait FromTextFields: DeserializeOwned {
+    const ID_FIELDS: &'static [&'static str] = &[];
+}
+
+// Request body read from `application/json`, `multipart/form-data`,
+// `application/x-www-form-urlencoded`, or the deprecated query string
+// when the request has no body
+pub struct Payload<T> {
+    pub value: T,
+    pub legacy: bool,
+}
+
+impl<T> Payload<T> {
+    // Headers to send back, flags callers still on the query-string form
+    pub fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if self.legacy {
+            headers.insert("deprecation", HeaderValue::from_static("true"));
+            headers.insert(
+                header::WARNING,
+                HeaderValue::from_static(
+                    "299 - \"query string bodies are deprecated, send JSON or a form body\"",
+                ),
+            );
+        }
+        return headers;
+    }
+}
+
+#[async_trait]
+impl<S, T> FromRequest<S> for Payload<T>
+where
+    S: Send + Sync,
+    T: FromTextFields + FromLegacyQuery,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let content_type = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("")
+            .to_lowercase();
+
+        if content_type.starts_with("application/json") {
+            let Json(value) = Json::<T>::from_request(req, state).await.map_err(|e| {
+                AppError::validation("invalid JSON body")
+                    .with_details(json!({ "source": e.body_text() }))
+            })?;
+            return Ok(Payload {
+                value,
+                legacy: false,
+            });
+        }
+
+        if content_type.starts_with("multipart/form-data") {
+            let multipart = Multipart::from_request(req, state).await.map_err(|e| {
+                AppError::validation("invalid multipart body")
+                    .with_details(json!({ "source": e.body_text() }))
+            })?;
+            let fields = read_multipart(multipart).await?;
+            return Ok(Payload {
+                value: from_fields(fields, "multipart")?,
+                legacy: false,
+            });
+        }
+
+        if content_type.starts_with("application/x-www-form-urlencoded") {
+            let uri = req.uri().clone();
+            let Form(pairs) = Form::<Vec<(String, String)>>::from_request(req, state)
+                .await
+                .map_err(|e| {
+                    AppError::validation("invalid form body")
+                        .with_details(json!({ "source": e.body_text() }))
+                })?;
+            // Old clients send this content type with an empty body and
+            // everything in the query string
+            if !pairs.is_empty() {
+                let mut fields: Map<String, Value> = Map::new();
+                for (name, text) in pairs {
+                    push_field(&mut fields, name, text);
+                }
+                return Ok(Payload {
+                    value: from_fields(fields, "form")?,
+                    legacy: false,
+                });
+            }
+            let Query(params) = Query::<T::Params>::try_from_uri(&uri)?;
+            return Ok(Payload {
+                value: T::from_legacy(params)?,
+                legacy: true,
+            });
+        }
+
+        if !content_type.is_empty() {
+            return Err(AppError::validation(format!(
+                "unsupported content type '{}', send JSON, multipart or urlencoded form data",
+                content_type
+            )));
+        }
+
+        let Query(params) = Query::<T::Params>::from_request(req, state).await?;
+        return Ok(Payload {
+            value: T::from_legacy(params)?,
+            legacy: true,
+        });
+    }
+}
+
+// Text fields of a multipart body
+async fn read_multipart(mut multipart: Multipart) -> Result<Map<String, Value>, AppError> {
+    let mut fields: Map<String, Value> = Map::new();
+    loop {
+        let field = multipart.next_field().await.map_err(|e| {
+            AppError::validation("invalid multipart body")
+                .with_details(json!({ "source": e.body_text() }))
+        })?;
+        let Some(field) = field else {
+            break;
+        };
+        let name = match field.name() {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        let text = field.text().await.map_err(|e| {
+            AppError::validation(format!("field '{}' is not valid text", name))
+                .with_details(json!({ "source": e.body_text() }))
+        })?;
+        push_field(&mut fields, name, text);
+    }
+    return Ok(fields);
+}
+
+// Add one text field, a name sent more than once becomes an array. A
+// trailing `[]` on the name is dropped
+fn push_field(fields: &mut Map<String, Value>, name: String, text: String) {
+    let name = name.trim_end_matches("[]").to_string();
+    match fields.get_mut(&name) {
+        Some(Value::Array(items)) => items.push(Value::String(text)),
+        Some(existing) => {
+            let first = existing.take();
+            *existing = Value::Array(vec![first, Value::String(text)]);
+        }
+        None => {
+            fields.insert(name, Value::String(text));
+        }
+    }
+}
+
+// The body type from the text fields of a multipart or form body
+fn from_fields<T: FromTextFields>(
+    mut fields: Map<String, Value>,
+    kind: &str,
+) -> Result<T, AppError> {
+    for name in T::ID_FIELDS {
+        match fields.get_mut(*name) {
+            Some(Value::Array(items)) => items.iter_mut().for_each(digits_to_id),
+            Some(value) => digits_to_id(value),
+            None => {}
+        }
+    }
+    return serde_json::from_value::<T>(Value::Object(fields)).map_err(|e| {
+        AppError::validation(format!("invalid {} body", kind))
+            .with_details(json!({ "source": e.to_string() }))
+    });
+}
+
+fn digits_to_id(value: &mut Value) {
+    if let Some(id) = value
+        .as_str()
+        .and_then(|text| text.trim().parse::<i32>().ok())
+    {
+        *value = Value::from(id);
+    }
+}
@@ -1,128 +1,2182 @@
 use serde_derive::Deserialize;
 use serde_derive::Serialize;
 
-use crate::book;
-use crate::sql;
-use std::collections::HashMap;
-
-#[derive(PartialEq, PartialOrd, Serialize, Deserialize, Clone)]
-struct SortedData {
-    index: i32,
-    pub score: f64,
-}
+use crate::analyzer::{self, Lang};
+use crate::book::{self, Page};
+use crate::error::AppError;
+use crate::fuzzy;
+use crate::index::{field_terms, Field, SearchIndex};
+use crate::sql::SortOrder;
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Deserialize, Serialize)]
 pub struct SearchResult {
-    book: book::Book,
+    pub book: book::Book,
     score: f64,
+    matches: Vec<FieldMatch>,
+}
+
+// One field of a result that matched, the indexed terms it matched on and
+// the field text with those terms wrapped in `<mark>`
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct FieldMatch {
+    field: Field,
+    terms: Vec<String>,
+    snippet: String,
+}
+
+// Words of context kept on each side of the first hit in a description
+const SNIPPET_CONTEXT: usize = 10;
+
+// BM25 saturation and length normalization
+const K1: f64 = 1.2;
+const B: f64 = 0.75;
+
+// A query word that is only a prefix of the indexed term counts for less
+const PREFIX_WEIGHT: f64 = 0.5;
+
+// Shorter words only match whole terms, "c" would otherwise pull in every
+// term starting with a c
+const MIN_PREFIX_LEN: usize = 3;
+
+// Most terms a prefix stands for, the ones in the most books are kept
+const MAX_PREFIX_TERMS: usize = 50;
+
+// A term reached through a typo counts for this much, divided by the edits
+const FUZZY_WEIGHT: f64 = 0.4;
+
+// How much one occurrence in each field is worth
+fn field_weight(field: Field) -> f64 {
+    match field {
+        Field::Title => 3.0,
+        Field::Author => 2.0,
+        Field::Tag => 2.0,
+        Field::Desc => 1.0,
+        Field::Year => 1.0,
+    }
+}
+
+fn idf(index: &SearchIndex, term: &str) -> f64 {
+    let n = index.doc_count() as f64;
+    let df = index.doc_freq(term) as f64;
+    return (1.0 + (n - df + 0.5) / (df + 0.5)).ln();
+}
+
+// One word of the query with the forms it is looked up under: its stem
+// for titles and descriptions, the word itself for names, tags and years
+struct QueryWord {
+    word: String,
+    forms: Vec<String>,
+}
+
+// Normalized query words minus stopwords, each once
+fn query_words(text: &str, lang: Lang) -> Vec<QueryWord> {
+    let analyzer = analyzer::for_lang(lang);
+    let mut res: Vec<QueryWord> = Vec::new();
+    for token in analyzer::tokenize(text) {
+        if analyzer.is_stopword(&token) || res.iter().any(|w| w.word == token) {
+            continue;
+        }
+        let mut forms = vec![analyzer.stem(&token)];
+        if !forms.contains(&token) {
+            forms.push(token.clone());
+        }
+        res.push(QueryWord { word: token, forms });
+    }
+    return res;
+}
+
+// BM25F score of one indexed term for every book containing it, in one
+// field only when `field` is given
+fn term_scores(index: &SearchIndex, term: &str, field: Option<Field>) -> HashMap<i32, f64> {
+    let mut weighted_tf: HashMap<i32, f64> = HashMap::new();
+    for p in index
+        .postings(term)
+        .iter()
+        .filter(|p| field.is_none_or(|f| p.field == f))
+    {
+        let avg = index.avg_field_length(p.field);
+        let len = index.field_length(p.book_id, p.field) as f64;
+        let norm = if avg > 0.0 {
+            1.0 - B + B * len / avg
+        } else {
+            1.0
+        };
+        *weighted_tf.entry(p.book_id).or_insert(0.0) +=
+            field_weight(p.field) * p.freq as f64 / norm;
+    }
+
+    let idf = idf(index, term);
+    let mut res: HashMap<i32, f64> = HashMap::new();
+    for (book_id, tf) in weighted_tf {
+        res.insert(book_id, idf * tf * (K1 + 1.0) / (K1 + tf));
+    }
+    return res;
+}
+
+fn keep_best<'a>(found: &mut HashMap<&'a String, f64>, term: &'a String, weight: f64) {
+    let entry = found.entry(term).or_insert(0.0);
+    *entry = entry.max(weight);
+}
+
+// Indexed terms a query word stands for and how much each counts,
+// typos are only looked for when no term starts with the word
+fn expand<'a>(index: &'a SearchIndex, word: &'a QueryWord) -> Vec<(&'a String, f64)> {
+    let mut found: HashMap<&String, f64> = HashMap::new();
+    for form in &word.forms {
+        if form.chars().count() < MIN_PREFIX_LEN {
+            if let Some((term, _)) = index.prefixed(form).next().filter(|(t, _)| *t == form) {
+                keep_best(&mut found, term, 1.0);
+            }
+            continue;
+        }
+        let mut longer: Vec<&String> = Vec::new();
+        for (term, _) in index.prefixed(form) {
+            if term == form {
+                keep_best(&mut found, term, 1.0);
+            } else {
+                longer.push(term);
+            }
+        }
+        if longer.len() > MAX_PREFIX_TERMS {
+            longer.sort_by(|a, b| index.doc_freq(b).cmp(&index.doc_freq(a)).then(a.cmp(b)));
+            longer.truncate(MAX_PREFIX_TERMS);
+        }
+        for term in longer {
+            keep_best(&mut found, term, PREFIX_WEIGHT);
+        }
+    }
+    if found.is_empty() {
+        for form in &word.forms {
+            for (term, dist) in near_terms(index, form) {
+                keep_best(&mut found, term, FUZZY_WEIGHT / dist as f64);
+            }
+        }
+    }
+    let mut res: Vec<(&String, f64)> = found.into_iter().collect();
+    res.sort_by(|a, b| a.0.cmp(b.0));
+    return res;
+}
+
+// Indexed terms a few typos away from `form`, only terms of a length
+// within reach are compared
+fn near_terms<'a>(index: &'a SearchIndex, form: &str) -> Vec<(&'a String, usize)> {
+    let len = form.chars().count();
+    let limit = fuzzy::max_edits(len);
+    if limit == 0 {
+        return Vec::new();
+    }
+    let lengths = len.saturating_sub(limit)..=len + limit;
+    return fuzzy::corrections(index.terms_of_length(lengths), form);
+}
+
+// Closest indexed term for a word nothing starts with, fewest edits first,
+// then the term found in the most books
+fn correction(index: &SearchIndex, word: &QueryWord) -> Option<String> {
+    if word
+        .forms
+        .iter()
+        .any(|f| index.prefixed(f).next().is_some())
+    {
+        return None;
+    }
+    let mut candidates: Vec<(&String, usize)> = Vec::new();
+    for form in &word.forms {
+        candidates.extend(near_terms(index, form));
+    }
+    candidates.sort_by(|a, b| {
+        a.1.cmp(&b.1)
+            .then(index.doc_freq(b.0).cmp(&index.doc_freq(a.0)))
+            .then(a.0.cmp(b.0))
+    });
+    return candidates.first().map(|(term, _)| term.to_string());
+}
+
+// Exact conditions of a query, answered by the database instead of the index
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Filter {
+    // Inclusive, either end may be left open
+    Year { from: Option<i32>, to: Option<i32> },
+    Tag(String),
+}
+
+// Books passing each filter of a query, filled in by the sql layer
+pub type FilterSets = HashMap<Filter, HashSet<i32>>;
+
+// Parsed `/search` query. Terms written next to each other without an
+// operator are alternatives, filters and negations next to them must hold:
+// `a b year:2000 -c` is `(a OR b) AND year:2000 AND NOT c`
+#[derive(Clone, Debug, PartialEq)]
+pub enum QueryNode {
+    // One word, `span` is where it sits in the query, in chars
+    Word {
+        field: Option<Field>,
+        text: String,
+        span: (usize, usize),
+    },
+    Phrase {
+        field: Option<Field>,
+        text: String,
+    },
+    Filter(Filter),
+    And(Vec<QueryNode>),
+    Or(Vec<QueryNode>),
+    Not(Box<QueryNode>),
 }
 
-fn vectorize_book(documents: &Vec<book::Book>) -> Vec<HashMap<String, f64>> {
-    let mut all_word_count: Vec<HashMap<String, f64>> = Vec::new();
-    for doc in documents {
-        let mut word_count = HashMap::new();
-        for word in doc.title.clone().split_whitespace() {
-            *word_count
-                .entry(word.to_lowercase().to_string())
-                .or_insert(0.0) += 1.0;
+impl QueryNode {
+    // Every filter used anywhere in the query, each once
+    pub fn filters(&self) -> Vec<Filter> {
+        let mut res: Vec<Filter> = Vec::new();
+        self.collect_filters(&mut res);
+        return res;
+    }
+
+    fn collect_filters(&self, res: &mut Vec<Filter>) {
+        match self {
+            QueryNode::Filter(f) => {
+                if !res.contains(f) {
+                    res.push(f.clone());
+                }
+            }
+            QueryNode::And(nodes) | QueryNode::Or(nodes) => {
+                for n in nodes {
+                    n.collect_filters(res);
+                }
+            }
+            QueryNode::Not(inner) => inner.collect_filters(res),
+            QueryNode::Word { .. } | QueryNode::Phrase { .. } => {}
         }
-        for word in doc.author.clone().split_whitespace() {
-            *word_count
-                .entry(word.to_lowercase().to_string())
-                .or_insert(0.0) += 1.0;
+    }
+
+    // Words and phrases the query looks for, leaving out negated ones
+    fn positive_text(&self, res: &mut Vec<String>) {
+        match self {
+            QueryNode::Word { text, .. } | QueryNode::Phrase { text, .. } => res.push(text.clone()),
+            QueryNode::And(nodes) | QueryNode::Or(nodes) => {
+                for n in nodes {
+                    n.positive_text(res);
+                }
+            }
+            QueryNode::Filter(_) | QueryNode::Not(_) => {}
         }
-        for word in doc.tags.clone() {
-            *word_count
-                .entry(word.name.to_lowercase().to_string())
-                .or_insert(0.0) += 1.0;
+    }
+
+    // Feed what the query asks for into `state`, words go in normalized
+    // and where they sit in the text is left out
+    fn hash_terms<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            QueryNode::Word { field, text, .. } | QueryNode::Phrase { field, text } => {
+                field.hash(state);
+                analyzer::tokenize(text).hash(state);
+            }
+            QueryNode::Filter(f) => f.hash(state),
+            QueryNode::And(nodes) | QueryNode::Or(nodes) => {
+                nodes.len().hash(state);
+                for n in nodes {
+                    n.hash_terms(state);
+                }
+            }
+            QueryNode::Not(inner) => inner.hash_terms(state),
         }
-        *word_count.entry(doc.year.to_string()).or_insert(0.0) += 1.0;
-        all_word_count.push(word_count);
     }
+}
 
-    let mut result: Vec<HashMap<String, f64>> = Vec::new();
+// Why a query could not be parsed, `position` counts chars from the start
+#[derive(Debug, PartialEq)]
+pub struct ParseError {
+    pub position: usize,
+    pub message: String,
+}
 
-    for i in 0..all_word_count.len() {
-        result.push(all_word_count[i].clone());
-        if i < all_word_count.len() - 1 {
-            let tmp = all_word_count[i + 1].clone();
-            for (key, _) in tmp {
-                let _ = *result[i].entry(key).or_insert(0.0);
+fn parse_error(position: usize, message: impl Into<String>) -> ParseError {
+    return ParseError {
+        position,
+        message: message.into(),
+    };
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Phrase(String),
+    // `name:` written right in front of its value
+    Field(String),
+    Open,
+    Close,
+    And,
+    Or,
+    Not,
+}
+
+// Tokens with their start and end in chars
+fn lex(text: &str) -> Result<Vec<(Token, usize, usize)>, ParseError> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut res: Vec<(Token, usize, usize)> = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        if c == '(' || c == ')' {
+            let token = if c == '(' { Token::Open } else { Token::Close };
+            res.push((token, i, i + 1));
+            i += 1;
+            continue;
+        }
+        if c == '"' {
+            let Some(len) = chars[i + 1..].iter().position(|c| *c == '"') else {
+                return Err(parse_error(i, "unterminated phrase, missing '\"'"));
+            };
+            let end = i + 1 + len;
+            res.push((
+                Token::Phrase(chars[i + 1..end].iter().collect()),
+                i,
+                end + 1,
+            ));
+            i = end + 1;
+            continue;
+        }
+        if c == '-' && chars.get(i + 1).is_some_and(|n| !n.is_whitespace()) {
+            res.push((Token::Not, i, i + 1));
+            i += 1;
+            continue;
+        }
+
+        let start = i;
+        while i < chars.len() && !chars[i].is_whitespace() && !matches!(chars[i], '(' | ')' | '"') {
+            // `name:value`, a colon followed by a space ("C: The...") stays in the word
+            let is_field = chars[i] == ':'
+                && i > start
+                && chars[start..i].iter().all(|c| c.is_alphabetic())
+                && chars.get(i + 1).is_some_and(|n| !n.is_whitespace());
+            if is_field {
+                break;
             }
+            i += 1;
+        }
+        let word: String = chars[start..i].iter().collect();
+        if chars.get(i) == Some(&':') {
+            res.push((Token::Field(word), start, i + 1));
+            i += 1;
+            continue;
+        }
+        let token = match word.as_str() {
+            "AND" => Token::And,
+            "OR" => Token::Or,
+            "NOT" => Token::Not,
+            _ => Token::Word(word),
+        };
+        res.push((token, start, i));
+    }
+    return Ok(res);
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize, usize)>,
+    next: usize,
+    // Length of the query, where errors at the end point to
+    len: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        return self.tokens.get(self.next).map(|t| &t.0);
+    }
+
+    fn position(&self) -> usize {
+        return self.tokens.get(self.next).map(|t| t.1).unwrap_or(self.len);
+    }
+
+    fn advance(&mut self) -> Option<(Token, usize, usize)> {
+        let res = self.tokens.get(self.next).cloned();
+        self.next += 1;
+        return res;
+    }
+
+    fn parse_or(&mut self) -> Result<QueryNode, ParseError> {
+        let mut nodes = vec![self.parse_and()?];
+        while self.peek() == Some(&Token::Or) {
+            self.advance();
+            nodes.push(self.parse_and()?);
+        }
+        return Ok(if nodes.len() == 1 {
+            nodes.remove(0)
         } else {
-            let tmp = all_word_count[0].clone();
-            for (key, _) in tmp {
-                let _ = *result[i].entry(key).or_insert(0.0);
+            QueryNode::Or(nodes)
+        });
+    }
+
+    fn parse_and(&mut self) -> Result<QueryNode, ParseError> {
+        let mut nodes = vec![self.parse_group()?];
+        while self.peek() == Some(&Token::And) {
+            self.advance();
+            nodes.push(self.parse_group()?);
+        }
+        return Ok(if nodes.len() == 1 {
+            nodes.remove(0)
+        } else {
+            QueryNode::And(nodes)
+        });
+    }
+
+    // Clauses written one after the other with no operator between them
+    fn parse_group(&mut self) -> Result<QueryNode, ParseError> {
+        let mut text: Vec<QueryNode> = Vec::new();
+        let mut must: Vec<QueryNode> = Vec::new();
+        loop {
+            match self.parse_unary()? {
+                node @ (QueryNode::Filter(_) | QueryNode::Not(_)) => must.push(node),
+                node => text.push(node),
             }
+            if matches!(
+                self.peek(),
+                None | Some(Token::Close | Token::And | Token::Or)
+            ) {
+                break;
+            }
+        }
+        match text.len() {
+            0 => {}
+            1 => must.insert(0, text.remove(0)),
+            _ => must.insert(0, QueryNode::Or(text)),
         }
+        return Ok(if must.len() == 1 {
+            must.remove(0)
+        } else {
+            QueryNode::And(must)
+        });
     }
 
-    return result;
+    fn parse_unary(&mut self) -> Result<QueryNode, ParseError> {
+        if self.peek() == Some(&Token::Not) {
+            self.advance();
+            return Ok(QueryNode::Not(Box::new(self.parse_unary()?)));
+        }
+        return self.parse_primary();
+    }
+
+    fn parse_primary(&mut self) -> Result<QueryNode, ParseError> {
+        let at = self.position();
+        let Some((token, start, end)) = self.advance() else {
+            return Err(parse_error(
+                at,
+                "expected a search term at the end of the query",
+            ));
+        };
+        match token {
+            Token::Word(text) => {
+                return Ok(QueryNode::Word {
+                    field: None,
+                    text,
+                    span: (start, end),
+                })
+            }
+            Token::Phrase(text) => return Ok(QueryNode::Phrase { field: None, text }),
+            Token::Field(name) => return self.parse_field(&name, start, end),
+            Token::Open => {
+                let inner = self.parse_or()?;
+                if self.peek() != Some(&Token::Close) {
+                    return Err(parse_error(start, "'(' is never closed"));
+                }
+                self.advance();
+                return Ok(inner);
+            }
+            Token::Close => return Err(parse_error(start, "unexpected ')'")),
+            Token::And | Token::Or | Token::Not => {
+                let op = match token {
+                    Token::And => "AND",
+                    Token::Or => "OR",
+                    _ => "NOT",
+                };
+                return Err(parse_error(
+                    start,
+                    format!("expected a search term before '{}'", op),
+                ));
+            }
+        }
+    }
+
+    // Value of `name:`, right after it
+    fn parse_field(
+        &mut self,
+        name: &str,
+        start: usize,
+        end: usize,
+    ) -> Result<QueryNode, ParseError> {
+        let field = match name.to_lowercase().as_str() {
+            "title" => Some(Field::Title),
+            "author" => Some(Field::Author),
+            "desc" | "description" => Some(Field::Desc),
+            "tag" => Some(Field::Tag),
+            "year" => Some(Field::Year),
+            _ => None,
+        };
+        let Some(field) = field else {
+            return Err(parse_error(
+                start,
+                format!(
+                    "unknown field '{}', use title, author, desc, tag or year",
+                    name
+                ),
+            ));
+        };
+        let (value, value_start, value_end, phrase) = match self.advance() {
+            Some((Token::Word(w), s, e)) => (w, s, e, false),
+            Some((Token::Phrase(p), s, e)) => (p, s, e, true),
+            _ => {
+                return Err(parse_error(
+                    end,
+                    format!("expected a value after '{}:'", name),
+                ))
+            }
+        };
+
+        match field {
+            Field::Tag => return Ok(QueryNode::Filter(Filter::Tag(value.trim().to_string()))),
+            Field::Year => return parse_years(&value, value_start),
+            _ if phrase => {
+                return Ok(QueryNode::Phrase {
+                    field: Some(field),
+                    text: value,
+                })
+            }
+            _ => {
+                return Ok(QueryNode::Word {
+                    field: Some(field),
+                    text: value,
+                    span: (value_start, value_end),
+                })
+            }
+        }
+    }
 }
 
-fn vectorize_word(words: &str, vector_book: Vec<HashMap<String, f64>>) -> HashMap<String, f64> {
-    let mut result: HashMap<String, f64> = HashMap::new();
-    let keywords: Vec<String> = words.split_whitespace().map(|w| w.to_lowercase()).collect();
+// `2000`, `1990..2005`, `1990..` or `..2005`
+fn parse_years(value: &str, at: usize) -> Result<QueryNode, ParseError> {
+    let year = |s: &str| -> Result<Option<i32>, ParseError> {
+        if s.trim().is_empty() {
+            return Ok(None);
+        }
+        return s
+            .trim()
+            .parse::<i32>()
+            .map(Some)
+            .map_err(|_| parse_error(at, format!("'{}' is not a year", s)));
+    };
+    let (from, to) = match value.split_once("..") {
+        Some((a, b)) => (year(a)?, year(b)?),
+        None => {
+            let y = year(value)?;
+            (y, y)
+        }
+    };
+    if from.is_none() && to.is_none() {
+        return Err(parse_error(at, "year range needs at least one end"));
+    }
+    if let (Some(a), Some(b)) = (from, to) {
+        if a > b {
+            return Err(parse_error(
+                at,
+                format!("year range {}..{} ends before it starts", a, b),
+            ));
+        }
+    }
+    return Ok(QueryNode::Filter(Filter::Year { from, to }));
+}
 
-    for w in keywords {
-        *result.entry(w.clone()).or_insert(0.0) += 1.0;
+// Parse a `/search` query, an empty one matches nothing
+pub fn parse_query(text: &str) -> Result<QueryNode, ParseError> {
+    let tokens = lex(text)?;
+    if tokens.is_empty() {
+        return Ok(QueryNode::Or(Vec::new()));
+    }
+    let mut parser = Parser {
+        tokens,
+        next: 0,
+        len: text.chars().count(),
+    };
+    let root = parser.parse_or()?;
+    if parser.peek().is_some() {
+        return Err(parse_error(parser.position(), "unexpected ')'"));
+    }
+    return Ok(root);
+}
+
+fn field_text(book: &book::Book, field: Field) -> &str {
+    match field {
+        Field::Title => &book.title,
+        Field::Author => &book.author,
+        Field::Desc => &book.desc,
+        Field::Year => &book.year,
+        Field::Tag => "",
+    }
+}
 
-        for obj in &vector_book {
-            for (key, _) in obj {
-                // if key.contains(&w) && w.len() >= 2{
-                if key.contains(&w) {
-                    *result.entry(key.clone()).or_insert(0.0) += 1.0;
+// Runs a parsed query against the index
+struct Matcher<'a> {
+    index: &'a SearchIndex,
+    filters: &'a FilterSets,
+    lang: Lang,
+}
+
+impl Matcher<'_> {
+    // Score of each book for one query word, through its best matching term
+    fn word_scores(&self, word: &QueryWord, field: Option<Field>) -> HashMap<i32, f64> {
+        let mut best: HashMap<i32, f64> = HashMap::new();
+        for (term, weight) in expand(self.index, word) {
+            for (book_id, score) in term_scores(self.index, term, field) {
+                let entry = best.entry(book_id).or_insert(0.0);
+                *entry = entry.max(score * weight);
+            }
+        }
+        return best;
+    }
+
+    // Books holding the phrase word for word in one field, None when the
+    // phrase is only stopwords. Each book is checked in its own language
+    fn phrase_scores(&self, field: Option<Field>, text: &str) -> Option<HashMap<i32, f64>> {
+        let fields = match field {
+            Some(f) => vec![f],
+            None => vec![Field::Title, Field::Author, Field::Desc],
+        };
+        let mut res: HashMap<i32, f64> = HashMap::new();
+        let mut any_terms = false;
+        for lang in [Lang::En, Lang::Id] {
+            for f in &fields {
+                let terms = field_terms(*f, lang, text);
+                if terms.is_empty() {
+                    continue;
+                }
+                any_terms = true;
+
+                let mut candidates: Option<HashSet<i32>> = None;
+                for t in &terms {
+                    let ids: HashSet<i32> = self
+                        .index
+                        .postings(t)
+                        .iter()
+                        .filter(|p| p.field == *f)
+                        .map(|p| p.book_id)
+                        .collect();
+                    candidates = Some(match candidates {
+                        None => ids,
+                        Some(c) => c.intersection(&ids).copied().collect(),
+                    });
+                }
+
+                for id in candidates.unwrap_or_default() {
+                    if self.index.lang(id) != lang {
+                        continue;
+                    }
+                    let Some(book) = self.index.book(id) else {
+                        continue;
+                    };
+                    let words = field_terms(*f, lang, field_text(book, *f));
+                    if !words.windows(terms.len()).any(|w| w == terms.as_slice()) {
+                        continue;
+                    }
+                    let score: f64 = terms
+                        .iter()
+                        .map(|t| {
+                            term_scores(self.index, t, Some(*f))
+                                .get(&id)
+                                .copied()
+                                .unwrap_or(0.0)
+                        })
+                        .sum();
+                    *res.entry(id).or_insert(0.0) += score;
                 }
             }
         }
+        if !any_terms {
+            return None;
+        }
+        return Some(res);
     }
 
-    result
+    // Books matching the node, None when it asks for nothing (only stopwords)
+    fn matches(&self, node: &QueryNode) -> Option<HashSet<i32>> {
+        match node {
+            QueryNode::Word { field, text, .. } => {
+                let mut res: Option<HashSet<i32>> = None;
+                // "battle-hardened" is two words and needs both
+                for w in query_words(text, self.lang) {
+                    let found: HashSet<i32> = self.word_scores(&w, *field).into_keys().collect();
+                    res = Some(match res {
+                        None => found,
+                        Some(r) => r.intersection(&found).copied().collect(),
+                    });
+                }
+                return res;
+            }
+            QueryNode::Phrase { field, text } => {
+                return self
+                    .phrase_scores(*field, text)
+                    .map(|s| s.into_keys().collect());
+            }
+            QueryNode::Filter(f) => return Some(self.filters.get(f).cloned().unwrap_or_default()),
+            QueryNode::And(nodes) => {
+                let mut res: Option<HashSet<i32>> = None;
+                for found in nodes.iter().filter_map(|n| self.matches(n)) {
+                    res = Some(match res {
+                        None => found,
+                        Some(r) => r.intersection(&found).copied().collect(),
+                    });
+                }
+                return res;
+            }
+            QueryNode::Or(nodes) => {
+                let mut res: Option<HashSet<i32>> = None;
+                for found in nodes.iter().filter_map(|n| self.matches(n)) {
+                    res.get_or_insert_with(HashSet::new).extend(found);
+                }
+                return res;
+            }
+            QueryNode::Not(inner) => {
+                let excluded = self.matches(inner)?;
+                return Some(
+                    self.index
+                        .book_ids()
+                        .filter(|id| !excluded.contains(id))
+                        .collect(),
+                );
+            }
+        }
+    }
+
+    // Adds up the scores of the words and phrases asked for, negated ones
+    // and filters don't count
+    fn score(&self, node: &QueryNode, total: &mut HashMap<i32, f64>) {
+        let mut add = |scores: HashMap<i32, f64>| {
+            for (book_id, score) in scores {
+                *total.entry(book_id).or_insert(0.0) += score;
+            }
+        };
+        match node {
+            QueryNode::Word { field, text, .. } => {
+                for w in query_words(text, self.lang) {
+                    add(self.word_scores(&w, *field));
+                }
+            }
+            QueryNode::Phrase { field, text } => {
+                if let Some(scores) = self.phrase_scores(*field, text) {
+                    add(scores);
+                }
+            }
+            QueryNode::And(nodes) | QueryNode::Or(nodes) => {
+                for n in nodes {
+                    self.score(n, total);
+                }
+            }
+            QueryNode::Filter(_) | QueryNode::Not(_) => {}
+        }
+    }
 }
 
-fn cosine_similarity(vec1: &HashMap<String, f64>, vec2: &HashMap<String, f64>) -> f64 {
-    let dot_product: f64 = vec1
-        .iter()
-        .filter_map(|(k, v1)| vec2.get(k).map(|v2| v1 * v2))
-        .sum();
-
-    let magnitude1: f64 = vec1.values().map(|v| v * v).sum::<f64>().sqrt();
-    let magnitude2: f64 = vec2.values().map(|v| v * v).sum::<f64>().sqrt();
-
-    if magnitude1 == 0.0 || magnitude2 == 0.0 {
-        return 0.0;
-    }
-
-    dot_product / (magnitude1 * magnitude2)
-}
-
-pub async fn s_search_book(keyword: &str, sort_mode: String) -> Vec<SearchResult> {
-    let keyword_str: String = keyword.to_string();
-    let mut result: Vec<SearchResult> = Vec::new();
-    tokio::task::spawn_blocking(move || {
-        let book: Vec<book::Book> = sql::sql_read_book(sort_mode).unwrap();
-        let stuff = vectorize_book(&book);
-        let stuff2 = vectorize_word(&keyword_str, stuff.clone());
-        let mut kesamaan: Vec<SortedData> = Vec::new();
-        for i in 0..stuff.len() {
-            let obj = &stuff[i];
-            kesamaan.push(SortedData {
-                index: i as i32,
-                score: cosine_similarity(&stuff2, &obj),
-            })
-        }
-        kesamaan.sort_by(|a, b| a.partial_cmp(b).unwrap());
-        for k in &kesamaan {
-            if k.score > 0.0 {
-                let new_obj: SearchResult = SearchResult {
-                    book: book[k.index as usize].clone(),
-                    score: k.score.clone(),
-                };
-                result.push(new_obj);
+// The query with misspelled words swapped for their correction, if any were
+pub fn did_you_mean(
+    index: &SearchIndex,
+    text: &str,
+    query: &QueryNode,
+    lang: Lang,
+) -> Option<String> {
+    let mut fixes: Vec<((usize, usize), String)> = Vec::new();
+    collect_fixes(index, query, lang, &mut fixes);
+    if fixes.is_empty() {
+        return None;
+    }
+    fixes.sort_by_key(|f| f.0);
+
+    let chars: Vec<char> = text.chars().collect();
+    let mut res = String::new();
+    let mut at = 0;
+    for ((start, end), fixed) in fixes {
+        res.extend(&chars[at..start]);
+        res.push_str(&fixed);
+        at = end;
+    }
+    res.extend(&chars[at..]);
+    return Some(res);
+}
+
+fn collect_fixes(
+    index: &SearchIndex,
+    node: &QueryNode,
+    lang: Lang,
+    fixes: &mut Vec<((usize, usize), String)>,
+) {
+    match node {
+        QueryNode::Word { text, span, .. } => {
+            let mut changed = false;
+            let mut words: Vec<String> = Vec::new();
+            for w in query_words(text, lang) {
+                match correction(index, &w) {
+                    Some(fixed) => {
+                        changed = true;
+                        words.push(fixed);
+                    }
+                    None => words.push(w.word),
+                }
+            }
+            if changed {
+                fixes.push((*span, words.join(" ")));
+            }
+        }
+        QueryNode::And(nodes) | QueryNode::Or(nodes) => {
+            for n in nodes {
+                collect_fixes(index, n, lang, fixes);
+            }
+        }
+        QueryNode::Phrase { .. } | QueryNode::Filter(_) | QueryNode::Not(_) => {}
+    }
+}
+
+// Books matching the query with their score, best first, ties broken by id.
+// A query of only filters gives every match a score of 0
+pub fn rank(
+    index: &SearchIndex,
+    query: &QueryNode,
+    filters: &FilterSets,
+    lang: Lang,
+) -> Vec<(i32, f64)> {
+    let matcher = Matcher {
+        index,
+        filters,
+        lang,
+    };
+    let Some(ids) = matcher.matches(query) else {
+        return Vec::new();
+    };
+    let mut scores: HashMap<i32, f64> = HashMap::new();
+    matcher.score(query, &mut scores);
+
+    let mut res: Vec<(i32, f64)> = ids
+        .into_iter()
+        .map(|id| (id, scores.get(&id).copied().unwrap_or(0.0)))
+        .collect();
+    res.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    return res;
+}
+
+// What `/search` results are ordered by, relevance unless asked otherwise
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchOrder {
+    #[default]
+    Relevance,
+    Title,
+    Author,
+    Year,
+    Newest,
+}
+
+impl SearchOrder {
+    // `id` is kept from the old column names and means order of addition
+    pub fn from_param(order: Option<&str>) -> SearchOrder {
+        match order.map(|s| s.trim().to_lowercase()).as_deref() {
+            Some("title") => SearchOrder::Title,
+            Some("author") => SearchOrder::Author,
+            Some("year") => SearchOrder::Year,
+            Some("newest") | Some("id") => SearchOrder::Newest,
+            _ => SearchOrder::Relevance,
+        }
+    }
+
+    // Best match and newest book come first, text columns read A to Z
+    pub fn default_direction(self) -> SortOrder {
+        match self {
+            SearchOrder::Relevance | SearchOrder::Newest => SortOrder::Desc,
+            _ => SortOrder::Asc,
+        }
+    }
+}
+
+// Order the results came back in, sent along with them
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct SearchSort {
+    pub order: SearchOrder,
+    pub direction: SortOrder,
+}
+
+impl SearchSort {
+    // A missing or unknown `sort` keeps the natural direction of `order`
+    pub fn from_params(order: Option<&str>, sort: Option<&str>) -> SearchSort {
+        let order = SearchOrder::from_param(order);
+        let direction = match sort.map(|s| s.trim().to_lowercase()).as_deref() {
+            Some("asc") => SortOrder::Asc,
+            Some("desc") => SortOrder::Desc,
+            _ => order.default_direction(),
+        };
+        return SearchSort { order, direction };
+    }
+}
+
+// Facet values picked to narrow a search, all of them must hold
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FacetSelection {
+    // Every one of these tags
+    pub tags: Vec<i32>,
+    pub author: Option<String>,
+    // First year of the decade, 1990 for the nineties
+    pub decade: Option<i32>,
+}
+
+impl FacetSelection {
+    fn has_tags(&self, book: &book::Book) -> bool {
+        return self
+            .tags
+            .iter()
+            .all(|id| book.tags.iter().any(|t| t.id == *id));
+    }
+
+    fn has_author(&self, book: &book::Book) -> bool {
+        return self
+            .author
+            .as_ref()
+            .is_none_or(|a| author_key(a) == author_key(&book.author));
+    }
+
+    fn has_decade(&self, book: &book::Book) -> bool {
+        return self.decade.is_none() || decade(&book.year) == self.decade;
+    }
+
+    fn matches(&self, book: &book::Book) -> bool {
+        return self.has_tags(book) && self.has_author(book) && self.has_decade(book);
+    }
+}
+
+// Authors are told apart by their normalized words, so "Herbert Schildt"
+// and "herbert  SCHILDT" are one author
+fn author_key(name: &str) -> String {
+    return analyzer::tokenize(name).join(" ");
+}
+
+// Knobs of one search besides the query itself
+#[derive(Clone, Debug, Default)]
+pub struct SearchOptions {
+    // Guessed from the query when not given
+    pub lang: Option<Lang>,
+    pub sort: SearchSort,
+    pub facets: FacetSelection,
+    // Page size, every result when None
+    pub limit: Option<usize>,
+    // Start right after this spot, from `next_cursor` of the previous page
+    pub cursor: Option<Cursor>,
+}
+
+// Where a page of results stopped: sort key, score and id of its last
+// result. The next page starts right after that spot instead of at a fixed
+// offset, so books added or removed meanwhile don't shift the pages.
+// Scores move whenever the catalog changes, so relevance pages instead
+// point into the result set their first page was cut from: `set` is its
+// token in `ResultSets` and `pos` where the next page starts. `search` is
+// the `search_key` of the search the cursor came from
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Cursor {
+    pub sort: SearchSort,
+    search: u64,
+    key: String,
+    score: f64,
+    id: i32,
+    set: Option<u64>,
+    #[serde(default)]
+    pos: usize,
+}
+
+impl Cursor {
+    fn at(book: &book::Book, score: f64, sort: SearchSort) -> Cursor {
+        let key = match sort.order {
+            SearchOrder::Title => book.title.clone(),
+            SearchOrder::Author => book.author.clone(),
+            SearchOrder::Year => book.year.clone(),
+            SearchOrder::Relevance | SearchOrder::Newest => String::new(),
+        };
+        return Cursor {
+            sort,
+            search: 0,
+            key,
+            score,
+            id: book.id,
+            set: None,
+            pos: 0,
+        };
+    }
+
+    // Opaque to clients, hex of the JSON form
+    pub fn encode(&self) -> String {
+        let json = serde_json::to_string(self).unwrap_or_default();
+        return json.bytes().map(|b| format!("{:02x}", b)).collect();
+    }
+
+    // `is_multiple_of` only came with Rust 1.87
+    #[allow(clippy::manual_is_multiple_of)]
+    pub fn decode(text: &str) -> Result<Cursor, AppError> {
+        let invalid = || AppError::validation(format!("invalid cursor '{}'", text));
+        if text.len() % 2 != 0 || !text.is_ascii() {
+            return Err(invalid());
+        }
+        let bytes = (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&text[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| invalid())?;
+        return serde_json::from_slice(&bytes).map_err(|_| invalid());
+    }
+}
+
+// Result sets kept for later relevance pages, the oldest is dropped past this
+const MAX_RESULT_SETS: usize = 100;
+
+// Every result of one search in page order, with the facets counted over
+// them
+#[derive(Debug)]
+pub struct ResultSet {
+    search: u64,
+    points: Vec<Cursor>,
+    facets: Facets,
+}
+
+// Fingerprint of what a search asks for: the words and filters of its
+// query, the picked facets and the language. Cursors carry it so they can't
+// page through some other search
+fn search_key(query: &QueryNode, facets: &FacetSelection, lang: Lang) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    query.hash_terms(&mut hasher);
+    let mut tags = facets.tags.clone();
+    tags.sort();
+    tags.dedup();
+    tags.hash(&mut hasher);
+    facets.author.as_deref().map(author_key).hash(&mut hasher);
+    facets.decade.hash(&mut hasher);
+    lang.hash(&mut hasher);
+    return hasher.finish();
+}
+
+// Result sets of recent relevance searches that have more pages, by the
+// token their cursors carry
+#[derive(Debug)]
+pub struct ResultSets {
+    inner: Mutex<StoredSets>,
+}
+
+#[derive(Debug)]
+struct StoredSets {
+    next: u64,
+    order: VecDeque<u64>,
+    sets: HashMap<u64, Arc<ResultSet>>,
+}
+
+impl ResultSets {
+    pub fn new() -> ResultSets {
+        // Tokens start somewhere else on every run, so a cursor from before
+        // a restart doesn't land in some other search
+        let start = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default();
+        return ResultSets {
+            inner: Mutex::new(StoredSets {
+                next: start,
+                order: VecDeque::new(),
+                sets: HashMap::new(),
+            }),
+        };
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, StoredSets>, AppError> {
+        return self
+            .inner
+            .lock()
+            .map_err(|_| AppError::internal("result sets are poisoned"));
+    }
+
+    fn save(&self, set: Arc<ResultSet>) -> Result<u64, AppError> {
+        let mut stored = self.lock()?;
+        let token = stored.next;
+        stored.next = token.wrapping_add(1);
+        stored.order.push_back(token);
+        stored.sets.insert(token, set);
+        while stored.order.len() > MAX_RESULT_SETS {
+            if let Some(old) = stored.order.pop_front() {
+                stored.sets.remove(&old);
+            }
+        }
+        return Ok(token);
+    }
+
+    fn get(&self, token: u64) -> Result<Option<Arc<ResultSet>>, AppError> {
+        return Ok(self.lock()?.sets.get(&token).cloned());
+    }
+}
+
+impl Default for ResultSets {
+    fn default() -> ResultSets {
+        return ResultSets::new();
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TagFacet {
+    pub id: i32,
+    pub name: String,
+    pub count: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FacetCount<T> {
+    pub value: T,
+    pub count: usize,
+}
+
+// How many of the results fall under each tag, author and decade,
+// most common first
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Facets {
+    pub tags: Vec<TagFacet>,
+    pub authors: Vec<FacetCount<String>>,
+    pub decades: Vec<FacetCount<i32>>,
+}
+
+fn decade(year: &str) -> Option<i32> {
+    return Some(year_of(year)?.div_euclid(10) * 10);
+}
+
+// Year as a number, None when the field is empty or not a year
+fn year_of(year: &str) -> Option<i32> {
+    return year.trim().parse::<i32>().ok();
+}
+
+fn facet_counts<T: Ord + Clone>(counts: HashMap<T, usize>) -> Vec<FacetCount<T>> {
+    let mut res: Vec<FacetCount<T>> = counts
+        .into_iter()
+        .map(|(value, count)| FacetCount { value, count })
+        .collect();
+    res.sort_by(|a, b| b.count.cmp(&a.count).then(a.value.cmp(&b.value)));
+    return res;
+}
+
+// Each dimension is counted over the books passing the selections of the
+// other two, so picking a tag still shows how many books the other tags have
+fn count_facets(books: &[&book::Book], selection: &FacetSelection) -> Facets {
+    let mut tags: HashMap<i32, TagFacet> = HashMap::new();
+    // Books and spellings seen for each author
+    let mut authors: HashMap<String, (usize, HashMap<&str, usize>)> = HashMap::new();
+    let mut decades: HashMap<i32, usize> = HashMap::new();
+    for book in books {
+        let has_tags = selection.has_tags(book);
+        let has_author = selection.has_author(book);
+        let has_decade = selection.has_decade(book);
+        if has_author && has_decade {
+            for t in &book.tags {
+                let facet = tags.entry(t.id).or_insert_with(|| TagFacet {
+                    id: t.id,
+                    name: t.name.clone(),
+                    count: 0,
+                });
+                facet.count += 1;
+            }
+        }
+        let key = author_key(&book.author);
+        if has_tags && has_decade && !key.is_empty() {
+            let (count, spellings) = authors.entry(key).or_default();
+            *count += 1;
+            *spellings.entry(book.author.trim()).or_insert(0) += 1;
+        }
+        if has_tags && has_author {
+            if let Some(d) = decade(&book.year) {
+                *decades.entry(d).or_insert(0) += 1;
             }
         }
-        return result;
-    })
-    .await
-    .unwrap()
+    }
+    // An author is shown under their most used spelling
+    let authors: HashMap<String, usize> = authors
+        .into_values()
+        .filter_map(|(count, spellings)| {
+            let shown = spellings
+                .into_iter()
+                .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(a.0)))?;
+            Some((shown.0.to_string(), count))
+        })
+        .collect();
+
+    let mut tags: Vec<TagFacet> = tags.into_values().collect();
+    tags.sort_by(|a, b| {
+        b.count
+            .cmp(&a.count)
+            .then(a.name.cmp(&b.name))
+            .then(a.id.cmp(&b.id))
+    });
+    return Facets {
+        tags,
+        authors: facet_counts(authors),
+        decades: facet_counts(decades),
+    };
+}
+
+#[derive(Serialize)]
+pub struct SearchResponse {
+    pub sort: SearchSort,
+    // Language the query was analyzed as, given or detected
+    pub lang: Lang,
+    // `total` counts every result left after the selected facets
+    #[serde(flatten)]
+    pub page: Page<SearchResult>,
+    // Each dimension counted over the results left after the selections
+    // made in the other dimensions
+    pub facets: Facets,
+    // Set when some query words were only matched through typos, or not at all
+    pub did_you_mean: Option<String>,
+}
+
+fn escape_html(text: &str) -> String {
+    return text
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;");
+}
+
+// Field text with every word analyzed to one of `terms` marked, descriptions
+// are cut down to the words around the first hit
+fn highlight(text: &str, terms: &[String], field: Field, lang: Lang) -> String {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let hit = |w: &str| {
+        field_terms(field, lang, w)
+            .iter()
+            .any(|t| terms.contains(t))
+    };
+
+    let (mut start, mut end) = (0, words.len());
+    if field == Field::Desc {
+        if let Some(first) = words.iter().position(|w| hit(w)) {
+            start = first.saturating_sub(SNIPPET_CONTEXT);
+            end = (first + SNIPPET_CONTEXT + 1).min(words.len());
+        }
+    }
+
+    let mut parts: Vec<String> = Vec::new();
+    for w in &words[start..end] {
+        if hit(w) {
+            parts.push(format!("<mark>{}</mark>", escape_html(w)));
+        } else {
+            parts.push(escape_html(w));
+        }
+    }
+    let mut res = parts.join(" ");
+    if start > 0 {
+        res = format!("… {}", res);
+    }
+    if end < words.len() {
+        res = format!("{} …", res);
+    }
+    return res;
+}
+
+// Which fields of `book` the query words hit, in field order
+fn book_matches(index: &SearchIndex, book: &book::Book, keywords: &[QueryWord]) -> Vec<FieldMatch> {
+    let lang = index.lang(book.id);
+    let mut found: HashMap<Field, Vec<String>> = HashMap::new();
+    for w in keywords {
+        for (term, _) in expand(index, w) {
+            for p in index.postings(term).iter().filter(|p| p.book_id == book.id) {
+                let terms = found.entry(p.field).or_default();
+                if !terms.contains(term) {
+                    terms.push(term.clone());
+                }
+            }
+        }
+    }
+
+    let mut res: Vec<FieldMatch> = Vec::new();
+    for (field, mut terms) in found {
+        terms.sort();
+        let snippet = match field {
+            Field::Title => highlight(&book.title, &terms, field, lang),
+            Field::Author => highlight(&book.author, &terms, field, lang),
+            Field::Desc => highlight(&book.desc, &terms, field, lang),
+            Field::Year => highlight(&book.year, &terms, field, lang),
+            // Tags are indexed whole, mark each matching name as one piece
+            Field::Tag => book
+                .tags
+                .iter()
+                .filter(|t| {
+                    field_terms(field, lang, &t.name)
+                        .iter()
+                        .any(|n| terms.contains(n))
+                })
+                .map(|t| format!("<mark>{}</mark>", escape_html(&t.name)))
+                .collect::<Vec<String>>()
+                .join(", "),
+        };
+        res.push(FieldMatch {
+            field,
+            terms,
+            snippet,
+        });
+    }
+    res.sort_by_key(|m| m.field.slot());
+    return res;
+}
+
+// Requested key first, then lower id so equal books always come back in
+// the same order. Scores move whenever the catalog changes, so only
+// relevance looks at them, otherwise a cursor could skip or repeat books
+fn compare_points(a: &Cursor, b: &Cursor, sort: SearchSort) -> Ordering {
+    if sort.order == SearchOrder::Year {
+        let (a_year, b_year) = (year_of(&a.key), year_of(&b.key));
+        // Books without a year go last whichever way the years run
+        if a_year.is_none() || b_year.is_none() {
+            return a_year
+                .is_none()
+                .cmp(&b_year.is_none())
+                .then(a.id.cmp(&b.id));
+        }
+    }
+    let by_key = match sort.order {
+        SearchOrder::Relevance => a.score.total_cmp(&b.score),
+        SearchOrder::Title | SearchOrder::Author => a.key.cmp(&b.key),
+        SearchOrder::Year => year_of(&a.key).cmp(&year_of(&b.key)),
+        SearchOrder::Newest => a.id.cmp(&b.id),
+    };
+    let by_key = match sort.direction {
+        SortOrder::Asc => by_key,
+        SortOrder::Desc => by_key.reverse(),
+    };
+    return by_key.then(a.id.cmp(&b.id));
+}
+
+// Every matching book in page order, facets counted before the selected
+// ones narrow the results
+fn result_set(
+    index: &SearchIndex,
+    query: &QueryNode,
+    filters: &FilterSets,
+    lang: Lang,
+    options: &SearchOptions,
+) -> ResultSet {
+    let found: Vec<(&book::Book, f64)> = rank(index, query, filters, lang)
+        .into_iter()
+        .filter_map(|(book_id, score)| Some((index.book(book_id)?, score)))
+        .collect();
+    let books: Vec<&book::Book> = found.iter().map(|(b, _)| *b).collect();
+    let facets = count_facets(&books, &options.facets);
+
+    let sort = options.sort;
+    let mut points: Vec<Cursor> = found
+        .into_iter()
+        .filter(|(b, _)| options.facets.matches(b))
+        .map(|(b, score)| Cursor::at(b, score, sort))
+        .collect();
+    points.sort_by(|a, b| compare_points(a, b, sort));
+    return ResultSet {
+        search: search_key(query, &options.facets, lang),
+        points,
+        facets,
+    };
+}
+
+pub fn s_search_book(
+    index: &SearchIndex,
+    results: &ResultSets,
+    text: &str,
+    query: &QueryNode,
+    filters: &FilterSets,
+    options: &SearchOptions,
+) -> Result<SearchResponse, AppError> {
+    let mut positive: Vec<String> = Vec::new();
+    query.positive_text(&mut positive);
+    let positive = positive.join(" ");
+    let lang = options.lang.unwrap_or_else(|| analyzer::detect(&positive));
+
+    let sort = options.sort;
+    let cursor = options.cursor.as_ref();
+    let search = search_key(query, &options.facets, lang);
+    let other_search =
+        || AppError::validation("cursor belongs to another search, start again without it");
+    if cursor.is_some_and(|c| c.search != search) {
+        return Err(other_search());
+    }
+    // Later relevance pages are cut from the set of the first page, books
+    // added since don't show up and removed ones are left out
+    let (set, token) = match cursor.and_then(|c| c.set) {
+        Some(token) => match results.get(token)? {
+            Some(set) if set.search != search => return Err(other_search()),
+            Some(set) => (set, Some(token)),
+            None => {
+                return Err(AppError::validation(
+                    "cursor has expired, start again without it",
+                ))
+            }
+        },
+        None => (
+            Arc::new(result_set(index, query, filters, lang, options)),
+            None,
+        ),
+    };
+    let start = match cursor {
+        None => 0,
+        Some(c) if c.set.is_some() => c.pos.min(set.points.len()),
+        Some(c) => set
+            .points
+            .iter()
+            .position(|p| compare_points(p, c, sort) == Ordering::Greater)
+            .unwrap_or(set.points.len()),
+    };
+    // Books deleted since a kept set was made are passed over, the page is
+    // filled from the ones after them
+    let limit = options.limit.unwrap_or(set.points.len());
+    let mut found: Vec<(&Cursor, &book::Book)> = Vec::new();
+    let mut end = start;
+    while end < set.points.len() && found.len() < limit {
+        let point = &set.points[end];
+        if let Some(book) = index.book(point.id) {
+            found.push((point, book));
+        }
+        end += 1;
+    }
+    let live = |points: &[Cursor]| points.iter().filter(|p| index.book(p.id).is_some()).count();
+    let next_cursor = if limit > 0 && live(&set.points[end..]) > 0 {
+        let mut next = set.points[end - 1].clone();
+        next.search = search;
+        if sort.order == SearchOrder::Relevance {
+            next.set = match token {
+                Some(token) => Some(token),
+                None => Some(results.save(set.clone())?),
+            };
+            next.pos = end;
+        }
+        Some(next.encode())
+    } else {
+        None
+    };
+
+    // Only the results sent back get their matches worked out
+    let keywords = query_words(&positive, lang);
+    let page: Vec<SearchResult> = found
+        .into_iter()
+        .map(|(point, book)| SearchResult {
+            book: book.clone(),
+            score: point.score,
+            matches: book_matches(index, book, &keywords),
+        })
+        .collect();
+    return Ok(SearchResponse {
+        sort,
+        lang,
+        page: Page {
+            items: page,
+            total: live(&set.points) as i64,
+            next_cursor,
+        },
+        facets: set.facets.clone(),
+        did_you_mean: did_you_mean(index, text, query, lang),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::book::test_support::{named, titled};
+    use crate::book::{Book, Tag};
+
+    // Plain text queries, no filters involved
+    fn rank(index: &SearchIndex, query: &str, lang: Lang) -> Vec<(i32, f64)> {
+        return super::rank(
+            index,
+            &parse_query(query).unwrap(),
+            &FilterSets::new(),
+            lang,
+        );
+    }
+
+    fn search(
+        index: &SearchIndex,
+        query: &str,
+        lang: Option<Lang>,
+        sort: SearchSort,
+    ) -> SearchResponse {
+        let options = SearchOptions {
+            lang,
+            sort,
+            facets: FacetSelection::default(),
+            limit: None,
+            cursor: None,
+        };
+        let parsed = parse_query(query).unwrap();
+        return s_search_book(
+            index,
+            &ResultSets::new(),
+            query,
+            &parsed,
+            &FilterSets::new(),
+            &options,
+        )
+        .unwrap();
+    }
+
+    // Tag ids of the test catalog, position in this list plus one
+    const TAGS: &[&str] = &[
+        "programming",
+        "clang",
+        "c99",
+        "java",
+        "management",
+        "python",
+        "leadership",
+        "business",
+    ];
+
+    fn book(id: i32, title: &str, author: &str, tags: &[&str], year: &str, desc: &str) -> Book {
+        let tags: Vec<Tag> = tags
+            .iter()
+            .map(|t| named(TAGS.iter().position(|n| n == t).unwrap() as i32 + 1, t))
+            .collect();
+        return Book {
+            desc: desc.to_string(),
+            year: year.to_string(),
+            ..titled(id, title, author, tags)
+        };
+    }
+
+    // Small catalog standing in for the library, ids are referred to below
+    fn corpus() -> SearchIndex {
+        return SearchIndex::build(vec![
+            book(1, "C: The Complete Reference", "Herbert Schildt", &["programming", "clang", "c99"], "2000",
+                "Another gem from Herb Schildt, best-selling programming author. Full details on C99 and the C language."),
+            book(2, "The C Programming Language", "Brian Kernighan and Dennis Ritchie", &["programming", "clang"], "1988",
+                "The original book on the C programming language by its authors."),
+            book(3, "Java: The Complete Reference", "Herbert Schildt", &["programming", "java"], "2018",
+                "Comprehensive coverage of the Java language."),
+            book(4, "The Five Dysfunctions of a Team", "Patrick Lencioni", &["management"], "2002",
+                "A business fable about the pitfalls teams face as they grow together."),
+            book(5, "Python Crash Course", "Eric Matthes", &["programming", "python"], "2019",
+                "A hands-on introduction to programming with Python."),
+            book(6, "Team of Teams", "Stanley McChrystal", &["management", "leadership"], "2015",
+                "New rules of engagement for a complex world, the team as the unit of work."),
+            book(7, "The Art of the Start", "Guy Kawasaki", &["business"], "2004",
+                "The time-tested, battle-hardened guide for anyone starting anything."),
+        ]);
+    }
+
+    fn ids(index: &SearchIndex, query: &str) -> Vec<i32> {
+        return rank(index, query, Lang::En)
+            .iter()
+            .map(|(id, _)| *id)
+            .collect();
+    }
+
+    #[test]
+    fn rare_terms_outweigh_common_ones() {
+        let index = corpus();
+        // "the" is in almost every title, "reference" only in two
+        let mut top = ids(&index, "the reference")[..2].to_vec();
+        top.sort();
+        assert_eq!(top, [1, 3]);
+        // "java" only appears in book 3
+        assert_eq!(ids(&index, "complete reference java")[0], 3);
+    }
+
+    #[test]
+    fn title_hits_rank_above_description_hits() {
+        let mut index = corpus();
+        index.upsert(book(
+            8,
+            "Extreme Ownership",
+            "Jocko Willink",
+            &["leadership"],
+            "2015",
+            "How a team of Navy SEALs leads and wins.",
+        ));
+        // Books 6 and 4 have "team" in the title, book 8 only in the description
+        assert_eq!(ids(&index, "team"), [6, 4, 8]);
+    }
+
+    #[test]
+    fn author_query_finds_all_their_books() {
+        let index = corpus();
+        let res = ids(&index, "schildt");
+        assert_eq!(res, [1, 3]);
+    }
+
+    #[test]
+    fn tags_and_year_are_searchable() {
+        let index = corpus();
+        assert_eq!(ids(&index, "c99"), [1]);
+        assert_eq!(ids(&index, "1988"), [2]);
+        assert_eq!(ids(&index, "management")[..2], [4, 6]);
+    }
+
+    #[test]
+    fn exact_words_beat_prefix_matches() {
+        let index = corpus();
+        let res = rank(&index, "python", Lang::En);
+        assert_eq!(res[0].0, 5);
+        let prefix_only = rank(&index, "pyth", Lang::En);
+        assert_eq!(prefix_only[0].0, 5);
+        assert!(prefix_only[0].1 < res[0].1);
+    }
+
+    #[test]
+    fn prefixes_expand_to_a_bounded_set() {
+        let index = corpus();
+        // Too short to stand for longer words
+        assert!(ids(&index, "ja").is_empty());
+        let mut short = ids(&index, "c");
+        short.sort_unstable();
+        assert_eq!(short, [1, 2]);
+
+        // Authors zyx001..zyx060, zyx007 on four books
+        let mut books: Vec<Book> = (1..=60)
+            .map(|id| book(id, "x", &format!("zyx{:03}", id), &[], "", ""))
+            .collect();
+        books.extend((61..=63).map(|id| book(id, "x", "zyx007", &[], "", "")));
+        let index = SearchIndex::build(books);
+        let word = query_words("zyx", Lang::En).remove(0);
+        let terms: Vec<&str> = expand(&index, &word)
+            .iter()
+            .map(|(t, _)| t.as_str())
+            .collect();
+        assert_eq!(terms.len(), MAX_PREFIX_TERMS);
+        assert!(terms.contains(&"zyx007"));
+        assert!(terms.contains(&"zyx050"));
+        assert!(!terms.contains(&"zyx051"));
+    }
+
+    #[test]
+    fn ordering_is_deterministic() {
+        let index = corpus();
+        let first = rank(&index, "programming language", Lang::En);
+        for _ in 0..5 {
+            assert_eq!(rank(&index, "programming language", Lang::En), first);
+        }
+        assert_eq!(first[0].0, 2);
+    }
+
+    fn search_ids(index: &SearchIndex, query: &str, order: &str, sort: Option<&str>) -> Vec<i32> {
+        let sort = SearchSort::from_params(Some(order), sort);
+        let res = search(index, query, None, sort);
+        return res.page.items.iter().map(|r| r.book.id).collect();
+    }
+
+    #[test]
+    fn search_defaults_to_best_match_first() {
+        let index = corpus();
+        let res = search(
+            &index,
+            "java reference",
+            None,
+            SearchSort::from_params(None, None),
+        );
+        assert_eq!(res.sort.order, SearchOrder::Relevance);
+        assert_eq!(res.sort.direction, SortOrder::Desc);
+        assert_eq!(res.page.items[0].book.id, 3);
+        assert!(res.page.items.windows(2).all(|w| w[0].score >= w[1].score));
+    }
+
+    #[test]
+    fn years_sort_as_numbers_with_missing_last() {
+        let mut index = corpus();
+        index.upsert(book(8, "Beowulf", "Unknown", &["programming"], "850", ""));
+        index.upsert(book(9, "Undated", "Unknown", &["programming"], "", ""));
+        assert_eq!(
+            search_ids(&index, "programming", "year", Some("asc")),
+            [8, 2, 1, 3, 5, 9]
+        );
+        assert_eq!(
+            search_ids(&index, "programming", "year", Some("desc")),
+            [5, 3, 1, 2, 8, 9]
+        );
+    }
+
+    #[test]
+    fn search_secondary_orders() {
+        let index = corpus();
+        assert_eq!(search_ids(&index, "schildt", "title", None), [1, 3]);
+        assert_eq!(search_ids(&index, "schildt", "year", Some("desc")), [3, 1]);
+        assert_eq!(
+            search_ids(&index, "programming", "newest", None),
+            [5, 3, 2, 1]
+        );
+        assert_eq!(
+            search_ids(&index, "programming", "newest", Some("asc")),
+            [1, 2, 3, 5]
+        );
+        // Same author on both books, the lower id wins the tie whatever
+        // the scores, so a cursor stays put when scores move
+        let by_author = search_ids(&index, "schildt java", "author", None);
+        assert_eq!(by_author, [1, 3]);
+    }
+
+    #[test]
+    fn results_report_matched_fields() {
+        let index = corpus();
+        let sort = SearchSort::from_params(None, None);
+
+        // Only in the description, which is still searched
+        let res = search(&index, "battle-hardened", None, sort);
+        assert_eq!(res.page.items.len(), 1);
+        assert_eq!(
+            res.page.items[0].matches,
+            [FieldMatch {
+                field: Field::Desc,
+                terms: vec!["battl".to_string(), "harden".to_string()],
+                snippet: "The time-tested, <mark>battle-hardened</mark> guide for anyone starting anything."
+                    .to_string(),
+            }]
+        );
+
+        let res = search(&index, "c99 schildt", None, sort);
+        assert_eq!(res.page.items[0].book.id, 1);
+        let fields: Vec<Field> = res.page.items[0].matches.iter().map(|m| m.field).collect();
+        assert_eq!(fields, [Field::Author, Field::Tag, Field::Desc]);
+        assert_eq!(res.page.items[0].matches[1].snippet, "<mark>c99</mark>");
+        assert!(res.page.items[0].matches[2]
+            .snippet
+            .contains("<mark>C99</mark>"));
+    }
+
+    #[test]
+    fn long_descriptions_are_cut_around_the_hit() {
+        let desc = (0..40)
+            .map(|i| format!("w{}", i))
+            .collect::<Vec<String>>()
+            .join(" ");
+        let snippet = highlight(&desc, &["w20".to_string()], Field::Desc, Lang::En);
+        assert!(snippet.starts_with("… w10 "));
+        assert!(snippet.ends_with(" w30 …"));
+        assert!(snippet.contains("<mark>w20</mark>"));
+        assert_eq!(
+            highlight("x <b>", &["x".to_string()], Field::Title, Lang::En),
+            "<mark>x</mark> &lt;b&gt;"
+        );
+    }
+
+    #[test]
+    fn typos_still_find_books() {
+        let index = corpus();
+        let sort = SearchSort::from_params(None, None);
+
+        let res = search(&index, "progamming", None, sort);
+        assert!(!res.page.items.is_empty());
+        // Reached through the typo on the whole tag name and on the stem
+        let terms: Vec<&String> = res
+            .page
+            .items
+            .iter()
+            .flat_map(|r| &r.matches)
+            .flat_map(|m| &m.terms)
+            .collect();
+        assert!(terms.contains(&&"programming".to_string()));
+        assert!(terms.contains(&&"program".to_string()));
+        assert_eq!(res.did_you_mean.as_deref(), Some("programming"));
+
+        let res = search(&index, "Kernigan ritchie", None, sort);
+        assert_eq!(res.page.items[0].book.id, 2);
+        assert_eq!(res.did_you_mean.as_deref(), Some("kernighan ritchie"));
+
+        // A typo match never outranks the real word
+        let exact = rank(&index, "python", Lang::En)[0].1;
+        let typo = rank(&index, "pyhton", Lang::En)[0].1;
+        assert!(typo < exact);
+    }
+
+    #[test]
+    fn no_suggestion_for_known_words() {
+        let index = corpus();
+        let sort = SearchSort::from_params(None, None);
+        assert_eq!(search(&index, "schild", None, sort).did_you_mean, None);
+        assert_eq!(search(&index, "java", None, sort).did_you_mean, None);
+        // Nothing close enough to suggest
+        let res = search(&index, "zzzzzz", None, sort);
+        assert!(res.page.items.is_empty());
+        assert_eq!(res.did_you_mean, None);
+    }
+
+    #[test]
+    fn indonesian_and_english_words_meet() {
+        let index = SearchIndex::build(vec![
+            book(
+                1,
+                "Dasar-Dasar Pemrograman dengan Python",
+                "Budi Raharjo",
+                &[],
+                "2019",
+                "Buku ini membahas pemrograman untuk pemula.",
+            ),
+            book(
+                2,
+                "The Pragmatic Programmer",
+                "Andrew Hunt",
+                &[],
+                "1999",
+                "Programming as a craft.",
+            ),
+            book(
+                3,
+                "Kumpulan Resep Masakan",
+                "Sisca Soewitomo",
+                &[],
+                "2010",
+                "Resep masakan sehari-hari.",
+            ),
+        ]);
+        assert_eq!(index.lang(1), Lang::Id);
+        assert_eq!(index.lang(2), Lang::En);
+
+        let sort = SearchSort::from_params(None, None);
+        let res = search(&index, "pemrograman", None, sort);
+        assert_eq!(res.lang, Lang::Id);
+        let mut found: Vec<i32> = res.page.items.iter().map(|r| r.book.id).collect();
+        found.sort();
+        assert_eq!(found, [1, 2]);
+
+        let res = search(&index, "programming", Some(Lang::En), sort);
+        assert_eq!(res.page.items.len(), 2);
+        // Accents and punctuation do not get in the way
+        assert_eq!(rank(&index, "MASAKAN!", Lang::Id)[0].0, 3);
+        assert_eq!(rank(&index, "rahárjo", Lang::Id)[0].0, 1);
+    }
+
+    fn word(field: Option<Field>, text: &str, span: (usize, usize)) -> QueryNode {
+        return QueryNode::Word {
+            field,
+            text: text.to_string(),
+            span,
+        };
+    }
+
+    #[test]
+    fn parses_the_librarian_query() {
+        let q = r#"author:schildt tag:c99 year:1990..2005 "complete reference" -python"#;
+        assert_eq!(
+            parse_query(q).unwrap(),
+            QueryNode::And(vec![
+                QueryNode::Or(vec![
+                    word(Some(Field::Author), "schildt", (7, 14)),
+                    QueryNode::Phrase {
+                        field: None,
+                        text: "complete reference".to_string(),
+                    },
+                ]),
+                QueryNode::Filter(Filter::Tag("c99".to_string())),
+                QueryNode::Filter(Filter::Year {
+                    from: Some(1990),
+                    to: Some(2005),
+                }),
+                QueryNode::Not(Box::new(word(None, "python", (61, 67)))),
+            ])
+        );
+    }
+
+    #[test]
+    fn parses_operators_and_groups() {
+        assert_eq!(
+            parse_query("java OR (python AND NOT crash)").unwrap(),
+            QueryNode::Or(vec![
+                word(None, "java", (0, 4)),
+                QueryNode::And(vec![
+                    word(None, "python", (9, 15)),
+                    QueryNode::Not(Box::new(word(None, "crash", (24, 29)))),
+                ]),
+            ])
+        );
+        assert_eq!(
+            parse_query("year:..1999 title:\"the c\"").unwrap(),
+            QueryNode::And(vec![
+                QueryNode::Phrase {
+                    field: Some(Field::Title),
+                    text: "the c".to_string(),
+                },
+                QueryNode::Filter(Filter::Year {
+                    from: None,
+                    to: Some(1999),
+                }),
+            ])
+        );
+        // A colon followed by a space is part of the title, not a field
+        assert_eq!(
+            parse_query("C: reference").unwrap(),
+            QueryNode::Or(vec![
+                word(None, "C:", (0, 2)),
+                word(None, "reference", (3, 12))
+            ])
+        );
+        assert_eq!(parse_query("   ").unwrap(), QueryNode::Or(Vec::new()));
+    }
+
+    #[test]
+    fn malformed_queries_report_where() {
+        let cases = [
+            ("\"complete reference", 0),
+            ("java (python", 5),
+            ("java)", 4),
+            ("AND java", 0),
+            ("java OR", 7),
+            ("publisher:oreilly", 0),
+            ("year:19x0", 5),
+            ("year:2005..1990", 5),
+            ("year:..", 5),
+            ("java NOT", 8),
+        ];
+        for (q, position) in cases {
+            let err = parse_query(q).unwrap_err();
+            assert_eq!(err.position, position, "{}: {}", q, err.message);
+        }
+    }
+
+    #[test]
+    fn query_combines_text_and_filters() {
+        let index = corpus();
+        let q =
+            parse_query(r#"author:schildt tag:c99 year:1990..2005 "complete reference" -python"#)
+                .unwrap();
+        let mut filters = FilterSets::new();
+        filters.insert(Filter::Tag("c99".to_string()), [1].into());
+        filters.insert(
+            Filter::Year {
+                from: Some(1990),
+                to: Some(2005),
+            },
+            [1, 2, 4, 7].into(),
+        );
+        let res = super::rank(&index, &q, &filters, Lang::En);
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].0, 1);
+
+        // Only filters, everything passing them with no score
+        let q = parse_query("year:1990..2005 -management").unwrap();
+        let res = super::rank(&index, &q, &filters, Lang::En);
+        assert_eq!(res, [(1, 0.0), (2, 0.0), (7, 0.0)]);
+    }
+
+    #[test]
+    fn phrases_need_the_words_together() {
+        let index = corpus();
+        assert_eq!(ids(&index, "\"complete reference\""), [1, 3]);
+        assert_eq!(ids(&index, "\"reference complete\""), Vec::<i32>::new());
+        assert_eq!(ids(&index, "\"team of teams\""), [6]);
+        assert_eq!(ids(&index, "title:\"programming language\""), [2]);
+        assert_eq!(ids(&index, "desc:\"programming language\""), [2]);
+    }
+
+    #[test]
+    fn boolean_operators_narrow_results() {
+        let index = corpus();
+        assert_eq!(ids(&index, "schildt AND java"), [3]);
+        assert_eq!(ids(&index, "schildt -java"), [1]);
+        assert_eq!(ids(&index, "schildt NOT java"), [1]);
+        let mut either = ids(&index, "java OR python");
+        either.sort();
+        assert_eq!(either, [3, 5]);
+        assert_eq!(ids(&index, "author:team"), Vec::<i32>::new());
+        assert_eq!(ids(&index, "title:team")[..2], [6, 4]);
+    }
+
+    #[test]
+    fn did_you_mean_keeps_the_query_syntax() {
+        let index = corpus();
+        let sort = SearchSort::from_params(None, None);
+        let res = search(&index, "author:kernigan -pyhton", None, sort);
+        // Negated words are left alone
+        assert_eq!(
+            res.did_you_mean.as_deref(),
+            Some("author:kernighan -pyhton")
+        );
+    }
+
+    #[test]
+    fn facets_count_and_narrow_results() {
+        let index = corpus();
+        let query = parse_query("programming OR management").unwrap();
+        let mut options = SearchOptions::default();
+        let res = s_search_book(
+            &index,
+            &ResultSets::new(),
+            "",
+            &query,
+            &FilterSets::new(),
+            &options,
+        )
+        .unwrap();
+        assert_eq!(res.page.items.len(), 6);
+        assert_eq!(
+            res.facets.tags[..2],
+            [
+                TagFacet {
+                    id: 1,
+                    name: "programming".to_string(),
+                    count: 4,
+                },
+                TagFacet {
+                    id: 2,
+                    name: "clang".to_string(),
+                    count: 2,
+                },
+            ]
+        );
+        assert_eq!(
+            res.facets.authors[0],
+            FacetCount {
+                value: "Herbert Schildt".to_string(),
+                count: 2,
+            }
+        );
+        let decades: Vec<(i32, usize)> = res
+            .facets
+            .decades
+            .iter()
+            .map(|d| (d.value, d.count))
+            .collect();
+        assert_eq!(decades, [(2010, 3), (2000, 2), (1980, 1)]);
+
+        options.facets = FacetSelection {
+            tags: vec![1],
+            author: Some(" herbert schildt".to_string()),
+            decade: Some(2010),
+        };
+        let res = s_search_book(
+            &index,
+            &ResultSets::new(),
+            "",
+            &query,
+            &FilterSets::new(),
+            &options,
+        )
+        .unwrap();
+        let found: Vec<i32> = res.page.items.iter().map(|r| r.book.id).collect();
+        assert_eq!(found, [3]);
+        // Tags of Schildt's books from the 2010s
+        assert_eq!(res.facets.tags.len(), 2);
+        // Decades of Schildt's programming books
+        let decades: Vec<(i32, usize)> = res
+            .facets
+            .decades
+            .iter()
+            .map(|d| (d.value, d.count))
+            .collect();
+        assert_eq!(decades, [(2000, 1), (2010, 1)]);
+    }
+
+    #[test]
+    fn facets_ignore_their_own_selection() {
+        let mut books: Vec<Book> = (1..=7)
+            .filter_map(|id| corpus().book(id).cloned())
+            .collect();
+        books.push(book(
+            8,
+            "C Pointers",
+            "herbert  SCHILDT",
+            &["clang"],
+            "1995",
+            "",
+        ));
+        books.push(book(
+            9,
+            "C++ Basics",
+            "Herbert Schildt",
+            &["programming"],
+            "1998",
+            "",
+        ));
+        let index = SearchIndex::build(books);
+        let query = parse_query("programming OR management OR pointers").unwrap();
+        let mut options = SearchOptions::default();
+        options.facets.tags = vec![2];
+        let res = s_search_book(
+            &index,
+            &ResultSets::new(),
+            "",
+            &query,
+            &FilterSets::new(),
+            &options,
+        )
+        .unwrap();
+        let mut found: Vec<i32> = res.page.items.iter().map(|r| r.book.id).collect();
+        found.sort_unstable();
+        assert_eq!(found, [1, 2, 8]);
+
+        // Every tag of the results is still counted, not only the picked one
+        let tags: Vec<(&str, usize)> = res
+            .facets
+            .tags
+            .iter()
+            .map(|t| (t.name.as_str(), t.count))
+            .collect();
+        assert_eq!(
+            tags[..3],
+            [("programming", 5), ("clang", 3), ("management", 2)]
+        );
+        // Authors and decades only count books with the picked tag, spellings
+        // of one author are merged under the most used one
+        assert_eq!(
+            res.facets.authors[0],
+            FacetCount {
+                value: "Herbert Schildt".to_string(),
+                count: 2,
+            }
+        );
+        assert_eq!(res.facets.authors.len(), 2);
+
+        options.facets.author = Some("HERBERT schildt".to_string());
+        let res = s_search_book(
+            &index,
+            &ResultSets::new(),
+            "",
+            &query,
+            &FilterSets::new(),
+            &options,
+        )
+        .unwrap();
+        assert_eq!(res.page.total, 2);
+        let authors: Vec<(&str, usize)> = res
+            .facets
+            .authors
+            .iter()
+            .map(|a| (a.value.as_str(), a.count))
+            .collect();
+        assert_eq!(
+            authors,
+            [
+                ("Herbert Schildt", 2),
+                ("Brian Kernighan and Dennis Ritchie", 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn old_result_sets_expire() {
+        let index = corpus();
+        let sets = ResultSets::new();
+        let query = parse_query("programming").unwrap();
+        let options = SearchOptions {
+            limit: Some(1),
+            ..SearchOptions::default()
+        };
+        let search = |options: &SearchOptions| {
+            return s_search_book(&index, &sets, "", &query, &FilterSets::new(), options);
+        };
+        let first = search(&options).unwrap();
+        let cursor = Cursor::decode(first.page.next_cursor.as_deref().unwrap()).unwrap();
+        assert_eq!(cursor.pos, 1);
+        let next = SearchOptions {
+            cursor: Some(cursor),
+            ..options.clone()
+        };
+        let second = search(&next).unwrap();
+        assert_ne!(second.page.items[0].book.id, first.page.items[0].book.id);
+
+        for _ in 0..MAX_RESULT_SETS {
+            search(&options).unwrap();
+        }
+        assert!(search(&next).is_err());
+    }
 }
@@ -1,53 +1,781 @@
-use crate::search::s_search_book;
+use crate::analyzer::Lang;
+use crate::book::{
+    Book, BookPatch, NewBook, NewTag, Page, Tag, TagMerge, TagMergeResult, TagRef, TagRename,
+};
+use crate::centrality::{CentralBook, CentralNodes, CentralTag, Centrality, Measure};
+use crate::community::{communities, Communities, Community};
+use crate::complete::{Completer, Completions};
+use crate::error::AppError;
+use crate::graph::{GraphJson, Related, RelatedBooks, Similarity, TagGraph, TagNeighbors};
+use crate::index::SearchIndex;
+use crate::path::{cheapest, shortest_hops, GraphPath, Node, PathCost, PathStep};
+use crate::payload::{FromLegacyQuery, FromTextFields, Payload};
+use crate::search::{
+    parse_query, s_search_book, Cursor, FacetSelection, ResultSets, SearchOptions, SearchResponse,
+    SearchSort,
+};
 use crate::sql::*;
-use axum::{extract::Query, response::IntoResponse, Json};
-use serde_derive::Deserialize;
+use axum::{
+    extract::{
+        rejection::{JsonRejection, PathRejection, QueryRejection},
+        Path, Query, State,
+    },
+    http::{HeaderMap, StatusCode},
+    routing::{get, patch, post},
+    Json, Router,
+};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tokio::sync::{Mutex, Notify};
+use tokio::task::AbortHandle;
 
-// `/get_tag?s={startat}&e={endat}`
+// Every route the server answers, the verb-style paths are kept for the `design/` frontend
+pub fn router(state: AppState) -> Router {
+    return Router::new()
+        .route("/get_tag", get(get_tag))
+        .route("/search", get(search_book))
+        .route("/autocomplete", get(autocomplete))
+        .route("/get_tag_neighbors", get(get_tag_neighbors))
+        .route("/get_tag_graph", get(get_tag_graph))
+        .route("/get_related_books", get(get_related_books))
+        .route("/get_path", get(get_path))
+        .route("/get_tag_communities", get(get_tag_communities))
+        .route("/get_central", get(get_central))
+        .route("/get_book_info", get(get_book_info))
+        .route("/get_book_from_tag", get(get_book_from_tag))
+        .route("/add_book", post(add_new_book))
+        .route("/add_tag", post(add_new_tag))
+        .route("/del_tag", post(del_tag))
+        .route("/del_book", post(del_book))
+        .route("/books/:id", patch(update_book))
+        .route("/tags/merge", post(merge_tags))
+        .route("/tags/:id", patch(rename_tag))
+        .nest("/api/v1", api_v1())
+        .with_state(state);
+}
+
+// Resource routes under `/api/v1`
+fn api_v1() -> Router<AppState> {
+    return Router::new()
+        .route("/books", get(list_books).post(add_new_book))
+        .route(
+            "/books/:id",
+            get(get_book).patch(update_book).delete(delete_book),
+        )
+        .route("/books/:id/related", get(list_related_books))
+        .route("/tags", get(list_tags).post(add_new_tag))
+        .route("/tags/merge", post(merge_tags))
+        .route(
+            "/tags/:id",
+            get(get_one_tag).patch(rename_tag).delete(delete_tag),
+        )
+        .route("/tags/:id/books", get(list_tag_books))
+        .route("/tags/:id/neighbors", get(list_tag_neighbors))
+        .route("/graph/tags", get(get_tag_graph))
+        .route("/graph/path", get(get_path))
+        .route("/graph/communities", get(get_tag_communities))
+        .route("/graph/central", get(get_central))
+        .route("/search", get(search_book))
+        .route("/autocomplete", get(autocomplete));
+}
+
+// Shared by every handler through axum `State`
+#[derive(Clone)]
+pub struct AppState {
+    pub db: DbPool,
+    pub index: Arc<RwLock<SearchIndex>>,
+    pub completer: Arc<RwLock<Completer>>,
+    pub graph: Arc<RwLock<TagGraph>>,
+    // Centrality of the tag graph, worked out again off the request path
+    // after changes and swapped in whole, see `spawn_centrality`
+    pub centrality: Arc<RwLock<Arc<Centrality>>>,
+    graph_changed: Arc<Notify>,
+    // Only held so the task stops with the state
+    _centrality_task: Arc<AbortOnDrop>,
+    // Rankings that later relevance pages of `/search` are cut from
+    pub results: Arc<ResultSets>,
+    // Held by every handler that changes the catalog, from its database
+    // write until the in-memory structures are synced, so two changes
+    // can't interleave and leave the index behind the database
+    pub writes: Arc<Mutex<()>>,
+}
+
+// Stops a background task once the last clone of the state holding it is
+// dropped
+struct AbortOnDrop(AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+impl AppState {
+    // Wrap the pool and build the search index, completions and tag graph
+    // from what is in the database
+    pub async fn load(db: DbPool) -> Result<AppState, AppError> {
+        let books = sql_read_book(db.clone(), BookSort::default()).await?;
+        let tags = sql_read_all_tags(db.clone()).await?;
+        let completer = Completer::build(books.clone(), tags.clone());
+        let graph = TagGraph::build(books.clone(), tags);
+        let centrality = Arc::new(RwLock::new(Arc::new(Centrality::compute(&graph))));
+        let graph = Arc::new(RwLock::new(graph));
+        let graph_changed = Arc::new(Notify::new());
+        let task = AppState::spawn_centrality(&graph, &centrality, &graph_changed);
+        return Ok(AppState {
+            db,
+            index: Arc::new(RwLock::new(SearchIndex::build(books))),
+            completer: Arc::new(RwLock::new(completer)),
+            graph,
+            centrality,
+            graph_changed,
+            _centrality_task: Arc::new(AbortOnDrop(task)),
+            results: Arc::new(ResultSets::new()),
+            writes: Arc::new(Mutex::new(())),
+        });
+    }
+
+    // Background task working the centrality out again from a copy of the
+    // graph each time it changed, changes made meanwhile are picked up by
+    // the next round. Aborted once the last clone of the state is dropped
+    fn spawn_centrality(
+        graph: &Arc<RwLock<TagGraph>>,
+        centrality: &Arc<RwLock<Arc<Centrality>>>,
+        changed: &Arc<Notify>,
+    ) -> AbortHandle {
+        let graph = Arc::downgrade(graph);
+        let centrality = Arc::downgrade(centrality);
+        let changed = changed.clone();
+        let task = tokio::spawn(async move {
+            loop {
+                changed.notified().await;
+                let (Some(graph), Some(centrality)) = (graph.upgrade(), centrality.upgrade())
+                else {
+                    return;
+                };
+                let done = match centrality.read() {
+                    Ok(c) => c.generation,
+                    Err(_) => return,
+                };
+                let copy = match graph.read() {
+                    Ok(g) if g.generation() == done => continue,
+                    Ok(g) => g.clone(),
+                    Err(_) => return,
+                };
+                drop(graph);
+                let computed = tokio::task::spawn_blocking(move || Centrality::compute(&copy));
+                let Ok(computed) = computed.await else {
+                    continue;
+                };
+                let Ok(mut current) = centrality.write() else {
+                    return;
+                };
+                *current = Arc::new(computed);
+            }
+        });
+        return task.abort_handle();
+    }
+
+    // The latest centrality, possibly a round behind the graph
+    fn read_centrality(&self) -> Result<Arc<Centrality>, AppError> {
+        return self
+            .centrality
+            .read()
+            .map(|c| c.clone())
+            .map_err(|_| AppError::internal("centrality is poisoned"));
+    }
+
+    fn read_index(&self) -> Result<RwLockReadGuard<'_, SearchIndex>, AppError> {
+        return self
+            .index
+            .read()
+            .map_err(|_| AppError::internal("search index is poisoned"));
+    }
+
+    fn write_index(&self) -> Result<RwLockWriteGuard<'_, SearchIndex>, AppError> {
+        return self
+            .index
+            .write()
+            .map_err(|_| AppError::internal("search index is poisoned"));
+    }
+
+    fn read_completer(&self) -> Result<RwLockReadGuard<'_, Completer>, AppError> {
+        return self
+            .completer
+            .read()
+            .map_err(|_| AppError::internal("completions are poisoned"));
+    }
+
+    fn write_completer(&self) -> Result<RwLockWriteGuard<'_, Completer>, AppError> {
+        return self
+            .completer
+            .write()
+            .map_err(|_| AppError::internal("completions are poisoned"));
+    }
+
+    fn read_graph(&self) -> Result<RwLockReadGuard<'_, TagGraph>, AppError> {
+        return self
+            .graph
+            .read()
+            .map_err(|_| AppError::internal("tag graph is poisoned"));
+    }
+
+    fn write_graph(&self) -> Result<RwLockWriteGuard<'_, TagGraph>, AppError> {
+        return self
+            .graph
+            .write()
+            .map_err(|_| AppError::internal("tag graph is poisoned"));
+    }
+
+    // A page of tags ranked highest first unless `asc`. Book counts come
+    // straight from the graph, the other measures from the latest centrality
+    fn ranked_tags(
+        &self,
+        measure: Measure,
+        asc: bool,
+        from: i32,
+        range: i32,
+    ) -> Result<Page<Tag>, AppError> {
+        let graph = self.read_graph()?;
+        let mut ranked: Vec<i32> = match measure {
+            Measure::Degree => {
+                let mut counts: Vec<(i32, usize)> = graph
+                    .tag_ids()
+                    .map(|id| (id, graph.books_of(id).count()))
+                    .collect();
+                counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+                counts.into_iter().map(|(id, _)| id).collect()
+            }
+            // Tags added since the centrality was last worked out score 0
+            // until the next round catches up
+            Measure::Pagerank | Measure::Betweenness => {
+                let scores: HashMap<i32, f64> = self
+                    .read_centrality()?
+                    .tags(measure)
+                    .into_iter()
+                    .map(|(id, s)| (id, s.get(measure)))
+                    .collect();
+                let mut ranked: Vec<(i32, f64)> = graph
+                    .tag_ids()
+                    .map(|id| (id, scores.get(&id).copied().unwrap_or(0.0)))
+                    .collect();
+                ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+                ranked.into_iter().map(|(id, _)| id).collect()
+            }
+        };
+        if asc {
+            ranked.reverse();
+        }
+        let items = ranked
+            .iter()
+            .skip(from.max(0) as usize)
+            .take(range.max(0) as usize)
+            .filter_map(|id| graph.tag(*id))
+            .collect();
+        return Ok(Page::from_offset(items, ranked.len() as i64, from.max(0)));
+    }
+
+    // Books in the index carrying any of the tags
+    fn books_with_tags(&self, tag_ids: &[i32]) -> Result<Vec<i32>, AppError> {
+        return Ok(self.read_index()?.books_with_tags(tag_ids));
+    }
+
+    // Reload books from the database into the index, ids that are gone get dropped
+    async fn sync_books(&self, book_ids: Vec<i32>) -> Result<(), AppError> {
+        if book_ids.is_empty() {
+            return Ok(());
+        }
+        let books = sql_get_books(self.db.clone(), book_ids.clone()).await?;
+        for id in book_ids {
+            if !books.iter().any(|b| b.id == id) {
+                self.unindex_book(id)?;
+            }
+        }
+        for b in books {
+            self.index_book(b)?;
+        }
+        return Ok(());
+    }
+
+    // Reload tags from the database into the completions and the tag graph,
+    // ids that are gone get dropped
+    async fn sync_tags(&self, tag_ids: Vec<i32>) -> Result<(), AppError> {
+        if tag_ids.is_empty() {
+            return Ok(());
+        }
+        let tags = sql_get_tags(self.db.clone(), tag_ids.clone()).await?;
+        let mut completer = self.write_completer()?;
+        let mut graph = self.write_graph()?;
+        for id in tag_ids {
+            completer.remove_tag(id);
+            graph.remove_tag(id);
+        }
+        for t in tags {
+            completer.upsert_tag(t.clone());
+            graph.upsert_tag(t);
+        }
+        self.graph_changed.notify_one();
+        return Ok(());
+    }
+
+    fn index_book(&self, book: Book) -> Result<(), AppError> {
+        self.write_completer()?.upsert_book(&book);
+        self.write_graph()?.upsert_book(&book);
+        self.graph_changed.notify_one();
+        self.write_index()?.upsert(book);
+        return Ok(());
+    }
+
+    fn unindex_book(&self, book_id: i32) -> Result<(), AppError> {
+        self.write_completer()?.remove_book(book_id);
+        self.write_graph()?.remove_book(book_id);
+        self.graph_changed.notify_one();
+        self.write_index()?.remove(book_id);
+        return Ok(());
+    }
+}
+
+// `order` on tag listings: `popular` ranks by number of books and `central`
+// by PageRank over the book-tag graph, anything else sorts by name
+fn tag_measure(order: Option<&str>) -> Option<Measure> {
+    match order.map(|s| s.trim().to_lowercase()).as_deref() {
+        Some("popular") => Some(Measure::Degree),
+        Some("central") => Some(Measure::Pagerank),
+        _ => None,
+    }
+}
+
+// Tags by name from the database, or ranked from the tag graph
+async fn read_tags(
+    state: AppState,
+    from: i32,
+    range: i32,
+    sort: Option<&str>,
+    order: Option<&str>,
+) -> Result<Page<Tag>, AppError> {
+    let Some(measure) = tag_measure(order) else {
+        let sorting_mode = SortOrder::from_param(sort);
+        return sql_read_tags(state.db, from, range, sorting_mode).await;
+    };
+    // Ranked lists start with the highest score unless asked otherwise
+    let asc = sort.is_some_and(|s| s.trim().eq_ignore_ascii_case("asc"));
+    return state.ranked_tags(measure, asc, from, range);
+}
+
+// `/get_tag?f={from}&r={range}&order={name|popular|central}`
 #[derive(Deserialize)]
 pub struct GetTagParams {
     f: i32,
     r: i32,
     sort: Option<String>,
+    order: Option<String>,
+}
+pub async fn get_tag(
+    State(state): State<AppState>,
+    query: Result<Query<GetTagParams>, QueryRejection>,
+) -> Result<Json<Page<Tag>>, AppError> {
+    let Query(params) = query?;
+    check_window(params.r, params.f)?;
+    let res = read_tags(
+        state,
+        params.f,
+        params.r,
+        params.sort.as_deref(),
+        params.order.as_deref(),
+    )
+    .await?;
+    return Ok(Json(res));
 }
-pub async fn get_tag(Query(params): Query<GetTagParams>) -> impl IntoResponse {
-    let sort_parse: String = params.sort.as_deref().unwrap_or("ASC").to_string();
-    let sorting_mode: String;
-    if !is_valid_sort(&sort_parse){
-        sorting_mode = "ASC".to_string();
-    } else {
-        sorting_mode = sort_parse;
-    }
-    match sql_read_tags(params.f, params.r, sorting_mode).await {
-        Ok(val) => {
-            if val.len() <= 0 {
-                return Json(None);
+
+// `/search?q={query}&order={relevance|title|author|year|newest}&sort={asc|desc}&lang={en|id}&limit={n}&cursor={next_cursor}`
+// `q` takes the query syntax of `search::parse_query`, filters go to the database
+#[derive(Deserialize)]
+pub struct SearchParams {
+    q: String,
+    sort: Option<String>,
+    order: Option<String>,
+    lang: Option<String>,
+    // Facets picked on a previous response: `tags=1,2`, `author=..`, `decade=1990`
+    tags: Option<String>,
+    author: Option<String>,
+    decade: Option<i32>,
+    limit: Option<i32>,
+    cursor: Option<String>,
+}
+
+impl SearchParams {
+    fn facets(&self) -> Result<FacetSelection, AppError> {
+        let mut tags: Vec<i32> = Vec::new();
+        for id in self.tags.as_deref().unwrap_or("").split(',') {
+            if id.trim().is_empty() {
+                continue;
             }
-            return Json(Some(val));
+            let id = id.trim().parse::<i32>().map_err(|_| {
+                AppError::validation(format!(
+                    "tags must be tag ids separated by commas, got '{}'",
+                    id
+                ))
+            })?;
+            tags.push(id);
         }
-        Err(_) => return Json(None),
+        return Ok(FacetSelection {
+            tags,
+            author: self.author.clone().filter(|a| !a.trim().is_empty()),
+            decade: self.decade,
+        });
+    }
+}
+pub async fn search_book(
+    State(state): State<AppState>,
+    query: Result<Query<SearchParams>, QueryRejection>,
+) -> Result<Json<SearchResponse>, AppError> {
+    let Query(params) = query?;
+    let sort = SearchSort::from_params(params.order.as_deref(), params.sort.as_deref());
+    // Without `lang` the language is guessed from the query itself
+    let lang = match params.lang.as_deref() {
+        None | Some("") => None,
+        Some(l) => Some(Lang::from_param(l).ok_or_else(|| {
+            AppError::validation(format!("unknown lang '{}', use 'en' or 'id'", l))
+        })?),
+    };
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT);
+    if !(0..=MAX_LIMIT).contains(&limit) {
+        return Err(AppError::validation(format!(
+            "limit must be between 0 and {}",
+            MAX_LIMIT
+        )));
     }
+    let cursor = match params.cursor.as_deref() {
+        None | Some("") => None,
+        Some(c) => Some(Cursor::decode(c)?),
+    };
+    if cursor.as_ref().is_some_and(|c| c.sort != sort) {
+        return Err(AppError::validation(
+            "cursor belongs to a search with another order, start again without it",
+        ));
+    }
+    let options = SearchOptions {
+        lang,
+        sort,
+        facets: params.facets()?,
+        limit: Some(limit as usize),
+        cursor,
+    };
+    let query = parse_query(&params.q)?;
+    let filters = sql_filter_books(state.db.clone(), query.filters()).await?;
+    let res = s_search_book(
+        &*state.read_index()?,
+        &state.results,
+        &params.q,
+        &query,
+        &filters,
+        &options,
+    )?;
+    return Ok(Json(res));
 }
 
-// `/search?q={query}`
+// `/autocomplete?q={typed so far}&limit={per kind}`
 #[derive(Deserialize)]
-pub struct SearchParams {
+pub struct AutocompleteParams {
     q: String,
-    sort: Option<String>,
+    limit: Option<i32>,
 }
-pub async fn search_book(Query(params): Query<SearchParams>) -> impl IntoResponse {
-    let sort_parse: String = params.sort.as_deref().unwrap_or("ASC").to_string();
-    let sorting_mode: String;
-    if !is_valid_sort(&sort_parse){
-        sorting_mode = "ASC".to_string();
-    } else {
-        sorting_mode = sort_parse;
+pub async fn autocomplete(
+    State(state): State<AppState>,
+    query: Result<Query<AutocompleteParams>, QueryRejection>,
+) -> Result<Json<Completions>, AppError> {
+    let Query(params) = query?;
+    let limit = params.limit.unwrap_or(AUTOCOMPLETE_LIMIT);
+    if !(0..=MAX_AUTOCOMPLETE_LIMIT).contains(&limit) {
+        return Err(AppError::validation(format!(
+            "limit must be between 0 and {}",
+            MAX_AUTOCOMPLETE_LIMIT
+        )));
+    }
+    let res = state.read_completer()?.complete(&params.q, limit as usize);
+    return Ok(Json(res));
+}
+
+// Neighbors of a tag in the tag graph, 404 for a tag that does not exist
+fn tag_neighbors(state: &AppState, id: i32, limit: Option<i32>) -> Result<TagNeighbors, AppError> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT);
+    if !(0..=MAX_LIMIT).contains(&limit) {
+        return Err(AppError::validation(format!(
+            "limit must be between 0 and {}",
+            MAX_LIMIT
+        )));
     }
-    let res = s_search_book(&params.q, sorting_mode).await;
-    if res.len() <= 0 {
-        return Json(None);
+    let graph = state.read_graph()?;
+    let tag = graph
+        .tag(id)
+        .ok_or_else(|| AppError::not_found(format!("tag {} does not exist", id)))?;
+    let mut neighbors = graph.neighbors(id);
+    neighbors.truncate(limit as usize);
+    return Ok(TagNeighbors { tag, neighbors });
+}
+
+// `/get_tag_neighbors?id={tag}&limit={n}`
+#[derive(Deserialize)]
+pub struct TagNeighborsParams {
+    id: i32,
+    limit: Option<i32>,
+}
+pub async fn get_tag_neighbors(
+    State(state): State<AppState>,
+    query: Result<Query<TagNeighborsParams>, QueryRejection>,
+) -> Result<Json<TagNeighbors>, AppError> {
+    let Query(params) = query?;
+    return Ok(Json(tag_neighbors(&state, params.id, params.limit)?));
+}
+
+// `/get_tag_graph?min_weight={n}`, every tag as a node and every pair of
+// tags sharing at least `min_weight` books as an edge
+#[derive(Deserialize)]
+pub struct TagGraphParams {
+    min_weight: Option<u32>,
+}
+pub async fn get_tag_graph(
+    State(state): State<AppState>,
+    query: Result<Query<TagGraphParams>, QueryRejection>,
+) -> Result<Json<GraphJson>, AppError> {
+    let Query(params) = query?;
+    let res = state.read_graph()?.to_json(params.min_weight.unwrap_or(1));
+    return Ok(Json(res));
+}
+
+// Books sharing tags with a book, 404 for a book that does not exist
+fn related_books(
+    state: &AppState,
+    id: i32,
+    params: &RelatedParams,
+) -> Result<RelatedBooks, AppError> {
+    let limit = params.limit.unwrap_or(RELATED_LIMIT);
+    if !(0..=MAX_LIMIT).contains(&limit) {
+        return Err(AppError::validation(format!(
+            "limit must be between 0 and {}",
+            MAX_LIMIT
+        )));
     }
-    Json(Some(res))
+    let method = params.method.unwrap_or_default();
+    let found = {
+        let graph = state.read_graph()?;
+        if !graph.has_book(id) {
+            return Err(AppError::not_found(format!("book {} does not exist", id)));
+        }
+        graph.related_books(id, method)
+    };
+    let index = state.read_index()?;
+    let related = found
+        .into_iter()
+        .filter_map(|r| {
+            Some(Related {
+                book: index.book(r.book_id)?.clone(),
+                score: r.score,
+                shared_tags: r.shared_tags,
+            })
+        })
+        .take(limit as usize)
+        .collect();
+    return Ok(RelatedBooks {
+        id,
+        method,
+        related,
+    });
+}
+
+// `?method={adamic_adar|jaccard}&limit={n}` on the related books endpoints
+#[derive(Deserialize)]
+pub struct RelatedParams {
+    method: Option<Similarity>,
+    limit: Option<i32>,
+}
+
+// `/get_related_books?id={book}&method={adamic_adar|jaccard}&limit={n}`
+#[derive(Deserialize)]
+pub struct GetRelatedParams {
+    id: i32,
+    method: Option<Similarity>,
+    limit: Option<i32>,
+}
+pub async fn get_related_books(
+    State(state): State<AppState>,
+    query: Result<Query<GetRelatedParams>, QueryRejection>,
+) -> Result<Json<RelatedBooks>, AppError> {
+    let Query(params) = query?;
+    let rest = RelatedParams {
+        method: params.method,
+        limit: params.limit,
+    };
+    return Ok(Json(related_books(&state, params.id, &rest)?));
+}
+
+// `/get_path?from={book:id|tag:id}&to={book:id|tag:id}&method={hops|weighted}`
+#[derive(Deserialize)]
+pub struct PathParams {
+    from: String,
+    to: String,
+    method: Option<PathCost>,
+}
+pub async fn get_path(
+    State(state): State<AppState>,
+    query: Result<Query<PathParams>, QueryRejection>,
+) -> Result<Json<GraphPath>, AppError> {
+    let Query(params) = query?;
+    let from = Node::from_param(&params.from)?;
+    let to = Node::from_param(&params.to)?;
+    let method = params.method.unwrap_or_default();
+
+    let (nodes, cost, tags) = {
+        let graph = state.read_graph()?;
+        for node in [from, to] {
+            let known = match node {
+                Node::Book(id) => graph.has_book(id),
+                Node::Tag(id) => graph.has_tag(id),
+            };
+            if !known {
+                return Err(AppError::not_found(format!(
+                    "{} does not exist",
+                    node.describe()
+                )));
+            }
+        }
+        let found = match method {
+            PathCost::Hops => shortest_hops(&graph, from, to).map(|p| (p.len() as f64 - 1.0, p)),
+            PathCost::Weighted => cheapest(&graph, from, to).map(|(p, cost)| (cost, p)),
+        };
+        let Some((cost, nodes)) = found else {
+            return Err(AppError::not_found(format!(
+                "{} and {} are not connected",
+                from.describe(),
+                to.describe()
+            ))
+            .with_details(json!({ "from": params.from, "to": params.to })));
+        };
+        let tags: HashMap<i32, Tag> = nodes
+            .iter()
+            .filter_map(|n| match n {
+                Node::Tag(id) => Some((*id, graph.tag(*id)?)),
+                Node::Book(_) => None,
+            })
+            .collect();
+        (nodes, cost, tags)
+    };
+
+    let index = state.read_index()?;
+    let mut path: Vec<PathStep> = Vec::new();
+    for node in &nodes {
+        let step = match node {
+            Node::Book(id) => index.book(*id).cloned().map(PathStep::Book),
+            Node::Tag(id) => tags.get(id).cloned().map(PathStep::Tag),
+        };
+        path.push(step.ok_or_else(|| {
+            AppError::internal(format!("{} is missing from the catalog", node.describe()))
+        })?);
+    }
+    return Ok(Json(GraphPath {
+        method,
+        hops: nodes.len() - 1,
+        cost,
+        path,
+    }));
+}
+
+// `/get_tag_communities`, tags grouped into the subject clusters of the
+// catalog for the category page
+pub async fn get_tag_communities(
+    State(state): State<AppState>,
+) -> Result<Json<Communities>, AppError> {
+    let (partition, tags) = {
+        let graph = state.read_graph()?;
+        let partition = communities(&graph);
+        let tags: HashMap<i32, Tag> = graph
+            .tag_ids()
+            .filter_map(|id| Some((id, graph.tag(id)?)))
+            .collect();
+        (partition, tags)
+    };
+    let index = state.read_index()?;
+    let communities = partition
+        .groups
+        .into_iter()
+        .enumerate()
+        .map(|(id, group)| Community {
+            id,
+            component: group.component,
+            modularity: group.modularity,
+            tags: group
+                .tags
+                .iter()
+                .filter_map(|t| tags.get(t).cloned())
+                .collect(),
+            books: group
+                .books
+                .iter()
+                .filter_map(|b| index.book(*b).cloned())
+                .collect(),
+        })
+        .collect();
+    return Ok(Json(Communities {
+        modularity: partition.modularity,
+        components: partition.components,
+        communities,
+    }));
+}
+
+// `/get_central?measure={degree|pagerank|betweenness}&limit={n}`
+#[derive(Deserialize)]
+pub struct CentralParams {
+    measure: Option<Measure>,
+    limit: Option<i32>,
+}
+pub async fn get_central(
+    State(state): State<AppState>,
+    query: Result<Query<CentralParams>, QueryRejection>,
+) -> Result<Json<CentralNodes>, AppError> {
+    let Query(params) = query?;
+    let limit = params.limit.unwrap_or(RELATED_LIMIT);
+    if !(0..=MAX_LIMIT).contains(&limit) {
+        return Err(AppError::validation(format!(
+            "limit must be between 0 and {}",
+            MAX_LIMIT
+        )));
+    }
+    let measure = params.measure.unwrap_or_default();
+    let centrality = state.read_centrality()?;
+    let (books, tags) = {
+        let graph = state.read_graph()?;
+        let mut books = centrality.books(measure);
+        books.truncate(limit as usize);
+        let tags: Vec<CentralTag> = centrality
+            .tags(measure)
+            .into_iter()
+            .filter_map(|(id, scores)| {
+                Some(CentralTag {
+                    tag: graph.tag(id)?,
+                    scores,
+                })
+            })
+            .take(limit as usize)
+            .collect();
+        (books, tags)
+    };
+    let index = state.read_index()?;
+    let books = books
+        .into_iter()
+        .filter_map(|(id, scores)| {
+            Some(CentralBook {
+                book: index.book(id)?.clone(),
+                scores,
+            })
+        })
+        .collect();
+    return Ok(Json(CentralNodes {
+        measure,
+        books,
+        tags,
+    }));
 }
 
 // `/get_book_info?id={id}`
@@ -56,18 +784,14 @@ pub struct BookInfoParams {
     id: i32,
     sort: Option<String>,
 }
-pub async fn get_book_info(Query(params): Query<BookInfoParams>) -> impl IntoResponse {
-    let sort_parse: String = params.sort.as_deref().unwrap_or("ASC").to_string();
-    let sorting_mode: String;
-    if !is_valid_sort(&sort_parse){
-        sorting_mode = "ASC".to_string();
-    } else {
-        sorting_mode = sort_parse;
-    }
-    match sql_get_book_info(params.id, sorting_mode).await {
-        Ok(val) => Json(Some(val)),
-        Err(_) => Json(None),
-    }
+pub async fn get_book_info(
+    State(state): State<AppState>,
+    query: Result<Query<BookInfoParams>, QueryRejection>,
+) -> Result<Json<Book>, AppError> {
+    let Query(params) = query?;
+    let sorting_mode = SortOrder::from_param(params.sort.as_deref());
+    let res = sql_get_book_info(state.db, params.id, sorting_mode).await?;
+    return Ok(Json(res));
 }
 
 // `/get_book_from_tag?id={tag}&f={from}&r={range}`
@@ -77,29 +801,30 @@ pub struct GetBookListFromTagParams {
     r: i32,
     id: i32,
     sort: Option<String>,
+    order: Option<String>,
 }
 pub async fn get_book_from_tag(
-    Query(params): Query<GetBookListFromTagParams>,
-) -> impl IntoResponse {
-    let sort_parse: String = params.sort.as_deref().unwrap_or("ASC").to_string();
-    let sorting_mode: String;
-    if !is_valid_sort(&sort_parse){
-        sorting_mode = "ASC".to_string();
-    } else {
-        sorting_mode = sort_parse;
-    }
-    match sql_read_specified_tagged_book(params.id, params.r, params.f, sorting_mode).await {
-        Ok(val) => {
-            if val.len() <= 0 {
-                return Json(None);
-            }
-            return Json(Some(val));
-        }
-        Err(_) => return Json(None),
-    }
+    State(state): State<AppState>,
+    query: Result<Query<GetBookListFromTagParams>, QueryRejection>,
+) -> Result<Json<Page<Book>>, AppError> {
+    let Query(params) = query?;
+    check_window(params.r, params.f)?;
+    let sort = BookSort {
+        column: BookColumn::from_param(params.order.as_deref()),
+        order: SortOrder::from_param(params.sort.as_deref()),
+    };
+    let res = sql_read_specified_tagged_book(state.db, params.id, params.r, params.f, sort).await?;
+    return Ok(Json(res));
+}
+
+// Reply for `/add_book` and `/add_tag`, carries the id sqlite assigned
+#[derive(Serialize)]
+pub struct AddedResponse {
+    status: &'static str,
+    id: i64,
 }
 
-// `/add_book?t={title}&a={author}&tg={tag} {tag}&im={path}`
+// Legacy `/add_book?title={title}&author={author}&tagid={tag} {tag}&year={year}&desc={desc}&imgp={path}`
 #[derive(Deserialize)]
 pub struct AddBookParams {
     title: String,
@@ -107,38 +832,103 @@ pub struct AddBookParams {
     tagid: String,
     imgp: String,
     year: String,
-    desc: String
+    desc: String,
 }
-pub async fn add_new_book(Query(params): Query<AddBookParams>) -> impl IntoResponse {
-    match sql_add_new_book(&params.title, &params.author, &params.tagid, &params.year, &params.desc, &params.imgp).await {
-        Ok(_) => Json(Some("SUCCESS")),
-        Err(_) => return Json(None),
+impl FromLegacyQuery for NewBook {
+    type Params = AddBookParams;
+
+    fn from_legacy(params: AddBookParams) -> Result<NewBook, AppError> {
+        let mut tags: Vec<TagRef> = Vec::new();
+        for tag in params.tagid.split_whitespace() {
+            let id: i32 = tag
+                .parse()
+                .map_err(|_| AppError::validation(format!("'{}' is not a valid tag id", tag)))?;
+            tags.push(TagRef::Id(id));
+        }
+        return Ok(NewBook {
+            title: params.title,
+            author: params.author,
+            desc: params.desc,
+            year: params.year,
+            cover: params.imgp,
+            tags,
+        });
     }
 }
 
-// `/add_tag?n={name}&im={image blob}`
+impl FromTextFields for NewBook {
+    const ID_FIELDS: &'static [&'static str] = &["tags"];
+}
+
+// `/add_book` with a JSON or multipart `NewBook` body
+pub async fn add_new_book(
+    State(state): State<AppState>,
+    payload: Payload<NewBook>,
+) -> Result<(HeaderMap, Json<AddedResponse>), AppError> {
+    let headers = payload.headers();
+    let _write = state.writes.lock().await;
+    let id = sql_add_new_book(state.db.clone(), payload.value).await?;
+    state.sync_books(vec![id as i32]).await?;
+    return Ok((
+        headers,
+        Json(AddedResponse {
+            status: "SUCCESS",
+            id,
+        }),
+    ));
+}
+
+// Legacy `/add_tag?name={name}&imgp={image blob}`
 #[derive(Deserialize)]
 pub struct AddTagParams {
     name: String,
     imgp: String,
 }
-pub async fn add_new_tag(Query(params): Query<AddTagParams>) -> impl IntoResponse {
-    match sql_add_new_tag(&params.name, &params.imgp).await {
-        Ok(_) => Json(Some("SUCCESS")),
-        Err(_) => return Json(None),
+impl FromLegacyQuery for NewTag {
+    type Params = AddTagParams;
+
+    fn from_legacy(params: AddTagParams) -> Result<NewTag, AppError> {
+        return Ok(NewTag {
+            name: params.name,
+            img: params.imgp,
+        });
     }
 }
 
+impl FromTextFields for NewTag {}
+
+// `/add_tag` with a JSON or multipart `NewTag` body
+pub async fn add_new_tag(
+    State(state): State<AppState>,
+    payload: Payload<NewTag>,
+) -> Result<(HeaderMap, Json<AddedResponse>), AppError> {
+    let headers = payload.headers();
+    let _write = state.writes.lock().await;
+    let id = sql_add_new_tag(state.db.clone(), payload.value).await?;
+    state.sync_tags(vec![id as i32]).await?;
+    return Ok((
+        headers,
+        Json(AddedResponse {
+            status: "SUCCESS",
+            id,
+        }),
+    ));
+}
+
 // `/del_book?id={book_id}`
 #[derive(Deserialize)]
 pub struct DelBookParams {
     id: i32,
 }
-pub async fn del_book(Query(params): Query<DelBookParams>) -> impl IntoResponse {
-    match sql_del_book_from_id(params.id).await {
-        Ok(_) => Json(Some("SUCCESS")),
-        Err(_) => return Json(None),
-    }
+pub async fn del_book(
+    State(state): State<AppState>,
+    query: Result<Query<DelBookParams>, QueryRejection>,
+) -> Result<Json<&'static str>, AppError> {
+    let Query(params) = query?;
+    let _write = state.writes.lock().await;
+    sql_del_book_from_id(state.db.clone(), params.id).await?;
+    state.unindex_book(params.id)?;
+    return Ok(Json("SUCCESS"));
 }
 
 // `/del_tag?id={tag_id}`
@@ -146,9 +936,1600 @@ pub async fn del_book(Query(params): Query<DelBookParams>) -> impl IntoResponse
 pub struct DelTagParams {
     id: i32,
 }
-pub async fn del_tag(Query(params): Query<DelTagParams>) -> impl IntoResponse {
-    match sql_del_tag_from_id(params.id).await {
-        Ok(_) => Json(Some("SUCCESS")),
-        Err(_) => return Json(None),
+pub async fn del_tag(
+    State(state): State<AppState>,
+    query: Result<Query<DelTagParams>, QueryRejection>,
+) -> Result<Json<&'static str>, AppError> {
+    let Query(params) = query?;
+    let _write = state.writes.lock().await;
+    let affected = state.books_with_tags(&[params.id])?;
+    sql_del_tag_from_id(state.db.clone(), params.id).await?;
+    state.sync_books(affected).await?;
+    state.sync_tags(vec![params.id]).await?;
+    return Ok(Json("SUCCESS"));
+}
+
+// `PATCH /books/{id}` with a JSON `BookPatch` body
+pub async fn update_book(
+    State(state): State<AppState>,
+    path: Result<Path<i32>, PathRejection>,
+    body: Result<Json<BookPatch>, JsonRejection>,
+) -> Result<Json<Book>, AppError> {
+    let Path(id) = path?;
+    let Json(patch) = body?;
+    let _write = state.writes.lock().await;
+    let res = sql_update_book(state.db.clone(), id, patch).await?;
+    state.index_book(res.clone())?;
+    return Ok(Json(res));
+}
+
+// `PATCH /tags/{id}` with a JSON `{"name": ..}` body
+pub async fn rename_tag(
+    State(state): State<AppState>,
+    path: Result<Path<i32>, PathRejection>,
+    body: Result<Json<TagRename>, JsonRejection>,
+) -> Result<Json<Tag>, AppError> {
+    let Path(id) = path?;
+    let Json(rename) = body?;
+    let _write = state.writes.lock().await;
+    let res = sql_rename_tag(state.db.clone(), id, &rename.name).await?;
+    state.sync_books(state.books_with_tags(&[id])?).await?;
+    state.sync_tags(vec![id]).await?;
+    return Ok(Json(res));
+}
+
+// `POST /tags/merge` with a JSON `{"sources": [..], "target": ..}` body
+pub async fn merge_tags(
+    State(state): State<AppState>,
+    body: Result<Json<TagMerge>, JsonRejection>,
+) -> Result<Json<TagMergeResult>, AppError> {
+    let Json(merge) = body?;
+    let _write = state.writes.lock().await;
+    let affected = state.books_with_tags(&merge.sources)?;
+    let mut tags = merge.sources.clone();
+    tags.push(merge.target);
+    let res = sql_merge_tags(state.db.clone(), merge).await?;
+    state.sync_books(affected).await?;
+    state.sync_tags(tags).await?;
+    return Ok(Json(res));
+}
+
+const DEFAULT_LIMIT: i32 = 20;
+const MAX_LIMIT: i32 = 200;
+const AUTOCOMPLETE_LIMIT: i32 = 5;
+const RELATED_LIMIT: i32 = 10;
+const MAX_AUTOCOMPLETE_LIMIT: i32 = 20;
+
+// `?offset={from}&limit={range}&cursor={next_cursor}&sort={asc|desc}&order={column}`
+// on `/api/v1` listings
+#[derive(Deserialize)]
+pub struct ListParams {
+    offset: Option<i32>,
+    limit: Option<i32>,
+    // `next_cursor` of the previous page, same as passing it as `offset`
+    cursor: Option<String>,
+    sort: Option<String>,
+    order: Option<String>,
+}
+
+impl ListParams {
+    // (limit, offset) checked against the allowed range
+    fn window(&self) -> Result<(i32, i32), AppError> {
+        let offset = match self.cursor.as_deref() {
+            Some(c) if !c.is_empty() => c
+                .parse::<i32>()
+                .map_err(|_| AppError::validation(format!("invalid cursor '{}'", c)))?,
+            _ => self.offset.unwrap_or(0),
+        };
+        let limit = self.limit.unwrap_or(DEFAULT_LIMIT);
+        check_window(limit, offset)?;
+        return Ok((limit, offset));
+    }
+
+    fn book_sort(&self) -> BookSort {
+        return BookSort {
+            column: BookColumn::from_param(self.order.as_deref()),
+            order: SortOrder::from_param(self.sort.as_deref()),
+        };
+    }
+}
+
+// Page size and start of a listing, `r` and `f` on the legacy routes
+fn check_window(limit: i32, offset: i32) -> Result<(), AppError> {
+    if offset < 0 {
+        return Err(AppError::validation("offset cannot be negative"));
+    }
+    if !(0..=MAX_LIMIT).contains(&limit) {
+        return Err(AppError::validation(format!(
+            "limit must be between 0 and {}",
+            MAX_LIMIT
+        )));
+    }
+    return Ok(());
+}
+
+// `GET /api/v1/books`
+pub async fn list_books(
+    State(state): State<AppState>,
+    query: Result<Query<ListParams>, QueryRejection>,
+) -> Result<Json<Page<Book>>, AppError> {
+    let Query(params) = query?;
+    let (limit, offset) = params.window()?;
+    let res = sql_read_books(state.db, limit, offset, params.book_sort()).await?;
+    return Ok(Json(res));
+}
+
+// `GET /api/v1/books/{id}`
+pub async fn get_book(
+    State(state): State<AppState>,
+    path: Result<Path<i32>, PathRejection>,
+    query: Result<Query<ListParams>, QueryRejection>,
+) -> Result<Json<Book>, AppError> {
+    let Path(id) = path?;
+    let Query(params) = query?;
+    let sorting_mode = SortOrder::from_param(params.sort.as_deref());
+    let res = sql_get_book_info(state.db, id, sorting_mode).await?;
+    return Ok(Json(res));
+}
+
+// `DELETE /api/v1/books/{id}`
+pub async fn delete_book(
+    State(state): State<AppState>,
+    path: Result<Path<i32>, PathRejection>,
+) -> Result<StatusCode, AppError> {
+    let Path(id) = path?;
+    let _write = state.writes.lock().await;
+    sql_del_book_from_id(state.db.clone(), id).await?;
+    state.unindex_book(id)?;
+    return Ok(StatusCode::NO_CONTENT);
+}
+
+// `GET /api/v1/tags`
+pub async fn list_tags(
+    State(state): State<AppState>,
+    query: Result<Query<ListParams>, QueryRejection>,
+) -> Result<Json<Page<Tag>>, AppError> {
+    let Query(params) = query?;
+    let (limit, offset) = params.window()?;
+    let res = read_tags(
+        state,
+        offset,
+        limit,
+        params.sort.as_deref(),
+        params.order.as_deref(),
+    )
+    .await?;
+    return Ok(Json(res));
+}
+
+// `GET /api/v1/tags/{id}`
+pub async fn get_one_tag(
+    State(state): State<AppState>,
+    path: Result<Path<i32>, PathRejection>,
+) -> Result<Json<Tag>, AppError> {
+    let Path(id) = path?;
+    let res = sql_get_tag(state.db, id).await?;
+    return Ok(Json(res));
+}
+
+// `DELETE /api/v1/tags/{id}`
+pub async fn delete_tag(
+    State(state): State<AppState>,
+    path: Result<Path<i32>, PathRejection>,
+) -> Result<StatusCode, AppError> {
+    let Path(id) = path?;
+    let _write = state.writes.lock().await;
+    let affected = state.books_with_tags(&[id])?;
+    sql_del_tag_from_id(state.db.clone(), id).await?;
+    state.sync_books(affected).await?;
+    state.sync_tags(vec![id]).await?;
+    return Ok(StatusCode::NO_CONTENT);
+}
+
+// `GET /api/v1/tags/{id}/books`
+pub async fn list_tag_books(
+    State(state): State<AppState>,
+    path: Result<Path<i32>, PathRejection>,
+    query: Result<Query<ListParams>, QueryRejection>,
+) -> Result<Json<Page<Book>>, AppError> {
+    let Path(id) = path?;
+    let Query(params) = query?;
+    let (limit, offset) = params.window()?;
+    let res =
+        sql_read_specified_tagged_book(state.db, id, limit, offset, params.book_sort()).await?;
+    return Ok(Json(res));
+}
+
+// `GET /api/v1/books/{id}/related`
+pub async fn list_related_books(
+    State(state): State<AppState>,
+    path: Result<Path<i32>, PathRejection>,
+    query: Result<Query<RelatedParams>, QueryRejection>,
+) -> Result<Json<RelatedBooks>, AppError> {
+    let Path(id) = path?;
+    let Query(params) = query?;
+    return Ok(Json(related_books(&state, id, &params)?));
+}
+
+// `GET /api/v1/tags/{id}/neighbors`
+pub async fn list_tag_neighbors(
+    State(state): State<AppState>,
+    path: Result<Path<i32>, PathRejection>,
+    query: Result<Query<ListParams>, QueryRejection>,
+) -> Result<Json<TagNeighbors>, AppError> {
+    let Path(id) = path?;
+    let Query(params) = query?;
+    // Neighbors come ranked by shared books in one list, paging or
+    // re-sorting them would be silently ignored
+    let unused = [
+        ("offset", params.offset.is_some()),
+        ("cursor", params.cursor.is_some()),
+        ("sort", params.sort.is_some()),
+        ("order", params.order.is_some()),
+    ];
+    if let Some((name, _)) = unused.iter().find(|(_, given)| *given) {
+        return Err(AppError::validation(format!(
+            "'{}' is not supported on tag neighbors, only 'limit' is",
+            name
+        )));
+    }
+    return Ok(Json(tag_neighbors(&state, id, params.limit)?));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorKind;
+    use axum::body::{to_bytes, Body};
+    use axum::http::{Method, Request};
+    use tempfile::TempDir;
+    use tower::ServiceExt;
+
+    const PAYLOADS: &[&str] = &[
+        "1 OR 1=1",
+        "1; DROP TABLE book",
+        "'; DROP TABLE book; --",
+        "\" OR \"\"=\"",
+        "ASC; DELETE FROM all_tags",
+        "x' UNION SELECT tags_id, name, img, 1, 1, 1 FROM all_tags --",
+        "%' OR '1'='1",
+    ];
+
+    // Fresh migrated database with two tags and one book linked to both
+    async fn test_state() -> (TempDir, AppState) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.sqlite");
+        let path = path.to_str().unwrap();
+        run_migrations(path, false).unwrap();
+        let pool = create_pool(path, 2).unwrap();
+        pool.get()
+            .unwrap()
+            .execute_batch(
+                "INSERT INTO all_tags (name, img) VALUES ('programming', ''), ('clang', '');
+                INSERT INTO book (title, author, desc, year, cover)
+                    VALUES ('C: The Complete Reference', 'Herbert Schildt', 'C99', '2000', '');
+                INSERT INTO book_tags (book_id, tags_id) VALUES (1, 1), (1, 2);",
+            )
+            .unwrap();
+        let state = AppState::load(pool).await.unwrap();
+        return (dir, state);
+    }
+
+    // Wait for the background centrality to catch up with the graph
+    async fn settle(state: &AppState) {
+        loop {
+            let graph = state.read_graph().unwrap().generation();
+            if state.read_centrality().unwrap().generation == graph {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn centrality_task_ends_with_the_state() {
+        let (_dir, state) = test_state().await;
+        let task = state._centrality_task.0.clone();
+        let handler = state.clone();
+        drop(state);
+        tokio::task::yield_now().await;
+        assert!(!task.is_finished());
+
+        // The last clone takes the task with it, no graph change needed
+        drop(handler);
+        for _ in 0..1000 {
+            if task.is_finished() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+        assert!(task.is_finished());
+    }
+
+    fn encode(text: &str) -> String {
+        let mut res = String::new();
+        for b in text.bytes() {
+            if b.is_ascii_alphanumeric() {
+                res.push(b as char);
+            } else {
+                res.push_str(&format!("%{:02X}", b));
+            }
+        }
+        return res;
+    }
+
+    async fn call(state: &AppState, method: Method, uri: &str) -> (StatusCode, String) {
+        let req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap();
+        let res = router(state.clone()).oneshot(req).await.unwrap();
+        let status = res.status();
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        return (status, String::from_utf8(body.to_vec()).unwrap());
+    }
+
+    // Row counts of book, all_tags and book_tags
+    fn counts(state: &AppState) -> (i64, i64, i64) {
+        let conn = state.db.get().unwrap();
+        let count = |table: &str| -> i64 {
+            conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+        };
+        return (count("book"), count("all_tags"), count("book_tags"));
+    }
+
+    #[tokio::test]
+    async fn get_tag_ignores_injection() {
+        let (_dir, state) = test_state().await;
+        for p in PAYLOADS {
+            let (code, body) = call(
+                &state,
+                Method::GET,
+                &format!("/get_tag?f=0&r=10&sort={}", encode(p)),
+            )
+            .await;
+            assert_eq!(code, StatusCode::OK);
+            assert!(body.contains("programming") && body.contains("clang"));
+            let (code, _) = call(
+                &state,
+                Method::GET,
+                &format!("/get_tag?f={}&r=10", encode(p)),
+            )
+            .await;
+            assert_eq!(code, StatusCode::BAD_REQUEST);
+        }
+        assert_eq!(counts(&state), (1, 2, 2));
+    }
+
+    #[tokio::test]
+    async fn search_ignores_injection() {
+        let (_dir, state) = test_state().await;
+        for p in PAYLOADS {
+            let uri = format!(
+                "/search?q={}&sort={}&order={}",
+                encode(p),
+                encode(p),
+                encode(p)
+            );
+            let (code, _) = call(&state, Method::GET, &uri).await;
+            assert_eq!(code, StatusCode::OK);
+        }
+        assert_eq!(counts(&state), (1, 2, 2));
+    }
+
+    #[tokio::test]
+    async fn get_book_info_ignores_injection() {
+        let (_dir, state) = test_state().await;
+        for p in PAYLOADS {
+            let (code, _) = call(
+                &state,
+                Method::GET,
+                &format!("/get_book_info?id={}", encode(p)),
+            )
+            .await;
+            assert_eq!(code, StatusCode::BAD_REQUEST);
+            let (code, body) = call(
+                &state,
+                Method::GET,
+                &format!("/get_book_info?id=1&sort={}", encode(p)),
+            )
+            .await;
+            assert_eq!(code, StatusCode::OK);
+            assert!(body.contains("Herbert Schildt"));
+        }
+        assert_eq!(counts(&state), (1, 2, 2));
+    }
+
+    #[tokio::test]
+    async fn get_book_from_tag_ignores_injection() {
+        let (_dir, state) = test_state().await;
+        for p in PAYLOADS {
+            let uri = format!("/get_book_from_tag?f=0&r=10&id={}", encode(p));
+            let (code, _) = call(&state, Method::GET, &uri).await;
+            assert_eq!(code, StatusCode::BAD_REQUEST);
+            let uri = format!(
+                "/get_book_from_tag?f=0&r=10&id=1&sort={}&order={}",
+                encode(p),
+                encode(p)
+            );
+            let (code, body) = call(&state, Method::GET, &uri).await;
+            assert_eq!(code, StatusCode::OK);
+            assert!(body.contains("Herbert Schildt"));
+        }
+        assert_eq!(counts(&state), (1, 2, 2));
+    }
+
+    #[tokio::test]
+    async fn add_book_stores_payload_literally() {
+        let (_dir, state) = test_state().await;
+        for (i, p) in PAYLOADS.iter().enumerate() {
+            let uri = format!(
+                "/add_book?title={}&author={}&desc={}&year=2000&imgp=&tagid=1",
+                encode(p),
+                encode(p),
+                encode(p)
+            );
+            let (code, body) = call(&state, Method::POST, &uri).await;
+            assert_eq!(code, StatusCode::OK);
+            let id = i + 2;
+            assert!(body.contains(&format!("\"id\":{}", id)));
+            let (_, body) = call(&state, Method::GET, &format!("/get_book_info?id={}", id)).await;
+            let book: crate::book::Book = serde_json::from_str(&body).unwrap();
+            assert_eq!(book.title, *p);
+            assert_eq!(book.author, *p);
+            assert_eq!(book.desc, *p);
+
+            let uri = format!(
+                "/add_book?title=x&author=x&desc=x&year=x&imgp=&tagid={}",
+                encode(p)
+            );
+            let (code, _) = call(&state, Method::POST, &uri).await;
+            assert_eq!(code, StatusCode::BAD_REQUEST);
+        }
+        assert_eq!(
+            counts(&state),
+            (1 + PAYLOADS.len() as i64, 2, 2 + PAYLOADS.len() as i64)
+        );
+    }
+
+    #[tokio::test]
+    async fn add_tag_stores_payload_literally() {
+        let (_dir, state) = test_state().await;
+        for p in PAYLOADS {
+            let uri = format!("/add_tag?name={}&imgp={}", encode(p), encode(p));
+            let (code, _) = call(&state, Method::POST, &uri).await;
+            assert_eq!(code, StatusCode::OK);
+        }
+        let (_, body) = call(&state, Method::GET, "/get_tag?f=0&r=100").await;
+        let tags: Page<Tag> = serde_json::from_str(&body).unwrap();
+        for p in PAYLOADS {
+            assert!(tags.items.iter().any(|t| t.name == *p));
+        }
+        assert_eq!(counts(&state), (1, 2 + PAYLOADS.len() as i64, 2));
+    }
+
+    #[tokio::test]
+    async fn delete_rejects_injection() {
+        let (_dir, state) = test_state().await;
+        for p in PAYLOADS {
+            let (code, _) =
+                call(&state, Method::POST, &format!("/del_book?id={}", encode(p))).await;
+            assert_eq!(code, StatusCode::BAD_REQUEST);
+            let (code, _) = call(&state, Method::POST, &format!("/del_tag?id={}", encode(p))).await;
+            assert_eq!(code, StatusCode::BAD_REQUEST);
+        }
+        assert_eq!(counts(&state), (1, 2, 2));
+    }
+
+    // Database as the server created it before migrations existed: no
+    // user_version, plain integer keys, text link ids and a link to a
+    // book that was deleted
+    fn baseline_db() -> (TempDir, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("old.sqlite");
+        let path = path.to_str().unwrap().to_string();
+        rusqlite::Connection::open(&path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE book (
+                    book_id INTEGER PRIMARY KEY, title TEXT, author TEXT,
+                    desc TEXT, year TEXT, cover TEXT
+                );
+                CREATE TABLE book_tags (
+                    btag_id TEXT PRIMARY KEY, book_id INTEGER, tags_id INTEGER,
+                    FOREIGN KEY (book_id) REFERENCES book(book_id),
+                    FOREIGN KEY (tags_id) REFERENCES all_tags(tags_id)
+                );
+                CREATE TABLE all_tags (tags_id INTEGER PRIMARY KEY, name TEXT, img TEXT);
+                INSERT INTO all_tags VALUES (1, 'programming', ''), (2, 'clang', '');
+                INSERT INTO book VALUES (1, 'C', 'Schildt', '', '2000', ''), (2, 'K&R', 'K', '', '', '');
+                INSERT INTO book_tags VALUES ('1', 1, 1), ('2', 1, 2), ('3', 2, 2), ('4', 7, 1);",
+            )
+            .unwrap();
+        return (dir, path);
+    }
+
+    // `CREATE TABLE` statements of the database, by table name
+    fn schema(path: &str) -> Vec<(String, String)> {
+        let conn = rusqlite::Connection::open(path).unwrap();
+        let mut stmt = conn
+            .prepare("SELECT name, sql FROM sqlite_master WHERE type = 'table' ORDER BY name")
+            .unwrap();
+        return stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn migrations_upgrade_baseline_schema() {
+        let (_dir, path) = baseline_db();
+        let applied = run_migrations(&path, false).unwrap();
+        assert_eq!(
+            applied.iter().map(|m| m.version).collect::<Vec<_>>(),
+            [1, 2]
+        );
+        assert!(run_migrations(&path, false).unwrap().is_empty());
+
+        let tables = schema(&path);
+        let sql = |name: &str| tables.iter().find(|t| t.0 == name).unwrap().1.clone();
+        assert!(sql("book").contains("AUTOINCREMENT"));
+        assert!(sql("all_tags").contains("AUTOINCREMENT"));
+        assert!(sql("book_tags").contains("ON DELETE CASCADE"));
+
+        let pool = create_pool(&path, 2).unwrap();
+        let conn = pool.get().unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), 2);
+        let links = || -> i64 {
+            conn.query_row("SELECT COUNT(*) FROM book_tags", [], |row| row.get(0))
+                .unwrap()
+        };
+        // The link to the missing book was dropped, the others kept
+        assert_eq!(links(), 3);
+        conn.execute("DELETE FROM book WHERE book_id = 2", [])
+            .unwrap();
+        assert_eq!(links(), 2);
+        conn.execute("DELETE FROM all_tags WHERE tags_id = 2", [])
+            .unwrap();
+        assert_eq!(links(), 1);
+        conn.execute("INSERT INTO book (title) VALUES ('new')", [])
+            .unwrap();
+        assert_eq!(conn.last_insert_rowid(), 3);
+    }
+
+    #[tokio::test]
+    async fn dry_run_changes_nothing() {
+        let (_dir, path) = baseline_db();
+        let before = schema(&path);
+        let pending = run_migrations(&path, true).unwrap();
+        assert_eq!(pending.len(), 2);
+        assert_eq!(schema(&path), before);
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), 0);
+        assert_eq!(run_migrations(&path, true).unwrap().len(), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn pooled_reads_run_concurrently() {
+        let (_dir, state) = test_state().await;
+        // Readers are not blocked by a write in progress on another connection
+        let writer = state.db.get().unwrap();
+        writer
+            .execute_batch("BEGIN; UPDATE book SET title = 'changed' WHERE book_id = 1;")
+            .unwrap();
+        let book = sql_get_book_info(state.db.clone(), 1, SortOrder::Asc)
+            .await
+            .unwrap();
+        assert_eq!(book.title, "C: The Complete Reference");
+
+        // Many more readers than connections, they take turns on the pool
+        let mut reads = tokio::task::JoinSet::new();
+        for _ in 0..16 {
+            let db = state.db.clone();
+            reads.spawn(async move {
+                let book = sql_get_book_info(db.clone(), 1, SortOrder::Asc).await?;
+                let tags = sql_read_tags(db, 0, 10, SortOrder::Asc).await?;
+                return Ok::<_, AppError>((book.title, tags.total));
+            });
+        }
+        writer.execute_batch("COMMIT;").unwrap();
+        drop(writer);
+        while let Some(res) = reads.join_next().await {
+            let (title, tags) = res.unwrap().unwrap();
+            assert!(title == "C: The Complete Reference" || title == "changed");
+            assert_eq!(tags, 2);
+        }
+        let book = sql_get_book_info(state.db.clone(), 1, SortOrder::Asc)
+            .await
+            .unwrap();
+        assert_eq!(book.title, "changed");
+    }
+
+    #[tokio::test]
+    async fn tags_load_across_batches() {
+        let (_dir, state) = test_state().await;
+        {
+            let mut conn = state.db.get().unwrap();
+            let tx = conn.transaction().unwrap();
+            // Books 2..=1201 get tag 1 when their id is even, tag 2 every third id
+            for id in 2..=1201 {
+                tx.execute(
+                    "INSERT INTO book (title, author, desc, year, cover) VALUES (?, '', '', '', '')",
+                    [format!("book {}", id)],
+                )
+                .unwrap();
+                for (tag, every) in [(1, 2), (2, 3)] {
+                    if id % every == 0 {
+                        tx.execute(
+                            "INSERT INTO book_tags (book_id, tags_id) VALUES (?, ?)",
+                            [id, tag],
+                        )
+                        .unwrap();
+                    }
+                }
+            }
+            tx.commit().unwrap();
+        }
+
+        let books = sql_read_book(
+            state.db.clone(),
+            BookSort {
+                column: BookColumn::Id,
+                order: SortOrder::Asc,
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(books.len(), 1201);
+        for book in &books[1..] {
+            let tags: Vec<i32> = book.tags.iter().map(|t| t.id).collect();
+            let mut wanted: Vec<i32> = Vec::new();
+            if book.id % 3 == 0 {
+                wanted.push(2);
+            }
+            if book.id % 2 == 0 {
+                wanted.push(1);
+            }
+            // Sorted by name: clang before programming
+            assert_eq!(tags, wanted, "book {}", book.id);
+        }
+
+        let ids: Vec<i32> = (1..=1201).rev().collect();
+        let books = sql_get_books(state.db.clone(), ids).await.unwrap();
+        assert_eq!(books.len(), 1201);
+        let linked: usize = books.iter().map(|b| b.tags.len()).sum();
+        assert_eq!(linked, 2 + 600 + 400);
+    }
+
+    fn new_book(title: &str, tags: Vec<TagRef>) -> NewBook {
+        return NewBook {
+            title: title.to_string(),
+            author: "x".to_string(),
+            desc: String::new(),
+            year: String::new(),
+            cover: String::new(),
+            tags,
+        };
+    }
+
+    #[tokio::test]
+    async fn inserts_get_fresh_ids() {
+        let (_dir, state) = test_state().await;
+        let id = sql_add_new_book(state.db.clone(), new_book("K&R", vec![TagRef::Id(1)]))
+            .await
+            .unwrap();
+        assert_eq!(id, 2);
+        let book = sql_get_book_info(state.db.clone(), id as i32, SortOrder::Asc)
+            .await
+            .unwrap();
+        assert_eq!((book.title.as_str(), book.tags.len()), ("K&R", 1));
+
+        // Deleted ids are never handed out again, even the last one
+        sql_del_book_from_id(state.db.clone(), 2).await.unwrap();
+        let id = sql_add_new_book(state.db.clone(), new_book("SICP", Vec::new()))
+            .await
+            .unwrap();
+        assert_eq!(id, 3);
+        let book = sql_get_book_info(state.db.clone(), 3, SortOrder::Asc)
+            .await
+            .unwrap();
+        assert_eq!(book.title, "SICP");
+
+        let tag = NewTag {
+            name: "python".to_string(),
+            img: String::new(),
+        };
+        let id = sql_add_new_tag(state.db.clone(), tag.clone())
+            .await
+            .unwrap();
+        assert_eq!(id, 3);
+        assert_eq!(
+            sql_get_tag(state.db.clone(), 3).await.unwrap().name,
+            "python"
+        );
+        sql_del_tag_from_id(state.db.clone(), 3).await.unwrap();
+        assert_eq!(sql_add_new_tag(state.db.clone(), tag).await.unwrap(), 4);
+        assert!(sql_get_tag(state.db.clone(), 3).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn failed_tag_link_rolls_back_book() {
+        let (_dir, state) = test_state().await;
+        state
+            .db
+            .get()
+            .unwrap()
+            .execute_batch(
+                "CREATE TRIGGER no_clang BEFORE INSERT ON book_tags WHEN NEW.tags_id = 2
+                BEGIN SELECT RAISE(ABORT, 'no clang'); END;",
+            )
+            .unwrap();
+        let res = sql_add_new_book(
+            state.db.clone(),
+            new_book("K&R", vec![TagRef::Id(1), TagRef::Id(2)]),
+        )
+        .await;
+        assert!(res.is_err());
+        assert_eq!(counts(&state), (1, 2, 2));
+
+        // Unknown tags are refused before anything is written
+        let res = sql_add_new_book(state.db.clone(), new_book("K&R", vec![TagRef::Id(9)])).await;
+        assert_eq!(res.unwrap_err().kind, ErrorKind::Validation);
+        assert_eq!(counts(&state), (1, 2, 2));
+    }
+
+    #[tokio::test]
+    async fn errors_carry_status_and_code() {
+        let (_dir, state) = test_state().await;
+        let (code, body) = call(&state, Method::GET, "/get_book_info?id=42").await;
+        assert_eq!(code, StatusCode::NOT_FOUND);
+        let err: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(err["code"], "not_found");
+
+        let (code, body) = call(&state, Method::POST, "/add_tag?name=Programming&imgp=").await;
+        assert_eq!(code, StatusCode::CONFLICT);
+        let err: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(err["code"], "conflict");
+        assert_eq!(err["details"]["id"], 1);
+
+        let (code, body) = call(&state, Method::GET, "/get_book_from_tag?f=0&r=10&id=9").await;
+        assert_eq!(code, StatusCode::NOT_FOUND);
+        assert!(body.contains("tag 9 does not exist"));
+
+        let (code, body) = call(&state, Method::POST, "/del_book?id=x").await;
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+        let err: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(err["code"], "validation");
+    }
+
+    #[tokio::test]
+    async fn structured_search_uses_sql_filters() {
+        let (_dir, state) = test_state().await;
+        let found = |body: &str| -> usize {
+            let res: serde_json::Value = serde_json::from_str(body).unwrap();
+            return res["items"].as_array().unwrap().len();
+        };
+        let queries = [
+            ("author:schildt tag:clang year:1990..2005", 1),
+            ("tag:CLANG", 1),
+            ("schildt year:2001..", 0),
+            ("schildt -tag:programming", 0),
+            ("\"complete reference\" OR nothing", 1),
+        ];
+        for (q, n) in queries {
+            let (code, body) = call(&state, Method::GET, &format!("/search?q={}", encode(q))).await;
+            assert_eq!(code, StatusCode::OK, "{}", q);
+            assert_eq!(found(&body), n, "{}", q);
+        }
+
+        let (code, body) = call(&state, Method::GET, "/search?q=schildt&tags=2&decade=2000").await;
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(found(&body), 1);
+        let res: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(res["facets"]["tags"][0]["count"], 1);
+        let (code, body) = call(&state, Method::GET, "/search?q=schildt&decade=1990").await;
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(found(&body), 0);
+        let (code, _) = call(&state, Method::GET, "/search?q=schildt&tags=2,x").await;
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+
+        let (code, body) = call(
+            &state,
+            Method::GET,
+            &format!("/search?q={}", encode("schildt (c99")),
+        )
+        .await;
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+        let err: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(err["code"], "validation");
+        assert_eq!(err["details"]["position"], 8);
+    }
+
+    #[tokio::test]
+    async fn autocomplete_follows_mutations() {
+        let (_dir, state) = test_state().await;
+        let complete = |body: String| -> Completions { serde_json::from_str(&body).unwrap() };
+
+        let (code, body) = call(&state, Method::GET, "/autocomplete?q=c").await;
+        assert_eq!(code, StatusCode::OK);
+        let res = complete(body);
+        assert_eq!(res.titles[0].text, "C: The Complete Reference");
+        assert_eq!(res.tags[0].text, "clang");
+
+        let (code, _) = call(&state, Method::POST, "/add_tag?name=c99&imgp=").await;
+        assert_eq!(code, StatusCode::OK);
+        let (_, body) = call(&state, Method::GET, "/api/v1/autocomplete?q=c9").await;
+        assert_eq!(complete(body).tags[0].books, 0);
+
+        let (code, _) = call(&state, Method::POST, "/del_book?id=1").await;
+        assert_eq!(code, StatusCode::OK);
+        let (_, body) = call(&state, Method::GET, "/autocomplete?q=schil").await;
+        assert!(complete(body).authors.is_empty());
+
+        let (code, _) = call(&state, Method::POST, "/del_tag?id=2").await;
+        assert_eq!(code, StatusCode::OK);
+        let (_, body) = call(&state, Method::GET, "/autocomplete?q=cl").await;
+        assert!(complete(body).tags.is_empty());
+
+        let (code, _) = call(&state, Method::GET, "/autocomplete?q=c&limit=99").await;
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn tag_graph_follows_mutations() {
+        let (_dir, state) = test_state().await;
+        let (code, body) = call(&state, Method::GET, "/get_tag_neighbors?id=1").await;
+        assert_eq!(code, StatusCode::OK);
+        let res: TagNeighbors = serde_json::from_str(&body).unwrap();
+        assert_eq!(res.tag.name, "programming");
+        assert_eq!(res.neighbors.len(), 1);
+        assert_eq!((res.neighbors[0].id, res.neighbors[0].weight), (2, 1));
+
+        let uri = "/add_book?title=K%26R&author=Kernighan&desc=&year=1988&imgp=&tagid=1 2";
+        let (code, _) = call(&state, Method::POST, &uri.replace(' ', "%20")).await;
+        assert_eq!(code, StatusCode::OK);
+        let (code, _) = call(&state, Method::POST, "/add_tag?name=python&imgp=").await;
+        assert_eq!(code, StatusCode::OK);
+        let (_, body) = call(&state, Method::GET, "/api/v1/graph/tags").await;
+        let graph: GraphJson = serde_json::from_str(&body).unwrap();
+        assert_eq!(graph.nodes.len(), 3);
+        assert_eq!(graph.nodes[0].books, 2);
+        assert_eq!(graph.edges.len(), 1);
+        assert_eq!(graph.edges[0].weight, 2);
+
+        let (code, _) = call(&state, Method::POST, "/del_tag?id=2").await;
+        assert_eq!(code, StatusCode::OK);
+        let (_, body) = call(&state, Method::GET, "/api/v1/tags/1/neighbors").await;
+        let res: TagNeighbors = serde_json::from_str(&body).unwrap();
+        assert!(res.neighbors.is_empty());
+        let (code, _) = call(&state, Method::GET, "/api/v1/tags/2/neighbors").await;
+        assert_eq!(code, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn related_books_explain_shared_tags() {
+        let (_dir, state) = test_state().await;
+        for (title, tags) in [("K%26R", "1%202"), ("SICP", "1"), ("Poems", "")] {
+            let uri = format!(
+                "/add_book?title={}&author=x&desc=&year=1988&imgp=&tagid={}",
+                title, tags
+            );
+            let (code, _) = call(&state, Method::POST, &uri).await;
+            assert_eq!(code, StatusCode::OK);
+        }
+
+        let (code, body) = call(&state, Method::GET, "/get_related_books?id=1&limit=5").await;
+        assert_eq!(code, StatusCode::OK);
+        let res: RelatedBooks = serde_json::from_str(&body).unwrap();
+        assert_eq!(res.method, Similarity::AdamicAdar);
+        let titles: Vec<&str> = res.related.iter().map(|r| r.book.title.as_str()).collect();
+        assert_eq!(titles, ["K&R", "SICP"]);
+        let shared: Vec<&str> = res.related[0]
+            .shared_tags
+            .iter()
+            .map(|t| t.name.as_str())
+            .collect();
+        assert_eq!(shared, ["clang", "programming"]);
+
+        let uri = "/api/v1/books/1/related?method=jaccard&limit=1";
+        let (_, body) = call(&state, Method::GET, uri).await;
+        let res: RelatedBooks = serde_json::from_str(&body).unwrap();
+        assert_eq!(res.related.len(), 1);
+        assert_eq!(res.related[0].score, 1.0);
+
+        let (_, body) = call(&state, Method::GET, "/api/v1/books/4/related").await;
+        let res: RelatedBooks = serde_json::from_str(&body).unwrap();
+        assert!(res.related.is_empty());
+        let (code, _) = call(&state, Method::GET, "/api/v1/books/9/related").await;
+        assert_eq!(code, StatusCode::NOT_FOUND);
+        let (code, _) = call(&state, Method::GET, "/get_related_books?id=1&method=x").await;
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn paths_walk_books_and_tags() {
+        let (_dir, state) = test_state().await;
+        for (name, tags) in [("python", ""), ("data", "")] {
+            let uri = format!("/add_tag?name={}&imgp={}", name, tags);
+            call(&state, Method::POST, &uri).await;
+        }
+        let uri = "/add_book?title=Fluent&author=x&desc=&year=2015&imgp=&tagid=1%203";
+        let (code, _) = call(&state, Method::POST, uri).await;
+        assert_eq!(code, StatusCode::OK);
+
+        let (code, body) = call(&state, Method::GET, "/get_path?from=tag:2&to=book:2").await;
+        assert_eq!(code, StatusCode::OK);
+        let res: GraphPath = serde_json::from_str(&body).unwrap();
+        assert_eq!(res.hops, 3);
+        let kinds: Vec<String> = res
+            .path
+            .iter()
+            .map(|s| match s {
+                PathStep::Book(b) => format!("book:{}", b.id),
+                PathStep::Tag(t) => format!("tag:{}", t.id),
+            })
+            .collect();
+        assert_eq!(kinds, ["tag:2", "book:1", "tag:1", "book:2"]);
+        assert!(body.contains(r#""kind":"tag""#));
+
+        let uri = "/api/v1/graph/path?from=book:1&to=tag:3&method=weighted";
+        let (code, body) = call(&state, Method::GET, uri).await;
+        assert_eq!(code, StatusCode::OK);
+        let res: GraphPath = serde_json::from_str(&body).unwrap();
+        assert_eq!((res.hops, res.cost), (3, 1.0));
+
+        let (code, body) = call(&state, Method::GET, "/get_path?from=book:1&to=tag:4").await;
+        assert_eq!(code, StatusCode::NOT_FOUND);
+        assert!(body.contains("are not connected"));
+        let (code, _) = call(&state, Method::GET, "/get_path?from=book:1&to=tag:9").await;
+        assert_eq!(code, StatusCode::NOT_FOUND);
+        let (code, _) = call(&state, Method::GET, "/get_path?from=1&to=tag:1").await;
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn communities_group_tags() {
+        let (_dir, state) = test_state().await;
+        for name in ["poetry", "haiku"] {
+            call(
+                &state,
+                Method::POST,
+                &format!("/add_tag?name={}&imgp=", name),
+            )
+            .await;
+        }
+        let uri = "/add_book?title=Basho&author=x&desc=&year=1694&imgp=&tagid=3%204";
+        let (code, _) = call(&state, Method::POST, uri).await;
+        assert_eq!(code, StatusCode::OK);
+
+        let (code, body) = call(&state, Method::GET, "/get_tag_communities").await;
+        assert_eq!(code, StatusCode::OK);
+        let res: Communities = serde_json::from_str(&body).unwrap();
+        assert_eq!(res.components, 2);
+        let tags: Vec<Vec<i32>> = res
+            .communities
+            .iter()
+            .map(|c| c.tags.iter().map(|t| t.id).collect())
+            .collect();
+        assert_eq!(tags, [vec![1, 2], vec![3, 4]]);
+        assert_eq!(res.communities[1].books[0].title, "Basho");
+        assert_eq!(res.modularity, 0.5);
+
+        let (code, _) = call(&state, Method::GET, "/api/v1/graph/communities").await;
+        assert_eq!(code, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn tags_rank_by_centrality() {
+        let (_dir, state) = test_state().await;
+        call(&state, Method::POST, "/add_tag?name=aaa&imgp=").await;
+        for title in ["K%26R", "SICP"] {
+            let uri = format!(
+                "/add_book?title={}&author=x&desc=&year=1988&imgp=&tagid=2",
+                title
+            );
+            let (code, _) = call(&state, Method::POST, &uri).await;
+            assert_eq!(code, StatusCode::OK);
+        }
+        let names = |body: &str| -> Vec<String> {
+            let page: Page<Tag> = serde_json::from_str(body).unwrap();
+            return page.items.into_iter().map(|t| t.name).collect();
+        };
+
+        let (_, body) = call(&state, Method::GET, "/get_tag?f=0&r=10").await;
+        assert_eq!(names(&body), ["aaa", "clang", "programming"]);
+        // Book counts are current right away, the rest once the
+        // centrality caught up
+        let (_, body) = call(&state, Method::GET, "/get_tag?f=0&r=10&order=popular").await;
+        assert_eq!(names(&body), ["clang", "programming", "aaa"]);
+        settle(&state).await;
+        let (_, body) = call(&state, Method::GET, "/get_tag?f=0&r=2&order=central").await;
+        let page: Page<Tag> = serde_json::from_str(&body).unwrap();
+        assert_eq!(page.items[0].name, "clang");
+        assert_eq!((page.total, page.next_cursor), (3, Some("2".to_string())));
+        let uri = "/api/v1/tags?order=popular&sort=asc&limit=1";
+        let (_, body) = call(&state, Method::GET, uri).await;
+        assert_eq!(names(&body), ["aaa"]);
+
+        // A tag the centrality has not seen yet is still listed, last
+        call(&state, Method::POST, "/add_tag?name=zzz&imgp=").await;
+        let (_, body) = call(&state, Method::GET, "/get_tag?f=0&r=10&order=central").await;
+        let page: Page<Tag> = serde_json::from_str(&body).unwrap();
+        assert_eq!(page.total, 4);
+        assert_eq!(page.items.last().unwrap().name, "zzz");
+        settle(&state).await;
+
+        let (code, body) = call(&state, Method::GET, "/get_central?limit=1").await;
+        assert_eq!(code, StatusCode::OK);
+        let res: CentralNodes = serde_json::from_str(&body).unwrap();
+        assert_eq!(res.measure, Measure::Pagerank);
+        assert_eq!(res.tags[0].tag.name, "clang");
+        assert_eq!(res.tags[0].scores.degree, 3);
+        // The book linking both tags is the only one between others
+        assert_eq!(res.books[0].book.id, 1);
+        let uri = "/api/v1/graph/central?measure=betweenness";
+        let (_, body) = call(&state, Method::GET, uri).await;
+        let res: CentralNodes = serde_json::from_str(&body).unwrap();
+        assert_eq!(res.books[0].book.id, 1);
+        assert!(res.books[0].scores.betweenness > 0.0);
+        assert_eq!(res.books[1].scores.betweenness, 0.0);
+
+        // Scores follow the catalog
+        call(&state, Method::POST, "/del_tag?id=2").await;
+        settle(&state).await;
+        let (_, body) = call(&state, Method::GET, "/get_central?measure=degree").await;
+        let res: CentralNodes = serde_json::from_str(&body).unwrap();
+        assert_eq!(res.tags[0].tag.name, "programming");
+        assert_eq!(res.tags[0].scores.degree, 1);
+    }
+
+    #[tokio::test]
+    async fn lists_page_with_totals_and_cursors() {
+        let (_dir, state) = test_state().await;
+        for i in 0..5 {
+            let uri = format!("/add_tag?name=extra{}&imgp=", i);
+            let (code, _) = call(&state, Method::POST, &uri).await;
+            assert_eq!(code, StatusCode::OK);
+        }
+
+        // Offset listings: the cursor is the next offset
+        let mut seen: Vec<String> = Vec::new();
+        let mut uri = "/api/v1/tags?limit=3".to_string();
+        loop {
+            let (code, body) = call(&state, Method::GET, &uri).await;
+            assert_eq!(code, StatusCode::OK);
+            let page: Page<Tag> = serde_json::from_str(&body).unwrap();
+            assert_eq!(page.total, 7);
+            seen.extend(page.items.into_iter().map(|t| t.name));
+            match page.next_cursor {
+                Some(c) => uri = format!("/api/v1/tags?limit=3&cursor={}", c),
+                None => break,
+            }
+        }
+        assert_eq!(seen.len(), 7);
+        let (_, body) = call(&state, Method::GET, "/get_tag?f=6&r=3").await;
+        let page: Page<Tag> = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            (page.items.len(), page.total, page.next_cursor),
+            (1, 7, None)
+        );
+
+        // Search pages pick up after the last book seen, even when a book
+        // that sorts before it shows up in between
+        for title in ["Alpha", "Bravo", "Delta"] {
+            let uri = format!(
+                "/add_book?title={}&author=Team&desc=&year=2001&imgp=&tagid=1",
+                title
+            );
+            let (code, _) = call(&state, Method::POST, &uri).await;
+            assert_eq!(code, StatusCode::OK);
+        }
+        let (_, body) = call(&state, Method::GET, "/search?q=team&order=title&limit=2").await;
+        let first: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(first["total"], 3);
+        assert_eq!(first["items"][1]["book"]["title"], "Bravo");
+        let cursor = first["next_cursor"].as_str().unwrap().to_string();
+
+        let uri = "/add_book?title=Aardvark&author=Team&desc=&year=2001&imgp=&tagid=1";
+        call(&state, Method::POST, uri).await;
+        let (_, body) = call(
+            &state,
+            Method::GET,
+            &format!("/search?q=team&order=title&limit=2&cursor={}", cursor),
+        )
+        .await;
+        let second: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(second["total"], 4);
+        assert_eq!(second["items"].as_array().unwrap().len(), 1);
+        assert_eq!(second["items"][0]["book"]["title"], "Delta");
+        assert_eq!(second["next_cursor"], serde_json::Value::Null);
+
+        let uri = format!("/search?q=team&order=year&cursor={}", cursor);
+        let (code, _) = call(&state, Method::GET, &uri).await;
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+        // A cursor only goes on with the search it came from
+        for other in ["q=alpha", "q=team&tags=1", "q=team&lang=id"] {
+            let uri = format!("/search?{}&order=title&cursor={}", other, cursor);
+            let (code, _) = call(&state, Method::GET, &uri).await;
+            assert_eq!(code, StatusCode::BAD_REQUEST, "{}", other);
+        }
+        let uri = format!("/search?q=Team&order=title&cursor={}", cursor);
+        let (code, _) = call(&state, Method::GET, &uri).await;
+        assert_eq!(code, StatusCode::OK);
+        let (code, _) = call(&state, Method::GET, "/search?q=team&cursor=zz").await;
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn relevance_pages_keep_their_ranking() {
+        let (_dir, state) = test_state().await;
+        for (i, desc) in ["", "team", "team team", "team team team", "team work"]
+            .iter()
+            .enumerate()
+        {
+            let uri = format!(
+                "/add_book?title=Crew{}&author=Team&desc={}&year=2001&imgp=&tagid=1",
+                i,
+                encode(desc)
+            );
+            let (code, _) = call(&state, Method::POST, &uri).await;
+            assert_eq!(code, StatusCode::OK);
+        }
+        let ids = |body: &str| -> Vec<i64> {
+            let page: serde_json::Value = serde_json::from_str(body).unwrap();
+            return page["items"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|r| r["book"]["id"].as_i64().unwrap())
+                .collect();
+        };
+        let (_, body) = call(&state, Method::GET, "/search?q=team").await;
+        let everything = ids(&body);
+        assert_eq!(everything.len(), 5);
+
+        let (_, body) = call(&state, Method::GET, "/search?q=team&limit=2").await;
+        let mut seen = ids(&body);
+        let first: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let mut cursor = first["next_cursor"].as_str().unwrap().to_string();
+
+        // A book that outranks all of them and moves every score
+        let uri =
+            "/add_book?title=Team&author=Team&desc=team+team+team+team&year=2001&imgp=&tagid=1";
+        let (code, _) = call(&state, Method::POST, uri).await;
+        assert_eq!(code, StatusCode::OK);
+        loop {
+            let uri = format!("/search?q=team&limit=2&cursor={}", cursor);
+            let (code, body) = call(&state, Method::GET, &uri).await;
+            assert_eq!(code, StatusCode::OK);
+            seen.extend(ids(&body));
+            let page: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(page["total"], 5);
+            match page["next_cursor"].as_str() {
+                Some(c) => cursor = c.to_string(),
+                None => break,
+            }
+        }
+        assert_eq!(seen, everything);
+
+        // Books removed since the first page are left out, the page is
+        // filled past them and the total counts the books still there
+        let (_, body) = call(&state, Method::GET, "/search?q=team&limit=2").await;
+        let first: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let cursor = first["next_cursor"].as_str().unwrap().to_string();
+        let (_, body) = call(&state, Method::GET, "/search?q=team&limit=6").await;
+        let ranked = ids(&body);
+        let gone = ranked[2];
+        let (code, _) = call(&state, Method::POST, &format!("/del_book?id={}", gone)).await;
+        assert_eq!(code, StatusCode::OK);
+        let uri = format!("/search?q=team&limit=2&cursor={}", cursor);
+        let (_, body) = call(&state, Method::GET, &uri).await;
+        assert_eq!(ids(&body), ranked[3..5]);
+        let page: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(page["total"], 5);
+        let cursor = page["next_cursor"].as_str().unwrap().to_string();
+
+        // Nothing is left once the last book is gone as well
+        let last = ranked[5];
+        let (code, _) = call(&state, Method::POST, &format!("/del_book?id={}", last)).await;
+        assert_eq!(code, StatusCode::OK);
+        let uri = format!("/search?q=team&limit=2&cursor={}", cursor);
+        let (_, body) = call(&state, Method::GET, &uri).await;
+        let page: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(page["items"].as_array().unwrap().len(), 0);
+        assert_eq!(
+            (page["total"].as_i64(), page["next_cursor"].as_str()),
+            (Some(4), None)
+        );
+
+        // Nor with another query
+        let (_, body) = call(&state, Method::GET, "/search?q=team&limit=2").await;
+        let first: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let cursor = first["next_cursor"].as_str().unwrap().to_string();
+        let uri = format!("/search?q=crew0&limit=2&cursor={}", cursor);
+        let (code, _) = call(&state, Method::GET, &uri).await;
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+    }
+
+    async fn send(state: &AppState, req: Request<Body>) -> (StatusCode, HeaderMap, String) {
+        let res = router(state.clone()).oneshot(req).await.unwrap();
+        let status = res.status();
+        let headers = res.headers().clone();
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        return (status, headers, String::from_utf8(body.to_vec()).unwrap());
+    }
+
+    #[tokio::test]
+    async fn add_routes_accept_json_and_multipart() {
+        let (_dir, state) = test_state().await;
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/add_book")
+            .header("content-type", "application/json")
+            .body(Body::from(
+                r#"{"title": "Long", "author": "A", "desc": "very long text", "tags": [2, "Programming "]}"#,
+            ))
+            .unwrap();
+        let (code, headers, body) = send(&state, req).await;
+        assert_eq!(code, StatusCode::OK);
+        assert!(headers.get("deprecation").is_none());
+        assert!(body.contains("\"id\":2"));
+        let (_, body) = call(&state, Method::GET, "/get_book_info?id=2").await;
+        let book: Book = serde_json::from_str(&body).unwrap();
+        assert_eq!(book.desc, "very long text");
+        assert_eq!(book.tags.len(), 2);
+
+        let multipart = "--XX\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nForm\r\n\
+            --XX\r\nContent-Disposition: form-data; name=\"author\"\r\n\r\nB\r\n\
+            --XX\r\nContent-Disposition: form-data; name=\"tags\"\r\n\r\n1\r\n\
+            --XX\r\nContent-Disposition: form-data; name=\"tags\"\r\n\r\nclang\r\n\
+            --XX--\r\n";
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/add_book")
+            .header("content-type", "multipart/form-data; boundary=XX")
+            .body(Body::from(multipart))
+            .unwrap();
+        let (code, _, body) = send(&state, req).await;
+        assert_eq!(code, StatusCode::OK);
+        assert!(body.contains("\"id\":3"));
+        let (_, body) = call(&state, Method::GET, "/get_book_info?id=3").await;
+        let book: Book = serde_json::from_str(&body).unwrap();
+        assert_eq!(book.tags.len(), 2);
+
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/add_tag")
+            .header("content-type", "application/json")
+            .body(Body::from(r#"{"name": "python"}"#))
+            .unwrap();
+        let (code, _, _) = send(&state, req).await;
+        assert_eq!(code, StatusCode::OK);
+
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/add_tag?name=rust&imgp=")
+            .body(Body::empty())
+            .unwrap();
+        let (code, headers, _) = send(&state, req).await;
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(headers.get("deprecation").unwrap(), "true");
+    }
+
+    #[tokio::test]
+    async fn numeric_tag_names_stay_names_in_json() {
+        let (_dir, state) = test_state().await;
+        let json = |uri: &str, body: &'static str| {
+            Request::builder()
+                .method(Method::POST)
+                .uri(uri)
+                .header("content-type", "application/json")
+                .body(Body::from(body))
+                .unwrap()
+        };
+        let (code, _, body) = send(&state, json("/add_tag", r#"{"name": "1984"}"#)).await;
+        assert_eq!(code, StatusCode::OK, "{}", body);
+        assert!(body.contains("\"id\":3"));
+
+        let req = json(
+            "/add_book",
+            r#"{"title": "Nineteen", "author": "O", "tags": ["1984", 1]}"#,
+        );
+        let (code, _, body) = send(&state, req).await;
+        assert_eq!(code, StatusCode::OK, "{}", body);
+        let (_, body) = call(&state, Method::GET, "/get_book_info?id=2").await;
+        let book: Book = serde_json::from_str(&body).unwrap();
+        let mut tags: Vec<(i32, &str)> =
+            book.tags.iter().map(|t| (t.id, t.name.as_str())).collect();
+        tags.sort();
+        assert_eq!(tags, [(1, "programming"), (3, "1984")]);
+
+        // A name nobody has is refused, it is not read as tag 42
+        let req = json(
+            "/add_book",
+            r#"{"title": "T", "author": "O", "tags": "42"}"#,
+        );
+        let (code, _, _) = send(&state, req).await;
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn add_routes_accept_form_bodies() {
+        let (_dir, state) = test_state().await;
+        let form = |uri: &str, body: &'static str| {
+            Request::builder()
+                .method(Method::POST)
+                .uri(uri)
+                .header("content-type", "application/x-www-form-urlencoded")
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let req = form(
+            "/add_book",
+            "title=Form+Book&author=A&desc=a%26b&tags=1&tags=clang",
+        );
+        let (code, headers, body) = send(&state, req).await;
+        assert_eq!(code, StatusCode::OK, "{}", body);
+        assert!(headers.get("deprecation").is_none());
+        let (_, body) = call(&state, Method::GET, "/get_book_info?id=2").await;
+        let book: Book = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            (book.title.as_str(), book.desc.as_str()),
+            ("Form Book", "a&b")
+        );
+        assert_eq!(book.tags.len(), 2);
+
+        let (code, _, _) = send(&state, form("/api/v1/tags", "name=python")).await;
+        assert_eq!(code, StatusCode::OK);
+        let (code, _, _) = send(&state, form("/add_tag", "img=x")).await;
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+
+        // An empty form body still falls back to the query string
+        let (code, headers, _) = send(&state, form("/add_tag?name=rust&imgp=", "")).await;
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(headers.get("deprecation").unwrap(), "true");
+
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/add_tag?name=go&imgp=")
+            .header("content-type", "text/plain")
+            .body(Body::from("name=go"))
+            .unwrap();
+        let (code, _, body) = send(&state, req).await;
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+        assert!(body.contains("unsupported content type"));
+        assert_eq!(counts(&state), (2, 4, 4));
+    }
+
+    #[tokio::test]
+    async fn patch_book_updates_fields_and_tags() {
+        let (_dir, state) = test_state().await;
+        let patch_req = |uri: &str, body: &'static str| {
+            Request::builder()
+                .method(Method::PATCH)
+                .uri(uri)
+                .header("content-type", "application/json")
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let req = patch_req(
+            "/books/1",
+            r#"{"title": "C: The Complete Reference, 4th Ed", "tags": ["clang"]}"#,
+        );
+        let (code, _, body) = send(&state, req).await;
+        assert_eq!(code, StatusCode::OK);
+        let book: Book = serde_json::from_str(&body).unwrap();
+        assert_eq!(book.id, 1);
+        assert_eq!(book.title, "C: The Complete Reference, 4th Ed");
+        assert_eq!(book.author, "Herbert Schildt");
+        assert_eq!(book.tags.len(), 1);
+        assert_eq!(book.tags[0].name, "clang");
+        assert_eq!(counts(&state), (1, 2, 1));
+
+        let (code, _, _) = send(&state, patch_req("/books/1", r#"{"tags": [1, 2, 3]}"#)).await;
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+        assert_eq!(counts(&state), (1, 2, 1));
+
+        let (code, _, _) = send(&state, patch_req("/books/7", r#"{"year": "2001"}"#)).await;
+        assert_eq!(code, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_changes_leave_index_in_step() {
+        let (_dir, state) = test_state().await;
+        let mut changes = tokio::task::JoinSet::new();
+        for i in 0..24 {
+            let state = state.clone();
+            changes.spawn(async move {
+                let body = if i % 2 == 0 {
+                    r#"{"tags": [1]}"#
+                } else {
+                    r#"{"tags": [2]}"#
+                };
+                let req = Request::builder()
+                    .method(Method::PATCH)
+                    .uri("/books/1")
+                    .header("content-type", "application/json")
+                    .body(Body::from(body))
+                    .unwrap();
+                return send(&state, req).await.0;
+            });
+        }
+        while let Some(code) = changes.join_next().await {
+            assert_eq!(code.unwrap(), StatusCode::OK);
+        }
+        let stored = sql_get_book_info(state.db.clone(), 1, SortOrder::Asc)
+            .await
+            .unwrap();
+        let indexed = state.read_index().unwrap().book(1).unwrap().clone();
+        assert_eq!(indexed.tags.len(), 1);
+        assert_eq!(indexed.tags[0].id, stored.tags[0].id);
+        let neighbors = state.read_graph().unwrap().tags_of(1).to_vec();
+        assert_eq!(neighbors, [stored.tags[0].id]);
+    }
+
+    #[tokio::test]
+    async fn merge_tags_relinks_books() {
+        let (_dir, state) = test_state().await;
+        state
+            .db
+            .get()
+            .unwrap()
+            .execute_batch(
+                "INSERT INTO all_tags (name, img) VALUES ('Programming ', ''), ('pemrograman', '');
+                INSERT INTO book (title, author, desc, year, cover) VALUES ('B', 'B', '', '', '');
+                INSERT INTO book_tags (book_id, tags_id) VALUES (1, 3), (2, 4);",
+            )
+            .unwrap();
+        let json_req = |method: Method, uri: &str, body: &'static str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("content-type", "application/json")
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let req = json_req(Method::PATCH, "/tags/4", r#"{"name": "programming"}"#);
+        let (code, _, _) = send(&state, req).await;
+        assert_eq!(code, StatusCode::CONFLICT);
+
+        let req = json_req(
+            Method::POST,
+            "/tags/merge",
+            r#"{"sources": [3, 4], "target": 1}"#,
+        );
+        let (code, _, body) = send(&state, req).await;
+        assert_eq!(code, StatusCode::OK);
+        let res: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(res["books_affected"], 2);
+        // Book 1 had both 1 and 3, its duplicate link is dropped
+        assert_eq!(counts(&state), (2, 2, 3));
+
+        let req = json_req(Method::PATCH, "/tags/2", r#"{"name": "c language"}"#);
+        let (code, _, body) = send(&state, req).await;
+        assert_eq!(code, StatusCode::OK);
+        assert!(body.contains("c language"));
+    }
+
+    #[tokio::test]
+    async fn merge_takes_more_sources_than_one_statement_binds() {
+        let (_dir, state) = test_state().await;
+        {
+            let mut conn = state.db.get().unwrap();
+            let tx = conn.transaction().unwrap();
+            // Tags 3..=1202, every tenth one on book 1
+            for i in 3..=1202 {
+                tx.execute(
+                    "INSERT INTO all_tags (name, img) VALUES (?, '')",
+                    [format!("dup {}", i)],
+                )
+                .unwrap();
+                if i % 10 == 0 {
+                    tx.execute(
+                        "INSERT INTO book_tags (book_id, tags_id) VALUES (1, ?)",
+                        [i],
+                    )
+                    .unwrap();
+                }
+            }
+            tx.execute(
+                "INSERT INTO book (title, author, desc, year, cover) VALUES ('B', '', '', '', '')",
+                [],
+            )
+            .unwrap();
+            tx.execute(
+                "INSERT INTO book_tags (book_id, tags_id) VALUES (2, 1202)",
+                [],
+            )
+            .unwrap();
+            tx.commit().unwrap();
+        }
+        let merge = TagMerge {
+            sources: (3..=1202).collect(),
+            target: 2,
+        };
+        let res = sql_merge_tags(state.db.clone(), merge).await.unwrap();
+        assert_eq!(res.merged.len(), 1200);
+        assert_eq!(res.books_affected, 2);
+        assert_eq!(counts(&state), (2, 2, 3));
+    }
+
+    #[tokio::test]
+    async fn windows_are_checked_everywhere() {
+        let (_dir, state) = test_state().await;
+        for uri in [
+            "/get_tag?f=-1&r=10",
+            "/get_tag?f=0&r=-1",
+            "/get_tag?f=0&r=201",
+            "/get_book_from_tag?id=1&f=-5&r=10",
+            "/get_book_from_tag?id=1&f=0&r=1000",
+            "/api/v1/tags/1/neighbors?offset=1",
+            "/api/v1/tags/1/neighbors?cursor=1",
+            "/api/v1/tags/1/neighbors?sort=asc",
+            "/api/v1/tags/1/neighbors?limit=201",
+        ] {
+            let (code, body) = call(&state, Method::GET, uri).await;
+            assert_eq!(code, StatusCode::BAD_REQUEST, "{}", uri);
+            assert!(body.contains("validation"), "{}", uri);
+        }
+        for uri in [
+            "/get_tag?f=0&r=200",
+            "/get_book_from_tag?id=1&f=3&r=0",
+            "/api/v1/tags/1/neighbors?limit=1",
+        ] {
+            let (code, _) = call(&state, Method::GET, uri).await;
+            assert_eq!(code, StatusCode::OK, "{}", uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn v1_routes_match_legacy_routes() {
+        let (_dir, state) = test_state().await;
+        let pairs = [
+            ("/get_tag?f=0&r=10", "/api/v1/tags?offset=0&limit=10"),
+            (
+                "/get_tag?f=1&r=1&sort=desc",
+                "/api/v1/tags?offset=1&limit=1&sort=desc",
+            ),
+            ("/get_book_info?id=1", "/api/v1/books/1"),
+            ("/get_book_info?id=42", "/api/v1/books/42"),
+            (
+                "/get_book_from_tag?f=0&r=10&id=1",
+                "/api/v1/tags/1/books?offset=0&limit=10",
+            ),
+            (
+                "/get_book_from_tag?f=0&r=10&id=9",
+                "/api/v1/tags/9/books?offset=0&limit=10",
+            ),
+            ("/search?q=schildt", "/api/v1/search?q=schildt"),
+        ];
+        for (legacy, v1) in pairs {
+            let old = call(&state, Method::GET, legacy).await;
+            let new = call(&state, Method::GET, v1).await;
+            assert_eq!(old, new, "{} and {} differ", legacy, v1);
+        }
+
+        let (code, body) = call(&state, Method::GET, "/api/v1/books").await;
+        assert_eq!(code, StatusCode::OK);
+        let books: Page<Book> = serde_json::from_str(&body).unwrap();
+        assert_eq!(books.items.len(), 1);
+        assert_eq!(books.total, 1);
+        assert_eq!(books.next_cursor, None);
+
+        let (code, _) = call(&state, Method::DELETE, "/api/v1/tags/2").await;
+        assert_eq!(code, StatusCode::NO_CONTENT);
+        let (code, _) = call(&state, Method::GET, "/api/v1/tags/2").await;
+        assert_eq!(code, StatusCode::NOT_FOUND);
+        let (code, _) = call(&state, Method::DELETE, "/api/v1/books/1").await;
+        assert_eq!(code, StatusCode::NO_CONTENT);
+        assert_eq!(counts(&state), (0, 1, 0));
     }
 }
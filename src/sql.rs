@@ -1,563 +1,871 @@
-use crate::book::{self, Tag};
-use rusqlite::{params, Connection, Result};
-use std::sync::{Arc, Mutex};
-
-enum AllTable {
-    Book,
-    AllTags,
-    BookTags,
-}
-
-pub fn is_valid_sort(sort: &str) -> bool {
-    let new_str: &str = &sort.to_uppercase();
-    match &new_str.to_uppercase()[..] {
-        "ASC" => return true,
-        "DESC" => return true,
-        _ => return false,
+use crate::book::{self, Page, Tag};
+use crate::error::AppError;
+use crate::search::{Filter, FilterSets};
+use r2d2::{Pool, PooledConnection};
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Params, Result, Row};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+// Direction for every `ORDER BY`, only these two strings ever reach the SQL text
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl SortOrder {
+    // Unknown values fall back to ascending like before
+    pub fn from_param(sort: Option<&str>) -> SortOrder {
+        match sort.map(|s| s.trim().to_uppercase()).as_deref() {
+            Some("DESC") => SortOrder::Desc,
+            _ => SortOrder::Asc,
+        }
+    }
+
+    fn as_sql(self) -> &'static str {
+        match self {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        }
+    }
+}
+
+// Book column a listing can be ordered by
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum BookColumn {
+    #[default]
+    Title,
+    Author,
+    Year,
+    Id,
+}
+
+impl BookColumn {
+    pub fn from_param(order: Option<&str>) -> BookColumn {
+        match order.map(|s| s.trim().to_lowercase()).as_deref() {
+            Some("author") => BookColumn::Author,
+            Some("year") => BookColumn::Year,
+            Some("id") => BookColumn::Id,
+            _ => BookColumn::Title,
+        }
+    }
+
+    fn as_sql(self) -> &'static str {
+        match self {
+            BookColumn::Title => "b.title",
+            BookColumn::Author => "b.author",
+            BookColumn::Year => "b.year",
+            BookColumn::Id => "b.book_id",
+        }
     }
 }
 
-lazy_static::lazy_static! {
-    static ref SQL_PATH: Arc<Mutex<String>> = Arc::new(Mutex::new(String::new()));
+// Book ordering, the id keeps rows with equal keys in a stable order
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BookSort {
+    pub column: BookColumn,
+    pub order: SortOrder,
+}
+
+impl BookSort {
+    fn order_by(self) -> String {
+        return format!(
+            "ORDER BY {} {}, b.book_id {}",
+            self.column.as_sql(),
+            self.order.as_sql(),
+            self.order.as_sql()
+        );
+    }
+}
+
+pub type DbPool = Pool<SqliteConnectionManager>;
+type DbConn = PooledConnection<SqliteConnectionManager>;
+
+// Connections shared by every handler, created once at startup
+pub fn create_pool(path: &str, size: u32) -> Result<DbPool, String> {
+    let manager = SqliteConnectionManager::file(path).with_init(|conn| {
+        // WAL lets readers run while a write is in progress, foreign_keys
+        // is needed on every connection for the ON DELETE CASCADE links to fire
+        conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA foreign_keys = ON;")?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.set_prepared_statement_cache_capacity(64);
+        Ok(())
+    });
+    return Pool::builder()
+        .max_size(size)
+        .build(manager)
+        .map_err(|e| e.to_string());
+}
+
+fn get_conn(pool: &DbPool) -> Result<DbConn, AppError> {
+    return Ok(pool.get()?);
 }
 
-fn get_sql_path_val() -> String {
-    let locked_path = SQL_PATH.lock().unwrap();
-    locked_path.clone() // Return a cloned String
+// Run `job` on the blocking thread pool with a connection taken from `pool`
+async fn with_conn<T, F>(pool: DbPool, job: F) -> Result<T, AppError>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut DbConn) -> Result<T, AppError> + Send + 'static,
+{
+    return tokio::task::spawn_blocking(move || {
+        let mut conn = get_conn(&pool)?;
+        job(&mut conn)
+    })
+    .await?;
 }
 
-pub fn set_sql_path_val(path: &str) {
-    let mut locked_path = SQL_PATH.lock().unwrap();
-    *locked_path = path.to_string(); // Set the new path
+// One schema step, applied once and recorded in `PRAGMA user_version`
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    sql: &'static str,
 }
 
-fn create_tables(conn: &Connection, mode: AllTable) -> Result<()> {
-    match mode {
-        AllTable::Book => {
-            conn.execute(
-                "CREATE TABLE book (
+// Ordered list of every schema change, append only
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create base tables",
+        sql: "
+            CREATE TABLE IF NOT EXISTS book (
                 book_id INTEGER PRIMARY KEY,
                 title TEXT,
                 author TEXT,
                 desc TEXT,
                 year TEXT,
                 cover TEXT
-                )",
-                [],
-            )?;
-        }
-        AllTable::BookTags => {
-            conn.execute(
-                "CREATE TABLE book_tags (
-                    btag_id TEXT PRIMARY KEY,
-                    book_id INTEGER,
-                    tags_id INTEGER,
-                    FOREIGN KEY (book_id) REFERENCES book(book_id),
-                    FOREIGN KEY (tags_id) REFERENCES all_tags(tags_id)
-                )",
-                [],
-            )?;
-        }
-        AllTable::AllTags => {
-            conn.execute(
-                "CREATE TABLE all_tags (
-                    tags_id INTEGER PRIMARY KEY,
-                    name TEXT,
-                    img TEXT
-                )",
-                [],
-            )?;
-        }
+            );
+            CREATE TABLE IF NOT EXISTS book_tags (
+                btag_id TEXT PRIMARY KEY,
+                book_id INTEGER,
+                tags_id INTEGER,
+                FOREIGN KEY (book_id) REFERENCES book(book_id),
+                FOREIGN KEY (tags_id) REFERENCES all_tags(tags_id)
+            );
+            CREATE TABLE IF NOT EXISTS all_tags (
+                tags_id INTEGER PRIMARY KEY,
+                name TEXT,
+                img TEXT
+            );
+        ",
+    },
+    Migration {
+        version: 2,
+        name: "autoincrement ids and cascading foreign keys",
+        sql: "
+            CREATE TABLE book_new (
+                book_id INTEGER PRIMARY KEY AUTOINCREMENT,
+                title TEXT,
+                author TEXT,
+                desc TEXT,
+                year TEXT,
+                cover TEXT
+            );
+            INSERT INTO book_new (book_id, title, author, desc, year, cover)
+                SELECT book_id, title, author, desc, year, cover FROM book;
+            DROP TABLE book;
+            ALTER TABLE book_new RENAME TO book;
+
+            CREATE TABLE all_tags_new (
+                tags_id INTEGER PRIMARY KEY AUTOINCREMENT,
+                name TEXT,
+                img TEXT
+            );
+            INSERT INTO all_tags_new (tags_id, name, img)
+                SELECT tags_id, name, img FROM all_tags;
+            DROP TABLE all_tags;
+            ALTER TABLE all_tags_new RENAME TO all_tags;
+
+            CREATE TABLE book_tags_new (
+                book_id INTEGER NOT NULL REFERENCES book(book_id) ON DELETE CASCADE,
+                tags_id INTEGER NOT NULL REFERENCES all_tags(tags_id) ON DELETE CASCADE,
+                PRIMARY KEY (book_id, tags_id)
+            );
+            INSERT OR IGNORE INTO book_tags_new (book_id, tags_id)
+                SELECT book_id, tags_id FROM book_tags
+                WHERE book_id IN (SELECT book_id FROM book)
+                AND tags_id IN (SELECT tags_id FROM all_tags);
+            DROP TABLE book_tags;
+            ALTER TABLE book_tags_new RENAME TO book_tags;
+            CREATE INDEX book_tags_tags_id ON book_tags (tags_id);
+        ",
+    },
+];
+
+pub fn schema_version(conn: &Connection) -> Result<i32> {
+    return conn.query_row("PRAGMA user_version", [], |row| row.get(0));
+}
+
+// Migrations newer than the database, in the order they will run
+pub fn pending_migrations(conn: &Connection) -> Result<Vec<&'static Migration>, String> {
+    let current = schema_version(conn).map_err(|e| e.to_string())?;
+    let latest = MIGRATIONS.last().map(|m| m.version).unwrap_or(0);
+    if current > latest {
+        return Err(format!(
+            "database schema version {} is newer than this binary knows about ({})",
+            current, latest
+        ));
     }
-    Ok(())
+    return Ok(MIGRATIONS.iter().filter(|m| m.version > current).collect());
 }
 
-fn check_table_existance(conn: &Connection, table_name: &str, sql_table: AllTable) -> Result<()> {
-    let statement: &str = &format!(
-        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type='table' AND name='{}'",
-        table_name
-    );
-    let table_exists: Result<bool> = conn.query_row(statement, [], |row| row.get(0));
-    if table_exists.is_err() || !table_exists.unwrap() {
-        create_tables(&conn, sql_table)?;
+// Bring the database at `path` up to date, each migration in its own transaction.
+// With `dry_run` nothing is written and the pending migrations are only returned.
+pub fn run_migrations(path: &str, dry_run: bool) -> Result<Vec<&'static Migration>, String> {
+    let mut conn = Connection::open(path).map_err(|e| e.to_string())?;
+    let pending = pending_migrations(&conn)?;
+    if dry_run || pending.is_empty() {
+        return Ok(pending);
     }
-    Ok(())
+
+    // Table rebuilds have to run with foreign keys off, sqlite ignores this inside a transaction
+    conn.execute_batch("PRAGMA foreign_keys = OFF;")
+        .map_err(|e| e.to_string())?;
+    let broken_keys = |conn: &Connection| -> Result<i64, String> {
+        return conn
+            .query_row("SELECT COUNT(*) FROM pragma_foreign_key_check", [], |row| {
+                row.get(0)
+            })
+            .map_err(|e| e.to_string());
+    };
+    for m in &pending {
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+        // Old databases can hold links to deleted rows, a migration only
+        // fails for the ones it adds
+        let before = broken_keys(&tx)?;
+        tx.execute_batch(m.sql)
+            .map_err(|e| format!("migration {} ({}) failed: {}", m.version, m.name, e))?;
+        let broken = broken_keys(&tx)?;
+        if broken > before {
+            return Err(format!(
+                "migration {} ({}) left {} broken foreign keys",
+                m.version, m.name, broken
+            ));
+        }
+        tx.execute_batch(&format!("PRAGMA user_version = {};", m.version))
+            .map_err(|e| e.to_string())?;
+        tx.commit().map_err(|e| e.to_string())?;
+    }
+    conn.execute_batch("PRAGMA foreign_keys = ON;")
+        .map_err(|e| e.to_string())?;
+    return Ok(pending);
 }
 
-fn check_all_table(conn: &Connection) -> Result<()> {
-    check_table_existance(&conn, "book", AllTable::Book)?;
-    check_table_existance(&conn, "book_tags", AllTable::BookTags)?;
-    check_table_existance(&conn, "all_tags", AllTable::AllTags)?;
-    return Ok(());
+pub async fn sql_read_tags(
+    pool: DbPool,
+    from: i32,
+    range: i32,
+    sort_mode: SortOrder,
+) -> Result<Page<book::Tag>, AppError> {
+    with_conn(pool, move |conn| {
+        let total: i64 = conn.query_row("SELECT COUNT(*) FROM all_tags", [], |row| row.get(0))?;
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT tags_id, name FROM all_tags ORDER BY name {} limit ? offset ?",
+            sort_mode.as_sql(),
+        ))?;
+        let res = stmt
+            .query_map(params![range, from], |row| {
+                Ok(book::Tag {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                })
+            })?
+            .collect::<Result<Vec<book::Tag>>>()?;
+        return Ok(Page::from_offset(res, total, from));
+    })
+    .await
+}
+
+pub async fn sql_get_tag(pool: DbPool, tag_id: i32) -> Result<book::Tag, AppError> {
+    with_conn(pool, move |conn| {
+        return conn
+            .query_row(
+                "SELECT tags_id, name FROM all_tags WHERE tags_id = ?",
+                params![tag_id],
+                |row| {
+                    Ok(book::Tag {
+                        id: row.get(0)?,
+                        name: row.get(1)?,
+                    })
+                },
+            )
+            .optional()?
+            .ok_or_else(|| AppError::not_found(format!("tag {} does not exist", tag_id)));
+    })
+    .await
 }
 
-pub async fn sql_read_tags(from: i32, range: i32, sort_mode: String) -> Result<Vec<book::Tag>, ()> {
-    tokio::task::spawn_blocking(move || {
+// Tags with the given ids, ids that do not exist are left out
+pub async fn sql_get_tags(pool: DbPool, tag_ids: Vec<i32>) -> Result<Vec<book::Tag>, AppError> {
+    with_conn(pool, move |conn| {
         let mut res: Vec<book::Tag> = Vec::new();
-        let conn = Connection::open(get_sql_path_val()).unwrap();
-        let _ = check_all_table(&conn);
-        let mut stmt = conn
-            .prepare(&format!(
-                "SELECT tags_id, name FROM all_tags ORDER BY name {} limit {} offset {}",
-                sort_mode, range, from,
-            ))
-            .unwrap();
-        let tags_iter = stmt
-            .query_map([], |row| {
+        for chunk in tag_ids.chunks(TAG_BATCH) {
+            let holders = vec!["?"; chunk.len()].join(", ");
+            let mut stmt = conn.prepare(&format!(
+                "SELECT tags_id, name FROM all_tags WHERE tags_id IN ({})",
+                holders
+            ))?;
+            let tags = stmt.query_map(params_from_iter(chunk.iter()), |row| {
                 Ok(book::Tag {
                     id: row.get(0)?,
                     name: row.get(1)?,
                 })
-            })
-            .unwrap();
-        for tag in tags_iter {
-            res.push(tag.unwrap());
+            })?;
+            res.extend(tags.collect::<Result<Vec<book::Tag>>>()?);
         }
-        return Ok::<Vec<book::Tag>, ()>(res);
+        return Ok(res);
     })
     .await
-    .unwrap()
 }
 
-pub async fn sql_read_specified_tagged_book(
-    tag_id: i32,
-    lim: i32,
-    off: i32,
-    sort_mode: String,
-) -> Result<Vec<book::Book>, ()> {
-    tokio::task::spawn_blocking(move || {
-        let mut res: Vec<book::Book> = Vec::new();
-        let conn = Connection::open(get_sql_path_val()).unwrap();
-        let _ = check_all_table(&conn);
-
-        // Get all books with their details
-        let mut stmt = conn
-            .prepare(&format!(
-                "SELECT b.book_id, b.title, b.author, b.desc, b.year, b.cover
-                FROM book b
-                JOIN book_tags bt ON b.book_id = bt.book_id
-                JOIN all_tags at ON bt.tags_id = at.tags_id
-                WHERE at.tags_id = {} ORDER BY b.title {} limit {} offset {}",
-                tag_id, sort_mode, lim, off,
-            ))
-            .unwrap();
-        let books_iter = stmt
+// Every tag, for building the in-memory structures at startup
+pub async fn sql_read_all_tags(pool: DbPool) -> Result<Vec<book::Tag>, AppError> {
+    with_conn(pool, move |conn| {
+        let mut stmt =
+            conn.prepare_cached("SELECT tags_id, name FROM all_tags ORDER BY tags_id")?;
+        let res = stmt
             .query_map([], |row| {
-                Ok(book::Book {
+                Ok(book::Tag {
                     id: row.get(0)?,
-                    title: row.get(1)?,
-                    author: row.get(2)?,
-                    desc: row.get(3)?,
-                    tags: vec![], // Placeholder for tags, will fill this later
-                    year: row.get(4)?,
-                    cover: row.get(5)?,
-                })
-            })
-            .unwrap();
-
-        // Loop through each book and fetch tags
-        for book in books_iter {
-            let mut book_data = book.unwrap();
-
-            // Fetch tags for the current book_id
-            let mut tag_stmt = conn
-                .prepare(&format!(
-                    "
-                SELECT at.name, at.tags_id 
-                FROM book_tags bt 
-                JOIN all_tags at ON bt.tags_id = at.tags_id 
-                WHERE bt.book_id = ? ORDER BY at.name {}
-                ",
-                    sort_mode
-                ))
-                .unwrap();
-            let tag_iter = tag_stmt
-                .query_map(params![book_data.id], |row| {
-                    let tag_name: String = row.get(0)?;
-                    let tag_id: i32 = row.get(1)?;
-                    let tmp_res: Tag = Tag {
-                        id: tag_id,
-                        name: tag_name,
-                    };
-                    Ok(tmp_res) // Convert tags_id to String
+                    name: row.get(1)?,
                 })
-                .unwrap();
-
-            // Collect tags into the book struct
-            for tag in tag_iter {
-                book_data.tags.push(tag.unwrap());
-            }
-
-            res.push(book_data);
-        }
-        Ok(res)
+            })?
+            .collect::<Result<Vec<book::Tag>>>()?;
+        return Ok(res);
     })
     .await
-    .unwrap()
-}
-
-pub fn sql_read_book(sort_mode: String) -> Result<Vec<book::Book>> {
-    let mut res: Vec<book::Book> = Vec::new();
-    let conn = Connection::open(get_sql_path_val())?;
-    let _ = check_all_table(&conn);
-
-    // Get all books with their details
-    let mut stmt = conn.prepare(&format!(
-        "SELECT book_id, title, author, desc, year, cover FROM book ORDER BY title {}",
-        sort_mode
-    ))?;
-    let books_iter = stmt.query_map([], |row| {
-        Ok(book::Book {
-            id: row.get(0)?,
-            title: row.get(1)?,
-            author: row.get(2)?,
-            desc: row.get(3)?,
-            tags: vec![], // Placeholder for tags, will fill this later
-            year: row.get(4)?,
-            cover: row.get(5)?,
-        })
-    })?;
-
-    // Loop through each book and fetch tags
-    for book in books_iter {
-        let mut book_data = book?;
-
-        // Fetch tags for the current book_id
-        let mut tag_stmt = conn.prepare(&format!(
-            "
-            SELECT at.name, at.tags_id 
-            FROM book_tags bt 
-            JOIN all_tags at ON bt.tags_id = at.tags_id 
-            WHERE bt.book_id = ? ORDER BY at.name {}",
-            sort_mode
+}
+
+fn tag_exists(conn: &Connection, tag_id: i32) -> Result<bool> {
+    return conn.query_row(
+        "SELECT COUNT(*) > 0 FROM all_tags WHERE tags_id = ?",
+        params![tag_id],
+        |row| row.get(0),
+    );
+}
+
+const BOOK_COLUMNS: &str = "b.book_id, b.title, b.author, b.desc, b.year, b.cover";
+
+// sqlite refuses more than 999 bound values in one statement
+const TAG_BATCH: usize = 500;
+
+fn book_from_row(row: &Row) -> Result<book::Book> {
+    return Ok(book::Book {
+        id: row.get(0)?,
+        title: row.get(1)?,
+        author: row.get(2)?,
+        desc: row.get(3)?,
+        tags: vec![], // Filled by attach_tags
+        year: row.get(4)?,
+        cover: row.get(5)?,
+    });
+}
+
+// Fill the tags of every book with one query per batch instead of one per book
+fn attach_tags(conn: &Connection, books: &mut [book::Book], sort_mode: SortOrder) -> Result<()> {
+    let mut position: HashMap<i32, usize> = HashMap::new();
+    for (i, b) in books.iter().enumerate() {
+        position.insert(b.id, i);
+    }
+    let ids: Vec<i32> = books.iter().map(|b| b.id).collect();
+
+    for chunk in ids.chunks(TAG_BATCH) {
+        let holders = vec!["?"; chunk.len()].join(", ");
+        let mut tag_stmt = conn.prepare_cached(&format!(
+            "SELECT bt.book_id, at.tags_id, at.name
+            FROM book_tags bt
+            JOIN all_tags at ON bt.tags_id = at.tags_id
+            WHERE bt.book_id IN ({}) ORDER BY at.name {}",
+            holders,
+            sort_mode.as_sql()
         ))?;
-        let tag_iter = tag_stmt.query_map(params![book_data.id], |row| {
-            let tag_name: String = row.get(0)?;
-            let tag_id: i32 = row.get(1)?;
-            let tmp_res: Tag = Tag {
-                id: tag_id,
-                name: tag_name,
+        let tag_iter = tag_stmt.query_map(params_from_iter(chunk.iter()), |row| {
+            let book_id: i32 = row.get(0)?;
+            let tag = Tag {
+                id: row.get(1)?,
+                name: row.get(2)?,
             };
-            Ok(tmp_res) // Convert tags_id to String
+            Ok((book_id, tag))
         })?;
-
-        // Collect tags into the book struct
         for tag in tag_iter {
-            book_data.tags.push(tag?);
+            let (book_id, tag) = tag?;
+            if let Some(&i) = position.get(&book_id) {
+                books[i].tags.push(tag);
+            }
         }
-
-        res.push(book_data);
     }
+    return Ok(());
+}
+
+// Run a query selecting BOOK_COLUMNS from `book b` and return the books with their tags
+fn load_books<P: Params>(
+    conn: &Connection,
+    query: &str,
+    query_params: P,
+    sort_mode: SortOrder,
+) -> Result<Vec<book::Book>> {
+    let mut stmt = conn.prepare_cached(query)?;
+    let mut res: Vec<book::Book> = stmt
+        .query_map(query_params, book_from_row)?
+        .collect::<Result<Vec<book::Book>>>()?;
+    attach_tags(conn, &mut res, sort_mode)?;
     return Ok(res);
 }
 
-pub async fn sql_get_book_info(book_id: i32, sort_mode: String) -> Result<book::Book, ()> {
-    tokio::task::spawn_blocking(move || {
-        let res: book::Book;
-        let conn = Connection::open(get_sql_path_val()).unwrap();
-        let _ = check_all_table(&conn);
+pub async fn sql_read_specified_tagged_book(
+    pool: DbPool,
+    tag_id: i32,
+    lim: i32,
+    off: i32,
+    sort: BookSort,
+) -> Result<Page<book::Book>, AppError> {
+    with_conn(pool, move |conn| {
+        if !tag_exists(conn, tag_id)? {
+            return Err(AppError::not_found(format!(
+                "tag {} does not exist",
+                tag_id
+            )));
+        }
+        let total: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM book_tags WHERE tags_id = ?",
+            params![tag_id],
+            |row| row.get(0),
+        )?;
+        let query = format!(
+            "SELECT {} FROM book b
+            JOIN book_tags bt ON b.book_id = bt.book_id
+            WHERE bt.tags_id = ? {} limit ? offset ?",
+            BOOK_COLUMNS,
+            sort.order_by(),
+        );
+        let books = load_books(conn, &query, params![tag_id, lim, off], sort.order)?;
+        return Ok(Page::from_offset(books, total, off));
+    })
+    .await
+}
 
-        // Get all books with their details
-        let mut stmt = conn
-            .prepare(&format!(
-                "SELECT book_id, title, author, desc, year, cover FROM book where book_id = {}",
-                book_id
-            ))
-            .unwrap();
-        let books_iter = stmt
-            .query_map([], |row| {
-                Ok(book::Book {
-                    id: row.get(0).unwrap(),
-                    title: row.get(1).unwrap(),
-                    author: row.get(2).unwrap(),
-                    desc: row.get(3).unwrap(),
-                    tags: vec![], // Placeholder for tags, will fill this later
-                    year: row.get(4).unwrap(),
-                    cover: row.get(5).unwrap(),
-                })
-            })
-            .unwrap();
-
-        // Loop through each book and fetch tags
-        for book in books_iter {
-            let mut book_data = book.unwrap();
-
-            // Fetch tags for the current book_id
-            let mut tag_stmt = conn
-                .prepare(&format!(
-                    "
-                    SELECT at.name, at.tags_id 
-                    FROM book_tags bt 
-                    JOIN all_tags at ON bt.tags_id = at.tags_id 
-                    WHERE bt.book_id = {} ORDER BY at.name {}",
-                    book_id, sort_mode
-                ))
-                .unwrap();
-            let tag_iter = tag_stmt
-                .query_map([], |row| {
-                    let tag_name: String = row.get(0).unwrap();
-                    let tag_id: i32 = row.get(1).unwrap();
-                    let tmp_res: Tag = Tag {
-                        id: tag_id,
-                        name: tag_name,
-                    };
-                    Ok(tmp_res) // Convert tags_id to String
-                })
-                .unwrap();
+pub async fn sql_read_books(
+    pool: DbPool,
+    lim: i32,
+    off: i32,
+    sort: BookSort,
+) -> Result<Page<book::Book>, AppError> {
+    with_conn(pool, move |conn| {
+        let total: i64 = conn.query_row("SELECT COUNT(*) FROM book", [], |row| row.get(0))?;
+        let query = format!(
+            "SELECT {} FROM book b {} limit ? offset ?",
+            BOOK_COLUMNS,
+            sort.order_by()
+        );
+        let books = load_books(conn, &query, params![lim, off], sort.order)?;
+        return Ok(Page::from_offset(books, total, off));
+    })
+    .await
+}
 
-            // Collect tags into the book struct
-            for tag in tag_iter {
-                book_data.tags.push(tag.unwrap());
-            }
+// Books with the given ids, ids that do not exist are left out
+pub async fn sql_get_books(pool: DbPool, book_ids: Vec<i32>) -> Result<Vec<book::Book>, AppError> {
+    with_conn(pool, move |conn| {
+        let mut res: Vec<book::Book> = Vec::new();
+        for chunk in book_ids.chunks(TAG_BATCH) {
+            let holders = vec!["?"; chunk.len()].join(", ");
+            let query = format!(
+                "SELECT {} FROM book b WHERE b.book_id IN ({})",
+                BOOK_COLUMNS, holders
+            );
+            res.extend(load_books(
+                conn,
+                &query,
+                params_from_iter(chunk.iter()),
+                SortOrder::Asc,
+            )?);
+        }
+        return Ok(res);
+    })
+    .await
+}
 
-            res = book_data;
-            return Ok(res);
+// Ids of the books passing each filter of a search query
+pub async fn sql_filter_books(pool: DbPool, filters: Vec<Filter>) -> Result<FilterSets, AppError> {
+    with_conn(pool, move |conn| {
+        let mut res = FilterSets::new();
+        for filter in filters {
+            let ids: HashSet<i32> = match &filter {
+                // Years are free text, only the ones that are a plain number count
+                Filter::Year { from, to } => {
+                    let mut stmt = conn.prepare_cached(
+                        "SELECT book_id FROM book
+                        WHERE trim(year) GLOB '[0-9]*' AND trim(year) NOT GLOB '*[^0-9]*'
+                        AND CAST(trim(year) AS INTEGER) BETWEEN ?1 AND ?2",
+                    )?;
+                    let rows = stmt.query_map(
+                        params![from.unwrap_or(i32::MIN), to.unwrap_or(i32::MAX)],
+                        |row| row.get(0),
+                    )?;
+                    rows.collect::<Result<HashSet<i32>>>()?
+                }
+                Filter::Tag(name) => {
+                    let mut stmt = conn.prepare_cached(
+                        "SELECT bt.book_id FROM book_tags bt
+                        JOIN all_tags t ON t.tags_id = bt.tags_id
+                        WHERE lower(trim(t.name)) = lower(trim(?1))",
+                    )?;
+                    let rows = stmt.query_map(params![name], |row| row.get(0))?;
+                    rows.collect::<Result<HashSet<i32>>>()?
+                }
+            };
+            res.insert(filter, ids);
         }
-        return Err(());
+        return Ok(res);
     })
     .await
-    .unwrap()
 }
 
-pub async fn sql_del_book_from_id(book_id: i32) -> Result<()> {
-    tokio::task::spawn_blocking(move || {
-        let conn = Connection::open(get_sql_path_val())?;
-        let _ = check_all_table(&conn);
-        conn.execute(
-            "
-                DELETE FROM book WHERE book_id = ?
-        ",
-            [book_id],
-        )?;
-        conn.execute(
-            "
-            DELETE FROM book_tags where book_id = ?
-        ",
-            [book_id],
-        )?;
-        return Ok(());
+pub async fn sql_read_book(pool: DbPool, sort: BookSort) -> Result<Vec<book::Book>, AppError> {
+    with_conn(pool, move |conn| {
+        let query = format!("SELECT {} FROM book b {}", BOOK_COLUMNS, sort.order_by());
+        return Ok(load_books(conn, &query, [], sort.order)?);
     })
     .await
-    .unwrap()
 }
 
-pub async fn sql_del_tag_from_id(tag_id: i32) -> Result<()> {
-    tokio::task::spawn_blocking(move || {
-        let conn = Connection::open(get_sql_path_val())?;
-        let _ = check_all_table(&conn);
-        conn.execute(
-            "
-                DELETE FROM all_tags WHERE tags_id = ?
-            ",
-            [tag_id],
-        )?;
-        conn.execute(
-            "
-                DELETE FROM book_tags where tags_id = ?
-            ",
-            [tag_id],
-        )?;
+pub async fn sql_get_book_info(
+    pool: DbPool,
+    book_id: i32,
+    sort_mode: SortOrder,
+) -> Result<book::Book, AppError> {
+    with_conn(pool, move |conn| {
+        let query = format!("SELECT {} FROM book b WHERE b.book_id = ?", BOOK_COLUMNS);
+        let books = load_books(conn, &query, params![book_id], sort_mode)?;
+        return books
+            .into_iter()
+            .next()
+            .ok_or_else(|| AppError::not_found(format!("book {} does not exist", book_id)));
+    })
+    .await
+}
+
+// Links in book_tags go with it through ON DELETE CASCADE
+pub async fn sql_del_book_from_id(pool: DbPool, book_id: i32) -> Result<(), AppError> {
+    with_conn(pool, move |conn| {
+        let deleted = conn.execute("DELETE FROM book WHERE book_id = ?", [book_id])?;
+        if deleted == 0 {
+            return Err(AppError::not_found(format!(
+                "book {} does not exist",
+                book_id
+            )));
+        }
         return Ok(());
     })
     .await
-    .unwrap()
 }
 
-pub async fn sql_search_title(title: &str, sort_mode: String) -> Result<Vec<book::Book>, ()> {
-    let title_str: String = title.to_string();
-    tokio::task::spawn_blocking(move || {
-        let mut res: Vec<book::Book> = Vec::new();
-        let conn = Connection::open(get_sql_path_val()).unwrap();
-        let _ = check_all_table(&conn);
+pub async fn sql_del_tag_from_id(pool: DbPool, tag_id: i32) -> Result<(), AppError> {
+    with_conn(pool, move |conn| {
+        let deleted = conn.execute("DELETE FROM all_tags WHERE tags_id = ?", [tag_id])?;
+        if deleted == 0 {
+            return Err(AppError::not_found(format!(
+                "tag {} does not exist",
+                tag_id
+            )));
+        }
+        return Ok(());
+    })
+    .await
+}
 
-        // Get all books with their details
-        let mut stmt =
-            conn.prepare(&format!("SELECT book_id, title, author, desc, year, cover FROM book WHERE title = {} ORDER BY title {}", title_str, sort_mode)).unwrap();
-        let books_iter = stmt
-            .query_map([], |row| {
-                Ok(book::Book {
-                    id: row.get(0)?,
-                    title: row.get(1)?,
-                    author: row.get(2)?,
-                    desc: row.get(3)?,
-                    tags: vec![], // Placeholder for tags, will fill this later
-                    year: row.get(4)?,
-                    cover: row.get(5)?,
-                })
-            })
-            .unwrap();
-
-        // Loop through each book and fetch tags
-        for book in books_iter {
-            let mut book_data = book.unwrap();
-
-            // Fetch tags for the current book_id
-            let mut tag_stmt = conn
-                .prepare(&format!(
-                    "
-                SELECT at.name, at.tags_id 
-                FROM book_tags bt 
-                JOIN all_tags at ON bt.tags_id = at.tags_id 
-                WHERE bt.book_id = ? ORDER BY at.name {}
-                ",
-                    sort_mode
-                ))
-                .unwrap();
-            let tag_iter = tag_stmt
-                .query_map(params![book_data.id], |row| {
-                    let tag_name: String = row.get(0)?;
-                    let tag_id: i32 = row.get(1)?;
-                    let tmp_res: Tag = Tag {
-                        id: tag_id,
-                        name: tag_name,
-                    };
-                    Ok(tmp_res) // Convert tags_id to String
-                })
-                .unwrap();
+pub async fn sql_add_new_tag(pool: DbPool, new_tag: book::NewTag) -> Result<i64, AppError> {
+    let tag_name = new_tag.name.trim().to_string();
+    let img = new_tag.img;
 
-            // Collect tags into the book struct
-            for tag in tag_iter {
-                book_data.tags.push(tag.unwrap());
-            }
+    with_conn(pool, move |conn| {
+        if tag_name.is_empty() {
+            return Err(AppError::validation("tag name cannot be empty"));
+        }
 
-            res.push(book_data);
+        // Refuse to create a second tag with the same name
+        if let Some(id) = find_tag_by_name(conn, &tag_name)? {
+            return Err(AppError::conflict(format!(
+                "tag '{}' already exists with id {}",
+                tag_name, id
+            ))
+            .with_details(json!({ "id": id })));
         }
-        return Ok::<Vec<book::Book>, ()>(res);
-    });
-    return Err(());
-}
 
-pub async fn sql_search_author(author: &str, sort_mode: String) -> Result<Vec<book::Book>, ()> {
-    let author_str: String = author.to_string();
-    tokio::task::spawn_blocking(move || {
-        let mut res: Vec<book::Book> = Vec::new();
-        let conn = Connection::open(get_sql_path_val()).unwrap();
-        let _ = check_all_table(&conn);
+        // Let sqlite assign the id
+        conn.execute(
+            "INSERT INTO all_tags (name, img) VALUES (?, ?)",
+            params![tag_name, img],
+        )?;
+        Ok(conn.last_insert_rowid())
+    })
+    .await
+}
 
-        // Get all books with their details
-        let mut stmt =
-            conn.prepare(&format!("SELECT book_id, title, author, desc, year, cover FROM book WHERE title = {} ORDER BY title {}", author_str, sort_mode)).unwrap();
-        let books_iter = stmt
-            .query_map([], |row| {
-                Ok(book::Book {
-                    id: row.get(0)?,
-                    title: row.get(1)?,
-                    author: row.get(2)?,
-                    desc: row.get(3)?,
-                    tags: vec![], // Placeholder for tags, will fill this later
-                    year: row.get(4)?,
-                    cover: row.get(5)?,
-                })
-            })
-            .unwrap();
-
-        // Loop through each book and fetch tags
-        for book in books_iter {
-            let mut book_data = book.unwrap();
-
-            // Fetch tags for the current book_id
-            let mut tag_stmt = conn
-                .prepare(&format!(
-                    "
-                SELECT at.name, at.tags_id 
-                FROM book_tags bt 
-                JOIN all_tags at ON bt.tags_id = at.tags_id 
-                WHERE bt.book_id = ? ORDER BY at.name {}
-                ",
-                    sort_mode
-                ))
-                .unwrap();
-            let tag_iter = tag_stmt
-                .query_map(params![book_data.id], |row| {
-                    let tag_name: String = row.get(0)?;
-                    let tag_id: i32 = row.get(1)?;
-                    let tmp_res: Tag = Tag {
-                        id: tag_id,
-                        name: tag_name,
-                    };
-                    Ok(tmp_res) // Convert tags_id to String
-                })
-                .unwrap();
+// Tag names compare without case and surrounding spaces
+fn find_tag_by_name(conn: &Connection, name: &str) -> Result<Option<i32>> {
+    return conn
+        .query_row(
+            "SELECT tags_id FROM all_tags WHERE lower(trim(name)) = lower(trim(?))",
+            params![name],
+            |row| row.get(0),
+        )
+        .optional();
+}
 
-            // Collect tags into the book struct
-            for tag in tag_iter {
-                book_data.tags.push(tag.unwrap());
+// Turn ids or names into existing tag ids, duplicates dropped
+fn resolve_tags(conn: &Connection, tags: &[book::TagRef]) -> Result<Vec<i32>, AppError> {
+    let mut tag_ids: Vec<i32> = Vec::new();
+    for tag in tags {
+        let tag_id = match tag {
+            book::TagRef::Id(id) => {
+                if !tag_exists(conn, *id)? {
+                    return Err(AppError::validation(format!("tag {} does not exist", id))
+                        .with_details(json!({ "tag_id": id })));
+                }
+                *id
             }
-
-            res.push(book_data);
+            book::TagRef::Name(name) => match find_tag_by_name(conn, name)? {
+                Some(id) => id,
+                None => {
+                    return Err(
+                        AppError::validation(format!("tag '{}' does not exist", name))
+                            .with_details(json!({ "tag_name": name })),
+                    );
+                }
+            },
+        };
+        if !tag_ids.contains(&tag_id) {
+            tag_ids.push(tag_id);
         }
-        return Ok::<Vec<book::Book>, ()>(res);
-    });
-    return Err(());
+    }
+    return Ok(tag_ids);
 }
 
-pub async fn sql_add_new_tag(tag_name: &str, img: &str) -> Result<usize, ()> {
-    let tag_name = tag_name.replace("'", "''");
-    let img = img.to_string();
+pub async fn sql_add_new_book(pool: DbPool, new_book: book::NewBook) -> Result<i64, AppError> {
+    with_conn(pool, move |conn| {
+        if new_book.title.trim().is_empty() {
+            return Err(AppError::validation("book title cannot be empty"));
+        }
 
-    tokio::task::spawn_blocking(move || {
-        let conn = Connection::open(get_sql_path_val()).map_err(|_| ())?;
-        check_all_table(&conn).map_err(|_| ())?;
+        // The book row and its tag links either all land or none of them do
+        let tx = conn.transaction()?;
+        let tag_ids = resolve_tags(&tx, &new_book.tags)?;
+
+        tx.execute(
+            "INSERT INTO book (title, author, desc, year, cover) VALUES (?, ?, ?, ?, ?)",
+            params![
+                new_book.title,
+                new_book.author,
+                new_book.desc,
+                new_book.year,
+                new_book.cover
+            ],
+        )?;
+        let book_id = tx.last_insert_rowid();
 
-        // Get the count of existing tags
-        let count: i32 = conn
-            .query_row("SELECT COUNT(*) FROM all_tags", [], |row| row.get(0))
-            .map_err(|_| ())?;
+        for tag_id in &tag_ids {
+            tx.execute(
+                "INSERT INTO book_tags (book_id, tags_id) VALUES (?, ?)",
+                params![book_id, tag_id],
+            )?;
+        }
 
-        // Insert the new tag using parameterized query
-        conn.execute(
-            "INSERT INTO all_tags (tags_id, name, img) VALUES (?, ?, ?)",
-            params![count + 1, tag_name, img],
-        )
-        .map_err(|_| ())
+        tx.commit()?;
+        Ok(book_id)
     })
     .await
-    .map_err(|_| ())?
 }
 
-pub async fn sql_add_new_book(book_name: &str, author: &str, tags_id: &str, year: &str, desc: &str,img: &str) -> Result<usize, ()> {
-    let title = book_name.replace("'", "''");
-    let auth = author.to_string();
-    let y = year.to_string();
-    let d = desc.replace("'", "''");
-    let img = img.to_string();
-    let tags_arr:Vec<String> = tags_id.split_whitespace().map(|s| s.to_string()).collect();
+// Apply the given fields to a book and reconcile its tag links, only the
+// links that actually changed are inserted or removed
+pub async fn sql_update_book(
+    pool: DbPool,
+    book_id: i32,
+    patch: book::BookPatch,
+) -> Result<book::Book, AppError> {
+    with_conn(pool, move |conn| {
+        if let Some(title) = &patch.title {
+            if title.trim().is_empty() {
+                return Err(AppError::validation("book title cannot be empty"));
+            }
+        }
 
+        let tx = conn.transaction()?;
+        let exists: bool = tx.query_row(
+            "SELECT COUNT(*) > 0 FROM book WHERE book_id = ?",
+            params![book_id],
+            |row| row.get(0),
+        )?;
+        if !exists {
+            return Err(AppError::not_found(format!(
+                "book {} does not exist",
+                book_id
+            )));
+        }
 
-    tokio::task::spawn_blocking(move || {
-        let conn = Connection::open(get_sql_path_val()).unwrap();
-        let _ = check_all_table(&conn);
+        let columns = [
+            ("title", &patch.title),
+            ("author", &patch.author),
+            ("desc", &patch.desc),
+            ("year", &patch.year),
+            ("cover", &patch.cover),
+        ];
+        for (column, value) in columns {
+            if let Some(value) = value {
+                tx.execute(
+                    &format!("UPDATE book SET {} = ? WHERE book_id = ?", column),
+                    params![value, book_id],
+                )?;
+            }
+        }
 
-        // Get the count of existing tags
-        let count: i32 = conn
-            .query_row("SELECT COUNT(*) FROM book", [], |row| row.get(0))
-            .map_err(|_| ())?;
+        if let Some(tags) = &patch.tags {
+            let wanted = resolve_tags(&tx, tags)?;
+            let current: Vec<i32> = tx
+                .prepare_cached("SELECT tags_id FROM book_tags WHERE book_id = ?")?
+                .query_map(params![book_id], |row| row.get(0))?
+                .collect::<Result<Vec<i32>>>()?;
+            for tag_id in current.iter().filter(|t| !wanted.contains(t)) {
+                tx.execute(
+                    "DELETE FROM book_tags WHERE book_id = ? AND tags_id = ?",
+                    params![book_id, tag_id],
+                )?;
+            }
+            for tag_id in wanted.iter().filter(|t| !current.contains(t)) {
+                tx.execute(
+                    "INSERT INTO book_tags (book_id, tags_id) VALUES (?, ?)",
+                    params![book_id, tag_id],
+                )?;
+            }
+        }
 
-        // Insert the new tag using parameterized query
-        let _ = conn.execute(
-            "INSERT INTO book (book_id, title, author, desc, year, cover) VALUES (?, ?, ?, ?, ?, ?)",
-            params![count + 1, title, auth, d, y, img],
-        )
-        .map_err(|_| ());
+        let query = format!("SELECT {} FROM book b WHERE b.book_id = ?", BOOK_COLUMNS);
+        let book = load_books(&tx, &query, params![book_id], SortOrder::Asc)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| AppError::not_found(format!("book {} does not exist", book_id)))?;
+        tx.commit()?;
+        return Ok(book);
+    })
+    .await
+}
 
-        let mut idx = 0;
-        for tag in tags_arr {
-            let tag_int: i32 = tag.trim().parse().map_err(|_| ())?;
-            let btag: String = format!("{}-{}", count + 1, idx);
+pub async fn sql_rename_tag(pool: DbPool, tag_id: i32, name: &str) -> Result<book::Tag, AppError> {
+    let name = name.trim().to_string();
+    with_conn(pool, move |conn| {
+        if name.is_empty() {
+            return Err(AppError::validation("tag name cannot be empty"));
+        }
+        let tx = conn.transaction()?;
+        if !tag_exists(&tx, tag_id)? {
+            return Err(AppError::not_found(format!(
+                "tag {} does not exist",
+                tag_id
+            )));
+        }
+        if let Some(other) = find_tag_by_name(&tx, &name)? {
+            if other != tag_id {
+                return Err(AppError::conflict(format!(
+                    "tag '{}' already exists with id {}, merge them instead",
+                    name, other
+                ))
+                .with_details(json!({ "id": other })));
+            }
+        }
+        tx.execute(
+            "UPDATE all_tags SET name = ? WHERE tags_id = ?",
+            params![name, tag_id],
+        )?;
+        tx.commit()?;
+        return Ok(book::Tag { id: tag_id, name });
+    })
+    .await
+}
 
-            let result = conn.execute(
-                "INSERT INTO book_tags (btag_id, book_id, tags_id) VALUES (?, ?, ?)",
-                params![btag, count + 1, tag_int],
-            );
+// Re-point every link of the source tags to the target, drop the
+// duplicate links and the source tags, all in one transaction
+pub async fn sql_merge_tags(
+    pool: DbPool,
+    merge: book::TagMerge,
+) -> Result<book::TagMergeResult, AppError> {
+    with_conn(pool, move |conn| {
+        let mut sources: Vec<i32> = Vec::new();
+        for id in merge.sources {
+            if !sources.contains(&id) {
+                sources.push(id);
+            }
+        }
+        if sources.is_empty() {
+            return Err(AppError::validation("no source tags to merge"));
+        }
+        if sources.contains(&merge.target) {
+            return Err(AppError::validation(format!(
+                "tag {} cannot be merged into itself",
+                merge.target
+            )));
+        }
 
-            if let Err(e) = result {
-                println!("Error inserting into book_tags: {:?}", e);
-                return Err(()); // Adjust as needed
+        let tx = conn.transaction()?;
+        let target: book::Tag = tx
+            .query_row(
+                "SELECT tags_id, name FROM all_tags WHERE tags_id = ?",
+                params![merge.target],
+                |row| {
+                    Ok(book::Tag {
+                        id: row.get(0)?,
+                        name: row.get(1)?,
+                    })
+                },
+            )
+            .optional()?
+            .ok_or_else(|| AppError::not_found(format!("tag {} does not exist", merge.target)))?;
+        for id in &sources {
+            if !tag_exists(&tx, *id)? {
+                return Err(AppError::not_found(format!("tag {} does not exist", id)));
             }
-            idx += 1;
         }
-        Ok(1)
+
+        // In batches to stay under the bound value limit however many
+        // sources are given
+        let mut affected: HashSet<i32> = HashSet::new();
+        for chunk in sources.chunks(TAG_BATCH) {
+            let holders = vec!["?"; chunk.len()].join(", ");
+            let mut stmt = tx.prepare(&format!(
+                "SELECT DISTINCT book_id FROM book_tags WHERE tags_id IN ({})",
+                holders
+            ))?;
+            let rows = stmt.query_map(params_from_iter(chunk.iter()), |row| row.get(0))?;
+            affected.extend(rows.collect::<Result<Vec<i32>>>()?);
+
+            let mut relink: Vec<i32> = vec![merge.target];
+            relink.extend(chunk.iter());
+            tx.execute(
+                &format!(
+                    "INSERT OR IGNORE INTO book_tags (book_id, tags_id)
+                    SELECT book_id, ? FROM book_tags WHERE tags_id IN ({})",
+                    holders
+                ),
+                params_from_iter(relink.iter()),
+            )?;
+            // Their old links go with them through ON DELETE CASCADE
+            tx.execute(
+                &format!("DELETE FROM all_tags WHERE tags_id IN ({})", holders),
+                params_from_iter(chunk.iter()),
+            )?;
+        }
+        tx.commit()?;
+
+        return Ok(book::TagMergeResult {
+            target,
+            merged: sources,
+            books_affected: affected.len() as i64,
+        });
     })
     .await
-    .map_err(|_| ())?
 }